@@ -16,6 +16,12 @@ pub enum HalErrorKind {
     /// Can be used either for invalid method calls or invalid mode values passed
     InvalidGpioMode,
 
+    /// Pin is owned by another endpoint
+    PinBusy,
+
+    /// Not enough resources to complete the request, e.g. all stream slots are in use
+    NoResources,
+
     Other(u8),
 }
 
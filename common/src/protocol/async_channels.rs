@@ -0,0 +1,234 @@
+//! Async variants of the packet and command channels, built on tokio
+//!
+//! A single I/O task owns the packet channel: it writes queued commands and reads device
+//! messages, so any number of tasks can have commands in flight on the same device.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+use crate::hal::{HalResult, HalErrorKind, HalError};
+use crate::protocol::channels::{FragmentedChannel, DeviceMessage, StreamPacket, allocate_request_id, encode_command};
+use crate::protocol::notification::NotificationDispatcher;
+
+#[async_trait]
+pub trait AsyncPacketChannel: Send {
+    /// Reads the next packet
+    ///
+    /// Must be cancel safe: if the future is dropped before completion, no packet data may be lost.
+    async fn read_packet(&mut self) -> io::Result<Vec<u8>>;
+
+    async fn write_packet(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+#[async_trait]
+impl<T: AsyncPacketChannel> AsyncPacketChannel for FragmentedChannel<T> {
+    async fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let packet = self.inner_mut().read_packet().await?;
+            if let Some(message) = self.reassemble(&packet)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        for packet in Self::split(data)? {
+            self.inner_mut().write_packet(&packet).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Async counterpart of `CommandChannel`
+///
+/// Methods take `&self`, commands issued concurrently are in flight at the same time.
+#[async_trait]
+pub trait AsyncCommandChannel: Send + Sync {
+    async fn command(&self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>>;
+
+    /// Returns the next data message of the stream
+    async fn read_stream(&self, stream: u8) -> HalResult<StreamPacket>;
+
+    /// Returns the dispatcher of notifications received on this channel
+    fn notifications(&self) -> NotificationDispatcher;
+}
+
+/// Queue of received data messages of one stream
+struct StreamQueue {
+    sender: mpsc::UnboundedSender<StreamPacket>,
+    receiver: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<StreamPacket>>>,
+}
+
+impl StreamQueue {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct AsyncDeviceState {
+    next_request_id: u8,
+    pending: HashMap<u8, oneshot::Sender<HalResult<Vec<u8>>>>,
+    streams: HashMap<u8, StreamQueue>,
+    /// Set when the I/O task stops, all later commands fail
+    closed: bool,
+}
+
+struct AsyncDeviceShared {
+    state: Mutex<AsyncDeviceState>,
+    notifications: NotificationDispatcher,
+}
+
+impl AsyncDeviceShared {
+    fn dispatch(&self, message: DeviceMessage) {
+        match message {
+            DeviceMessage::Response(request_id, response) => {
+                let sender = self.state.lock().unwrap().pending.remove(&request_id);
+                if let Some(sender) = sender {
+                    sender.send(response).ok();
+                }
+            },
+            DeviceMessage::StreamData(stream, packet) => {
+                let mut state = self.state.lock().unwrap();
+                let queue = state.streams.entry(stream).or_insert_with(StreamQueue::new);
+                queue.sender.send(packet).ok();
+            },
+            DeviceMessage::Notification(notification) => {
+                self.notifications.dispatch(notification);
+            },
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for (_, sender) in state.pending.drain() {
+            sender.send(Err(HalErrorKind::ProtocolError.into())).ok();
+        }
+        // Dropping the senders wakes up the stream readers
+        state.streams.clear();
+    }
+}
+
+/// Command channel on top of an async packet channel
+pub struct AsyncDeviceChannel {
+    shared: Arc<AsyncDeviceShared>,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl AsyncDeviceChannel {
+    /// Creates the channel and spawns its I/O task, must be called within a tokio runtime
+    ///
+    /// The task stops when the channel is dropped or when the packet channel fails.
+    pub fn new<T: AsyncPacketChannel + 'static>(mut channel: T) -> Self {
+        let shared = Arc::new(AsyncDeviceShared {
+            state: Mutex::new(AsyncDeviceState::default()),
+            notifications: NotificationDispatcher::new(),
+        });
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let task_shared = shared.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    packet = channel.read_packet() => match packet {
+                        Ok(packet) => {
+                            if let Ok(message) = DeviceMessage::parse(&packet) {
+                                task_shared.dispatch(message);
+                            }
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                        Err(_) => break,
+                    },
+                    packet = outgoing_receiver.recv() => match packet {
+                        Some(packet) => {
+                            if channel.write_packet(&packet).await.is_err() {
+                                break;
+                            }
+                        },
+                        None => break,
+                    },
+                }
+            }
+            task_shared.close();
+        });
+
+        Self {
+            shared,
+            outgoing,
+        }
+    }
+
+    fn queue_command(&self, endpoint: u8, command: &[u8]) -> HalResult<oneshot::Receiver<HalResult<Vec<u8>>>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(HalErrorKind::ProtocolError.into());
+        }
+        let state = &mut *state;
+        let request_id = allocate_request_id(&state.pending, &mut state.next_request_id)
+            .ok_or_else(|| HalError::from(HalErrorKind::NoResources))?;
+        state.pending.insert(request_id, sender);
+
+        let command_buffer = encode_command(endpoint, request_id, command);
+        if self.outgoing.send(command_buffer).is_err() {
+            state.pending.remove(&request_id);
+            return Err(HalErrorKind::ProtocolError.into());
+        }
+        Ok(receiver)
+    }
+}
+
+#[async_trait]
+impl AsyncCommandChannel for AsyncDeviceChannel {
+    async fn command(&self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
+        let receiver = self.queue_command(endpoint, command)?;
+        receiver.await.map_err(|_| HalError::from(HalErrorKind::ProtocolError))?
+    }
+
+    async fn read_stream(&self, stream: u8) -> HalResult<StreamPacket> {
+        let receiver = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(HalErrorKind::ProtocolError.into());
+            }
+            state.streams.entry(stream).or_insert_with(StreamQueue::new).receiver.clone()
+        };
+        let packet = receiver.lock().await.recv().await;
+        packet.ok_or_else(|| HalErrorKind::ProtocolError.into())
+    }
+
+    fn notifications(&self) -> NotificationDispatcher {
+        self.shared.notifications.clone()
+    }
+}
+
+/// Async command channel bound to a single endpoint
+#[derive(Clone)]
+pub struct AsyncEndpointChannel {
+    command_channel: Arc<dyn AsyncCommandChannel>,
+    endpoint: u8,
+}
+
+impl AsyncEndpointChannel {
+    pub fn new(command_channel: Arc<dyn AsyncCommandChannel>, endpoint: u8) -> Self {
+        Self {
+            command_channel,
+            endpoint,
+        }
+    }
+
+    pub async fn command(&self, command: &[u8]) -> HalResult<Vec<u8>> {
+        self.command_channel.command(self.endpoint, command).await
+    }
+
+    pub fn command_channel(&self) -> &Arc<dyn AsyncCommandChannel> {
+        &self.command_channel
+    }
+}
@@ -1,7 +1,12 @@
 use std::io;
 use crate::hal::{HalResult, HalErrorKind, HalError};
-use crate::protocol::{CommandHeader, ResponseHeader};
-use std::sync::{Arc, Mutex};
+use crate::protocol::{CommandHeader, DeviceMessageHeader, serialize_vec};
+use crate::protocol::fragment::{self, Reassembler, MAX_COMMAND_SIZE};
+use crate::protocol::notification::{Notification, NotificationDispatcher};
+use crate::protocol::pipeline::{PipelinedChannel, ResponseFuture};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::collections::{HashMap, VecDeque};
 
 pub trait PacketChannel {
     fn read_packet(&mut self) -> io::Result<Vec<u8>>;
@@ -9,54 +14,300 @@ pub trait PacketChannel {
     fn write_packet(&mut self, data: &[u8]) -> io::Result<()>;
 }
 
+/// Maximum size of a reassembled message accepted from the device
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Splits outgoing messages into fragments and reassembles incoming ones
+pub struct FragmentedChannel<T> {
+    inner: T,
+    reassembler: Reassembler,
+}
+
+impl<T> FragmentedChannel<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            reassembler: Reassembler::new(MAX_RESPONSE_SIZE),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Accepts a packet read from the inner channel and returns the message if it's complete
+    pub(crate) fn reassemble(&mut self, packet: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let message = self.reassembler.push(packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)));
+        if self.reassembler.take_lost() {
+            log::warn!("discarded an incomplete message from the device");
+        }
+        message
+    }
+
+    /// Splits a message into packets for the inner channel
+    pub(crate) fn split(data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        if data.len() > MAX_COMMAND_SIZE {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        Ok(fragment::split_message(data))
+    }
+}
+
+impl<T: PacketChannel> PacketChannel for FragmentedChannel<T> {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            let packet = self.inner.read_packet()?;
+            if let Some(message) = self.reassemble(&packet)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        for packet in Self::split(data)? {
+            self.inner.write_packet(&packet)?;
+        }
+        Ok(())
+    }
+}
+
 pub trait CommandChannel {
     fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>>;
-}
 
-impl<T: PacketChannel> CommandChannel for T {
-    fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
-        let header = CommandHeader {
-            endpoint
-        };
-        let mut header_buffer = [0; 1];
-        let header_size = ssmarshal::serialize(&mut header_buffer, &header).unwrap();
+    /// Returns the next data message of the stream, reading from the device if needed
+    fn read_stream(&mut self, stream: u8) -> HalResult<StreamPacket>;
 
-        let mut command_buffer = Vec::new();
-        command_buffer.extend_from_slice(&header_buffer[..header_size]);
-        command_buffer.extend_from_slice(command);
+    /// Reads and dispatches one message from the device if no response is expected
+    fn poll(&mut self) -> HalResult<()>;
 
-        self.write_packet(&command_buffer).map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
-        let response_buffer = self.read_packet().map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
+    /// Returns the dispatcher of notifications received on this channel
+    fn notifications(&self) -> NotificationDispatcher;
+}
+
+#[derive(Debug)]
+pub struct StreamPacket {
+    pub sequence: u16,
+    pub data: Vec<u8>,
+}
 
-        let (header, header_size) = ssmarshal::deserialize::<ResponseHeader>(&response_buffer)
+/// Message received from the device
+pub enum DeviceMessage {
+    /// Response with the request id
+    Response(u8, HalResult<Vec<u8>>),
+    /// Stream data with the stream id
+    StreamData(u8, StreamPacket),
+    Notification(Notification),
+}
+
+impl DeviceMessage {
+    pub fn parse(message: &[u8]) -> HalResult<Self> {
+        let (header, header_size) = ssmarshal::deserialize::<DeviceMessageHeader>(message)
             .map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
-        match header {
-            Ok(()) => return Ok(response_buffer[header_size..].to_vec()),
-            Err(error_kind) => return Err(error_kind.into()),
+        let payload = message[header_size..].to_vec();
+        Ok(match header {
+            DeviceMessageHeader::Response(request_id, Ok(())) => DeviceMessage::Response(request_id, Ok(payload)),
+            DeviceMessageHeader::Response(request_id, Err(error_kind)) => DeviceMessage::Response(request_id, Err(error_kind.into())),
+            DeviceMessageHeader::StreamData(header) => {
+                DeviceMessage::StreamData(header.stream, StreamPacket {
+                    sequence: header.sequence,
+                    data: payload,
+                })
+            },
+            DeviceMessageHeader::Notification(kind) => {
+                DeviceMessage::Notification(Notification {
+                    kind,
+                    payload,
+                })
+            },
+        })
+    }
+}
+
+/// Prepends the command header to the command
+pub fn encode_command(endpoint: u8, request_id: u8, command: &[u8]) -> Vec<u8> {
+    let header = CommandHeader {
+        endpoint,
+        request_id,
+    };
+    let mut command_buffer = serialize_vec(&header);
+    command_buffer.extend_from_slice(command);
+    command_buffer
+}
+
+/// Number of distinct request ids, i.e. the maximum number of commands in flight
+const REQUEST_ID_COUNT: usize = 1 << 8;
+
+/// Picks an unused request id for a pipelined command, starting at `next`
+///
+/// Returns `None` when all 256 ids are waiting for a response.
+pub(crate) fn allocate_request_id<T>(pending: &HashMap<u8, T>, next: &mut u8) -> Option<u8> {
+    if pending.len() >= REQUEST_ID_COUNT {
+        return None;
+    }
+    let mut request_id = *next;
+    while pending.contains_key(&request_id) {
+        request_id = request_id.wrapping_add(1);
+    }
+    *next = request_id.wrapping_add(1);
+    Some(request_id)
+}
+
+/// Command channel on top of a packet channel
+///
+/// Stream data received while waiting for a response is queued until it's read,
+/// notifications are passed to the dispatcher.
+pub struct DeviceChannel<T> {
+    inner: T,
+    next_request_id: u8,
+    streams: HashMap<u8, VecDeque<StreamPacket>>,
+    notifications: NotificationDispatcher,
+}
+
+impl<T: PacketChannel> DeviceChannel<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            next_request_id: 0,
+            streams: HashMap::new(),
+            notifications: NotificationDispatcher::new(),
         }
     }
+
+    /// Reads a message from the device, returns it if it's a response
+    fn read_message(&mut self) -> HalResult<Option<(u8, HalResult<Vec<u8>>)>> {
+        let message = self.inner.read_packet().map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
+
+        match DeviceMessage::parse(&message)? {
+            DeviceMessage::Response(request_id, response) => Ok(Some((request_id, response))),
+            DeviceMessage::StreamData(stream, packet) => {
+                self.streams.entry(stream).or_default().push_back(packet);
+                Ok(None)
+            },
+            DeviceMessage::Notification(notification) => {
+                self.notifications.dispatch(notification);
+                Ok(None)
+            },
+        }
+    }
+}
+
+impl<T: PacketChannel> CommandChannel for DeviceChannel<T> {
+    fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let command_buffer = encode_command(endpoint, request_id, command);
+        self.inner.write_packet(&command_buffer).map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
+        loop {
+            match self.read_message()? {
+                Some((id, response)) if id == request_id => return response,
+                // Skip stale responses to commands which failed on the host side
+                _ => continue,
+            }
+        }
+    }
+
+    fn read_stream(&mut self, stream: u8) -> HalResult<StreamPacket> {
+        loop {
+            if let Some(packet) = self.streams.get_mut(&stream).and_then(|q| q.pop_front()) {
+                return Ok(packet);
+            }
+            self.poll()?;
+        }
+    }
+
+    fn poll(&mut self) -> HalResult<()> {
+        // No command is in flight, so a response can only be a stale one
+        self.read_message()?;
+        Ok(())
+    }
+
+    fn notifications(&self) -> NotificationDispatcher {
+        self.notifications.clone()
+    }
 }
 
 #[derive(Clone)]
-pub struct SharedCommandChannel(Arc<Mutex<Box<dyn CommandChannel>>>);
+pub struct SharedCommandChannel(SharedChannelKind);
+
+#[derive(Clone)]
+enum SharedChannelKind {
+    /// Commands wait for each other
+    Locked(Rc<RefCell<Box<dyn CommandChannel>>>),
+    /// Commands are sent without waiting for the previous responses
+    Pipelined(Rc<PipelinedChannel>),
+}
 
 impl SharedCommandChannel {
     pub fn new(channel: Box<dyn CommandChannel>) -> Self {
-        Self(Arc::new(Mutex::new(channel)))
+        Self(SharedChannelKind::Locked(Rc::new(RefCell::new(channel))))
+    }
+
+    pub fn pipelined(channel: PipelinedChannel) -> Self {
+        Self(SharedChannelKind::Pipelined(Rc::new(channel)))
+    }
+
+    /// Sends the command, the returned future resolves to the response
+    ///
+    /// For a non-pipelined channel this waits for the response before returning.
+    pub fn send_command(&self, endpoint: u8, command: &[u8]) -> HalResult<ResponseFuture> {
+        match &self.0 {
+            SharedChannelKind::Locked(channel) => {
+                let response = channel.borrow_mut().command(endpoint, command);
+                Ok(ResponseFuture::ready(response))
+            },
+            SharedChannelKind::Pipelined(channel) => channel.send_command(endpoint, command),
+        }
     }
 }
 
 impl CommandChannel for SharedCommandChannel {
     fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
-        let mut channel = self.0.lock().unwrap();
-        channel.command(endpoint, command)
+        (&*self).command(endpoint, command)
+    }
+
+    fn read_stream(&mut self, stream: u8) -> HalResult<StreamPacket> {
+        (&*self).read_stream(stream)
+    }
+
+    fn poll(&mut self) -> HalResult<()> {
+        (&*self).poll()
+    }
+
+    fn notifications(&self) -> NotificationDispatcher {
+        (&self).notifications()
     }
 }
 
-impl<'a> CommandChannel for &'a SharedCommandChannel {
+impl CommandChannel for &SharedCommandChannel {
     fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
-        let mut channel = self.0.lock().unwrap();
-        channel.command(endpoint, command)
+        match &self.0 {
+            SharedChannelKind::Locked(channel) => channel.borrow_mut().command(endpoint, command),
+            SharedChannelKind::Pipelined(channel) => channel.call(endpoint, command),
+        }
+    }
+
+    fn read_stream(&mut self, stream: u8) -> HalResult<StreamPacket> {
+        match &self.0 {
+            SharedChannelKind::Locked(channel) => channel.borrow_mut().read_stream(stream),
+            SharedChannelKind::Pipelined(channel) => channel.next_stream_packet(stream),
+        }
+    }
+
+    fn poll(&mut self) -> HalResult<()> {
+        match &self.0 {
+            SharedChannelKind::Locked(channel) => channel.borrow_mut().poll(),
+            SharedChannelKind::Pipelined(_) => Ok(()),
+        }
+    }
+
+    fn notifications(&self) -> NotificationDispatcher {
+        match &self.0 {
+            SharedChannelKind::Locked(channel) => channel.borrow_mut().notifications(),
+            SharedChannelKind::Pipelined(channel) => channel.notification_dispatcher(),
+        }
     }
 }
 
@@ -77,4 +328,12 @@ impl SharedEndpointChannel {
     pub fn command(&self, command: &[u8]) -> HalResult<Vec<u8>> {
         (&self.command_channel).command(self.endpoint, command)
     }
+
+    pub fn send_command(&self, command: &[u8]) -> HalResult<ResponseFuture> {
+        self.command_channel.send_command(self.endpoint, command)
+    }
+
+    pub fn command_channel(&self) -> &SharedCommandChannel {
+        &self.command_channel
+    }
 }
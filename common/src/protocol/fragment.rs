@@ -0,0 +1,199 @@
+//! Transport-level fragmentation
+//!
+//! Every packet on the wire starts with a one-byte fragment header. Messages that don't fit into
+//! a single packet are split into fragments with consecutive sequence numbers, and all fragments
+//! except the last one have the `more` flag set.
+
+/// Maximum size of a single packet on the wire, including the fragment header
+pub const MAX_PACKET_SIZE: usize = 128;
+
+/// Maximum size of the message data carried by a single packet
+pub const MAX_FRAGMENT_PAYLOAD: usize = MAX_PACKET_SIZE - 1;
+
+/// Maximum size of a reassembled command accepted by the device
+pub const MAX_COMMAND_SIZE: usize = 256;
+
+/// Packet size of the bulk endpoints of the vendor interface
+pub const BULK_PACKET_SIZE: usize = 64;
+
+const FRAGMENT_MORE: u8 = 0x80;
+const FRAGMENT_SEQUENCE_MASK: u8 = 0x7f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// More fragments of the same message follow
+    pub more: bool,
+    /// Index of the fragment within the message, modulo 128
+    pub sequence: u8,
+}
+
+impl FragmentHeader {
+    pub fn from_byte(byte: u8) -> Self {
+        Self {
+            more: byte & FRAGMENT_MORE != 0,
+            sequence: byte & FRAGMENT_SEQUENCE_MASK,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        let more = if self.more { FRAGMENT_MORE } else { 0 };
+        more | (self.sequence & FRAGMENT_SEQUENCE_MASK)
+    }
+}
+
+/// Returns the number of fragments needed to send a message of the specified size
+pub fn fragment_count(message_size: usize) -> usize {
+    if message_size == 0 {
+        1
+    } else {
+        message_size.div_ceil(MAX_FRAGMENT_PAYLOAD)
+    }
+}
+
+/// Returns true if a bulk transfer of the specified size must be followed by a zero-length packet
+///
+/// The vendor interface carries one packet per bulk transfer instead of COBS framing. A transfer
+/// ends with a short packet, or when it reaches `MAX_PACKET_SIZE` bytes.
+pub fn needs_zero_length_packet(size: usize) -> bool {
+    size.is_multiple_of(BULK_PACKET_SIZE) && size < MAX_PACKET_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// Fragment was received out of order
+    UnexpectedSequence,
+    /// Reassembled message exceeds the buffer size
+    Overflow,
+}
+
+/// Fragment accepted by `FragmentSequencer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accepted {
+    /// The fragment completes the message
+    pub complete: bool,
+    /// The fragment starts a new message before the previous one was complete, the partially
+    /// received message must be discarded
+    pub restarted: bool,
+}
+
+/// Checks the order of received fragments
+#[derive(Default)]
+pub struct FragmentSequencer {
+    next_sequence: u8,
+    in_progress: bool,
+}
+
+impl FragmentSequencer {
+    pub const fn new() -> Self {
+        Self {
+            next_sequence: 0,
+            in_progress: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.next_sequence = 0;
+        self.in_progress = false;
+    }
+
+    /// Returns true if some fragments of a message were accepted, but not the last one
+    pub fn in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    /// Accepts the next fragment
+    ///
+    /// A fragment with sequence number 0 always starts a new message, even if the previous one
+    /// wasn't complete. On error the sequencer is reset and the partially received message must
+    /// be discarded.
+    pub fn accept(&mut self, header: FragmentHeader) -> Result<Accepted, FragmentError> {
+        let restarted = header.sequence != self.next_sequence && header.sequence == 0;
+        if header.sequence != self.next_sequence && !restarted {
+            self.reset();
+            return Err(FragmentError::UnexpectedSequence);
+        }
+        if header.more {
+            self.next_sequence = (header.sequence + 1) & FRAGMENT_SEQUENCE_MASK;
+            self.in_progress = true;
+        } else {
+            self.reset();
+        }
+        Ok(Accepted {
+            complete: !header.more,
+            restarted,
+        })
+    }
+}
+
+/// Splits a message into packets, each starting with a fragment header
+#[cfg(feature = "std")]
+pub fn split_message(message: &[u8]) -> Vec<Vec<u8>> {
+    let count = fragment_count(message.len());
+    (0..count).map(|i| {
+        let start = i * MAX_FRAGMENT_PAYLOAD;
+        let end = std::cmp::min(start + MAX_FRAGMENT_PAYLOAD, message.len());
+        let header = FragmentHeader {
+            more: i + 1 < count,
+            sequence: i as u8,
+        };
+        let mut packet = Vec::with_capacity(1 + end - start);
+        packet.push(header.to_byte());
+        packet.extend_from_slice(&message[start..end]);
+        packet
+    }).collect()
+}
+
+/// Collects fragments into complete messages
+#[cfg(feature = "std")]
+pub struct Reassembler {
+    sequencer: FragmentSequencer,
+    buffer: Vec<u8>,
+    max_message_size: usize,
+    /// An incomplete message was discarded because the next one started
+    lost: bool,
+}
+
+#[cfg(feature = "std")]
+impl Reassembler {
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            sequencer: FragmentSequencer::new(),
+            buffer: Vec::new(),
+            max_message_size,
+            lost: false,
+        }
+    }
+
+    /// Returns true if an incomplete message was discarded since the last call
+    pub fn take_lost(&mut self) -> bool {
+        std::mem::replace(&mut self.lost, false)
+    }
+
+    /// Accepts a packet and returns the message if it's complete
+    ///
+    /// Empty packets are ignored. On error the partially received message is discarded.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        if packet.is_empty() {
+            return Ok(None);
+        }
+        if !self.sequencer.in_progress() {
+            self.buffer.clear();
+        }
+        let header = FragmentHeader::from_byte(packet[0]);
+        let accepted = self.sequencer.accept(header)?;
+        if accepted.restarted {
+            self.buffer.clear();
+            self.lost = true;
+        }
+        if self.buffer.len() + packet.len() - 1 > self.max_message_size {
+            self.sequencer.reset();
+            return Err(FragmentError::Overflow);
+        }
+        self.buffer.extend_from_slice(&packet[1..]);
+        if accepted.complete {
+            Ok(Some(std::mem::take(&mut self.buffer)))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -1,13 +1,26 @@
 use serde::{Serialize, Deserialize};
+use core::{fmt, str};
 use crate::hal::gpio::GpioPinMode;
 
+pub const GPIO_ENDPOINT: u8 = 1;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GpioCommand {
     EnumeratePins,
     GetPinMode(u8),
+    /// Sets the pin mode, claiming the pin for the GPIO endpoint
     SetPinMode(u8, GpioPinMode),
     SetPinValue(u8, bool),
     GetPinValue(u8),
+    /// Reverts the pin to floating input and releases it
+    ReleasePin(u8),
+    /// Opens a stream of pin level samples with the specified number of initial credits
+    ///
+    /// Returns the stream id. Each sample takes `(pin count + 7) / 8` bytes,
+    /// bit N of a sample is the level of pin N.
+    StartCapture(u16),
+    /// Enables or disables `NotificationKind::PinEdge` notifications for the input pin
+    WatchPin(u8, bool),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,10 +30,103 @@ pub enum GpioResponse {
     SetPinMode,
     SetPinValue,
     GetPinValue(bool),
+    ReleasePin,
+    StartCapture(u8),
+    WatchPin,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Maximum length of a pin label in bytes
+pub const GPIO_PIN_LABEL_LEN: usize = 8;
+
+/// Zero-padded ASCII pin label, e.g. "LD3" or "PE9"
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpioPinLabel([u8; GPIO_PIN_LABEL_LEN]);
+
+impl GpioPinLabel {
+    /// Creates a label, truncating it to `GPIO_PIN_LABEL_LEN` bytes
+    pub fn new(label: &str) -> Self {
+        let mut buf = [0; GPIO_PIN_LABEL_LEN];
+        for (dst, src) in buf.iter_mut().zip(label.bytes().filter(u8::is_ascii)) {
+            *dst = src;
+        }
+        GpioPinLabel(buf)
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(GPIO_PIN_LABEL_LEN);
+        str::from_utf8(&self.0[..len]).unwrap_or("")
+    }
+}
+
+impl fmt::Debug for GpioPinLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for GpioPinLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Set of GPIO modes supported by a pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpioPinModes(u8);
+
+impl GpioPinModes {
+    pub const FLOATING_INPUT: Self = GpioPinModes(1 << 0);
+    pub const PUSH_PULL_OUTPUT: Self = GpioPinModes(1 << 1);
+    pub const ALTERNATE: Self = GpioPinModes(1 << 2);
+
+    pub const fn empty() -> Self {
+        GpioPinModes(0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        GpioPinModes(self.0 | other.0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Checks whether the mode kind is in the set (alternate function numbers are not checked)
+    pub fn supports(&self, mode: GpioPinMode) -> bool {
+        match mode {
+            GpioPinMode::FloatingInput => self.contains(Self::FLOATING_INPUT),
+            GpioPinMode::PushPullOutput => self.contains(Self::PUSH_PULL_OUTPUT),
+            GpioPinMode::Alternate(_) => self.contains(Self::ALTERNATE),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct GpioPinInformation {
+    /// Port letter, e.g. b'E' for PE9
     pub index_major: u8,
+    /// Pin number within the port, e.g. 9 for PE9
     pub index_minor: u8,
+    /// Human-readable label
+    pub label: GpioPinLabel,
+    pub modes: GpioPinModes,
+    /// Available alternate functions, bit N is set if AFN is available
+    pub alternate_functions: u16,
+}
+
+impl GpioPinInformation {
+    /// Returns the port letter of the pin
+    pub fn port(&self) -> char {
+        self.index_major as char
+    }
+
+    /// Checks whether the pin can be switched to the specified mode
+    pub fn supports(&self, mode: GpioPinMode) -> bool {
+        match mode {
+            GpioPinMode::Alternate(af) => {
+                self.modes.supports(mode) && af < 16 && self.alternate_functions & (1 << af) != 0
+            },
+            _ => self.modes.supports(mode),
+        }
+    }
 }
@@ -1,13 +1,47 @@
 use serde::{Serialize, Deserialize};
 use crate::hal::HalErrorKind;
+use crate::protocol::stream::StreamDataHeader;
+use crate::protocol::notification::NotificationKind;
 
+#[cfg(feature = "async")]
+pub mod async_channels;
 #[cfg(feature = "std")]
 pub mod channels;
+pub mod fragment;
 pub mod gpio;
+pub mod notification;
+#[cfg(feature = "std")]
+pub mod pipeline;
+pub mod stream;
+pub mod system;
+pub mod usb;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommandHeader {
     pub endpoint: u8,
+    /// Identifier copied into the response, used to match responses with pipelined commands
+    pub request_id: u8,
 }
 
 pub type ResponseHeader = Result<(), HalErrorKind>;
+
+/// Header of every message sent by the device
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DeviceMessageHeader {
+    /// Response to the command with the specified request id
+    Response(u8, ResponseHeader),
+    /// Data pushed to the host by an open stream
+    StreamData(StreamDataHeader),
+    /// Unsolicited event
+    Notification(NotificationKind),
+}
+
+/// Serializes a fixed-size value into a vector
+#[cfg(feature = "std")]
+pub fn serialize_vec<T: Serialize>(value: &T) -> Vec<u8> {
+    // ssmarshal output never exceeds the in-memory size of a fixed-size value
+    let mut buf = vec![0; core::mem::size_of::<T>()];
+    let size = ssmarshal::serialize(&mut buf, value).unwrap();
+    buf.truncate(size);
+    buf
+}
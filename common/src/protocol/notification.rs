@@ -0,0 +1,176 @@
+//! Unsolicited notifications sent by the device
+//!
+//! A notification message starts with `DeviceMessageHeader::Notification(kind)`,
+//! the payload format depends on the kind.
+
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "std")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "std")]
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(feature = "std")]
+use crate::hal::{HalError, HalResult, HalErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NotificationKind {
+    /// Level of a watched pin changed, payload is `PinEdgeNotification`
+    PinEdge,
+    /// Data was dropped because a buffer was full, payload is `BufferKind`
+    BufferOverrun,
+    /// Asynchronous error, payload is `HalErrorKind`
+    Error,
+    /// Log record, payload is `LogRecordHeader` followed by the target and the message as UTF-8
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PinEdgeNotification {
+    /// Pin index in `GpioCommand::EnumeratePins` order
+    pub pin: u8,
+    /// New pin level
+    pub level: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BufferKind {
+    /// Received commands
+    CommandRx,
+    /// Pending notifications
+    Notifications,
+    /// Log records waiting to be forwarded
+    LogRecords,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LogRecordHeader {
+    pub level: LogLevel,
+    /// Milliseconds since the device started, wraps around
+    pub timestamp: u32,
+    /// Size of the target, the message takes the rest of the payload
+    pub target_size: u8,
+}
+
+/// Decoded `NotificationKind::Log` payload
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub timestamp: u32,
+    pub target: String,
+    pub message: String,
+}
+
+#[cfg(feature = "std")]
+impl LogRecord {
+    pub fn decode(payload: &[u8]) -> HalResult<Self> {
+        let (header, header_size): (LogRecordHeader, _) = ssmarshal::deserialize(payload)
+            .map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
+        let text = &payload[header_size..];
+        let target_size = usize::from(header.target_size);
+        if target_size > text.len() {
+            return Err(HalErrorKind::ProtocolError.into());
+        }
+        let (target, message) = text.split_at(target_size);
+        let decode = |text: &[u8]| String::from_utf8(text.to_vec()).map_err(|_| HalError::from(HalErrorKind::ProtocolError));
+        Ok(Self {
+            level: header.level,
+            timestamp: header.timestamp,
+            target: decode(target)?,
+            message: decode(message)?,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub payload: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl Notification {
+    /// Deserializes a fixed-size payload
+    pub fn decode<T: DeserializeOwned>(&self) -> HalResult<T> {
+        match ssmarshal::deserialize(&self.payload) {
+            Ok((value, size)) if size == self.payload.len() => Ok(value),
+            _ => Err(HalErrorKind::ProtocolError.into()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+struct Subscriber {
+    kind: Option<NotificationKind>,
+    sender: mpsc::Sender<Notification>,
+}
+
+/// Routes notifications to subscribers
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct NotificationDispatcher {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+#[cfg(feature = "std")]
+impl NotificationDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to notifications of the specified kind, or to all notifications if `kind` is `None`
+    ///
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self, kind: Option<NotificationKind>) -> mpsc::Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(Subscriber {
+            kind,
+            sender,
+        });
+        receiver
+    }
+
+    pub fn dispatch(&self, notification: Notification) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| {
+            if s.kind.is_none_or(|kind| kind == notification.kind) {
+                s.sender.send(notification.clone()).is_ok()
+            } else {
+                true
+            }
+        });
+    }
+}
@@ -0,0 +1,248 @@
+//! Command channel with many commands in flight
+//!
+//! Commands are written as soon as they're submitted, a background thread reads the device
+//! messages and matches responses to commands by request id.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use crate::hal::{HalResult, HalErrorKind, HalError};
+use crate::protocol::channels::{PacketChannel, CommandChannel, DeviceMessage, StreamPacket, allocate_request_id, encode_command};
+use crate::protocol::notification::NotificationDispatcher;
+
+#[derive(Default)]
+struct ResponseState {
+    response: Option<HalResult<Vec<u8>>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct ResponseSlot {
+    state: Mutex<ResponseState>,
+    ready: Condvar,
+}
+
+impl ResponseSlot {
+    fn complete(&self, response: HalResult<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        state.response = Some(response);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+/// Pending response to a pipelined command
+pub struct ResponseFuture {
+    slot: Arc<ResponseSlot>,
+}
+
+impl ResponseFuture {
+    pub(crate) fn ready(response: HalResult<Vec<u8>>) -> Self {
+        let slot = ResponseSlot::default();
+        slot.state.lock().unwrap().response = Some(response);
+        Self {
+            slot: Arc::new(slot),
+        }
+    }
+
+    /// Blocks until the response is received
+    pub fn wait(self) -> HalResult<Vec<u8>> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(response) = state.response.take() {
+                return response;
+            }
+            state = self.slot.ready.wait(state).unwrap();
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = HalResult<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        if let Some(response) = state.response.take() {
+            Poll::Ready(response)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[derive(Default)]
+struct PipelineState {
+    next_request_id: u8,
+    pending: HashMap<u8, Arc<ResponseSlot>>,
+    streams: HashMap<u8, VecDeque<StreamPacket>>,
+    /// Set when the reader thread stops, all later commands fail
+    closed: bool,
+}
+
+struct PipelineShared {
+    state: Mutex<PipelineState>,
+    stream_data: Condvar,
+    notifications: NotificationDispatcher,
+    stop: AtomicBool,
+}
+
+impl PipelineShared {
+    fn dispatch(&self, message: DeviceMessage) {
+        match message {
+            DeviceMessage::Response(request_id, response) => {
+                let slot = self.state.lock().unwrap().pending.remove(&request_id);
+                if let Some(slot) = slot {
+                    slot.complete(response);
+                }
+            },
+            DeviceMessage::StreamData(stream, packet) => {
+                let mut state = self.state.lock().unwrap();
+                state.streams.entry(stream).or_default().push_back(packet);
+                self.stream_data.notify_all();
+            },
+            DeviceMessage::Notification(notification) => {
+                self.notifications.dispatch(notification);
+            },
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for (_, slot) in state.pending.drain() {
+            slot.complete(Err(HalErrorKind::ProtocolError.into()));
+        }
+        self.stream_data.notify_all();
+    }
+}
+
+pub struct PipelinedChannel {
+    shared: Arc<PipelineShared>,
+    writer: Mutex<Box<dyn PacketChannel + Send>>,
+    reader_thread: Option<JoinHandle<()>>,
+}
+
+impl PipelinedChannel {
+    /// Creates a channel using separate packet channels for reading and writing,
+    /// e.g. two handles of the same serial port
+    ///
+    /// The reader should time out periodically, so that the reader thread can be stopped.
+    pub fn new<R, W>(mut reader: R, writer: W) -> Self
+        where R: PacketChannel + Send + 'static, W: PacketChannel + Send + 'static
+    {
+        let shared = Arc::new(PipelineShared {
+            state: Mutex::new(PipelineState::default()),
+            stream_data: Condvar::new(),
+            notifications: NotificationDispatcher::new(),
+            stop: AtomicBool::new(false),
+        });
+
+        let thread_shared = shared.clone();
+        let reader_thread = thread::spawn(move || {
+            while !thread_shared.stop.load(Ordering::Relaxed) {
+                match reader.read_packet() {
+                    Ok(packet) => {
+                        if let Ok(message) = DeviceMessage::parse(&packet) {
+                            thread_shared.dispatch(message);
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                    Err(_) => break,
+                }
+            }
+            thread_shared.close();
+        });
+
+        Self {
+            shared,
+            writer: Mutex::new(Box::new(writer)),
+            reader_thread: Some(reader_thread),
+        }
+    }
+
+    /// Sends the command without waiting for the response
+    pub fn send_command(&self, endpoint: u8, command: &[u8]) -> HalResult<ResponseFuture> {
+        let slot = Arc::new(ResponseSlot::default());
+        let request_id = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(HalErrorKind::ProtocolError.into());
+            }
+            let state = &mut *state;
+            let request_id = allocate_request_id(&state.pending, &mut state.next_request_id)
+                .ok_or_else(|| HalError::from(HalErrorKind::NoResources))?;
+            state.pending.insert(request_id, slot.clone());
+            request_id
+        };
+
+        let command_buffer = encode_command(endpoint, request_id, command);
+        let result = self.writer.lock().unwrap().write_packet(&command_buffer);
+        if result.is_err() {
+            self.shared.state.lock().unwrap().pending.remove(&request_id);
+            return Err(HalErrorKind::ProtocolError.into());
+        }
+        Ok(ResponseFuture {
+            slot,
+        })
+    }
+
+    /// Sends the command and blocks until the response is received
+    pub fn call(&self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
+        self.send_command(endpoint, command)?.wait()
+    }
+
+    /// Returns the next data message of the stream, blocking until it's received
+    pub fn next_stream_packet(&self, stream: u8) -> HalResult<StreamPacket> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(packet) = state.streams.get_mut(&stream).and_then(|q| q.pop_front()) {
+                return Ok(packet);
+            }
+            if state.closed {
+                return Err(HalError::from(HalErrorKind::ProtocolError));
+            }
+            state = self.shared.stream_data.wait(state).unwrap();
+        }
+    }
+
+    pub fn notification_dispatcher(&self) -> NotificationDispatcher {
+        self.shared.notifications.clone()
+    }
+}
+
+impl CommandChannel for PipelinedChannel {
+    fn command(&mut self, endpoint: u8, command: &[u8]) -> HalResult<Vec<u8>> {
+        self.call(endpoint, command)
+    }
+
+    fn read_stream(&mut self, stream: u8) -> HalResult<StreamPacket> {
+        self.next_stream_packet(stream)
+    }
+
+    fn poll(&mut self) -> HalResult<()> {
+        // Messages are dispatched by the reader thread
+        Ok(())
+    }
+
+    fn notifications(&self) -> NotificationDispatcher {
+        self.notification_dispatcher()
+    }
+}
+
+impl Drop for PipelinedChannel {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.reader_thread.take() {
+            thread.join().ok();
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Streaming data channel
+//!
+//! An endpoint opens a stream with an endpoint-specific command and then pushes data messages
+//! to the host without being polled. The device sends a data message only while it has credits;
+//! each message consumes one credit and the host returns credits with
+//! `SystemCommand::GrantCredits` as it consumes the data.
+
+use serde::{Serialize, Deserialize};
+use crate::protocol::fragment::MAX_FRAGMENT_PAYLOAD;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StreamDataHeader {
+    pub stream: u8,
+    /// Sequence number of the data message within the stream, used to detect lost data
+    pub sequence: u16,
+}
+
+/// Serialized size of `DeviceMessageHeader::StreamData`
+pub const STREAM_DATA_HEADER_SIZE: usize = 4;
+
+/// Maximum size of the data carried by a stream message, stream messages are never fragmented
+pub const MAX_STREAM_DATA_SIZE: usize = MAX_FRAGMENT_PAYLOAD - STREAM_DATA_HEADER_SIZE;
@@ -0,0 +1,125 @@
+use serde::{Serialize, Deserialize};
+use crate::hal::gpio::GpioPinMode;
+use crate::protocol::notification::LogLevel;
+
+pub const SYSTEM_ENDPOINT: u8 = 0;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SystemCommand {
+    /// Returns the number of pins followed by the owning endpoint of each pin
+    /// (`Option<u8>`, in `GpioCommand::EnumeratePins` order)
+    GetPinOwners,
+    /// Returns credits for the specified number of stream data messages
+    GrantCredits(u8, u16),
+    CloseStream(u8),
+    /// Returns the message of the panic that caused the last reset and forgets it
+    ///
+    /// The response is the UTF-8 message, empty if the device didn't panic.
+    TakeLastPanic,
+    /// Resets the keep-alive timer, any other command does too
+    KeepAlive,
+    /// Sets the keep-alive timeout in milliseconds, 0 disables it
+    ///
+    /// When no command is received within the timeout, the device reverts all pins to their
+    /// safe state and disables the timeout again.
+    SetKeepAliveTimeout(u16),
+    /// Sets the state a pin reverts to when the host is gone
+    SetSafeState(u8, SafePinState),
+    /// Forwards log records up to the level as `NotificationKind::Log` notifications,
+    /// `None` stops forwarding
+    SetLogLevel(Option<LogLevel>),
+    /// Returns the registers of the hard fault that caused the last reset
+    ///
+    /// The response is an `Option<FaultRegisters>`. `TakeLastPanic` forgets them with the message,
+    /// so this command is sent first.
+    GetLastFault,
+}
+
+/// Exception frame and fault status registers saved by the hard fault handler
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[repr(C)]
+pub struct FaultRegisters {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    /// Configurable fault status register, MMFSR, BFSR and UFSR
+    pub cfsr: u32,
+    pub hfsr: u32,
+    /// Faulting address of a memory management fault, valid if CFSR.MMARVALID is set
+    pub mmfar: u32,
+    /// Faulting address of a bus fault, valid if CFSR.BFARVALID is set
+    pub bfar: u32,
+}
+
+/// Fault status bits of CFSR with their descriptions
+const CFSR_CAUSES: &[(u32, &str)] = &[
+    (1 << 0, "instruction access violation"),
+    (1 << 1, "data access violation"),
+    (1 << 3, "memory management fault on exception return"),
+    (1 << 4, "memory management fault on exception entry"),
+    (1 << 5, "memory management fault during lazy FP state preservation"),
+    (1 << 8, "instruction bus error"),
+    (1 << 9, "precise data bus error"),
+    (1 << 10, "imprecise data bus error"),
+    (1 << 11, "bus fault on exception return"),
+    (1 << 12, "bus fault on exception entry"),
+    (1 << 13, "bus fault during lazy FP state preservation"),
+    (1 << 16, "undefined instruction"),
+    (1 << 17, "invalid EPSR state"),
+    (1 << 18, "invalid exception return"),
+    (1 << 19, "no coprocessor"),
+    (1 << 24, "unaligned access"),
+    (1 << 25, "divide by zero"),
+];
+
+const HFSR_CAUSES: &[(u32, &str)] = &[
+    (1 << 1, "vector table read error"),
+    (1 << 30, "escalated fault"),
+    (1 << 31, "debug event"),
+];
+
+const CFSR_MMARVALID: u32 = 1 << 7;
+const CFSR_BFARVALID: u32 = 1 << 15;
+
+impl FaultRegisters {
+    /// Returns the descriptions of the fault status bits that are set
+    pub fn causes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let cfsr = CFSR_CAUSES.iter().filter(move |(bit, _)| self.cfsr & bit != 0);
+        let hfsr = HFSR_CAUSES.iter().filter(move |(bit, _)| self.hfsr & bit != 0);
+        cfsr.chain(hfsr).map(|(_, cause)| *cause)
+    }
+
+    /// Returns the address whose access caused the fault, if the fault status registers tell it
+    pub fn fault_address(&self) -> Option<u32> {
+        if self.cfsr & CFSR_MMARVALID != 0 {
+            Some(self.mmfar)
+        } else if self.cfsr & CFSR_BFARVALID != 0 {
+            Some(self.bfar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pin configuration applied when the host stops sending keep-alives or closes the port
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum SafePinState {
+    #[default]
+    FloatingInput,
+    OutputLow,
+    OutputHigh,
+}
+
+impl SafePinState {
+    pub fn mode(self) -> GpioPinMode {
+        match self {
+            SafePinState::FloatingInput => GpioPinMode::FloatingInput,
+            SafePinState::OutputLow | SafePinState::OutputHigh => GpioPinMode::PushPullOutput,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Identification of the USB interfaces
+//!
+//! The interfaces are vendor-specific and told apart by their subclass.
+
+pub const USB_VID: u16 = 0x16c0;
+pub const USB_PID: u16 = 0x27dd;
+
+pub const USB_CLASS_VENDOR_SPECIFIC: u8 = 0xff;
+
+/// Bulk interface carrying the command protocol
+pub const COMMAND_INTERFACE_SUBCLASS: u8 = 0x01;
+/// Interface with a single bulk IN endpoint carrying the firmware log as text
+pub const LOG_INTERFACE_SUBCLASS: u8 = 0x02;
+
+/// Vendor request to the log interface, `wValue` 1 sends the log over USB, 0 back to the UART
+pub const LOG_REQUEST_ENABLE: u8 = 0x01;
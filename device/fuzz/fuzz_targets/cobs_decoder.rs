@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use deadbug_device::cobs::{CobsDecoder, DecoderStatus};
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the size of the chunks the data arrives in
+    let (chunk_size, data) = match data.split_first() {
+        Some((size, data)) => (*size as usize + 1, data),
+        None => return,
+    };
+
+    let mut decoder = CobsDecoder::new();
+    for chunk in data.chunks(chunk_size) {
+        let mut buffer = chunk.to_vec();
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let (raw_size, data_size, status) = decoder.decode(&mut buffer[offset..]);
+            assert!(raw_size > 0 && data_size <= raw_size);
+            if status == DecoderStatus::InProgress {
+                assert_eq!(offset + raw_size, buffer.len());
+            }
+            offset += raw_size;
+        }
+    }
+});
@@ -0,0 +1,57 @@
+use deadbug_device::Device;
+use deadbug_device::device::{NeedBuffer, MAX_STREAM_MESSAGE_SIZE};
+use deadbug_device::notifications::MAX_NOTIFICATION_SIZE;
+use deadbug_device::pin_allocator::GpioPinSet;
+use deadbug_sim::SimPinSet;
+
+/// Runs a sequence of commands for the endpoint
+///
+/// Every command is encoded as a size byte, a response buffer size byte and the command data.
+/// Between the commands an input pin is toggled and the streams and notifications are polled
+/// like in the firmware main loop.
+pub fn run_commands(endpoint: u8, mut data: &[u8]) {
+    let mut device = Device::new(SimPinSet::f3_discovery()).unwrap();
+    let mut request_id = 0u8;
+    while data.len() >= 2 {
+        let size = data[0] as usize;
+        let buffer_size = data[1] as usize;
+        let command_data = &data[2..data.len().min(2 + size)];
+        data = &data[2 + command_data.len()..];
+
+        let mut command = vec![endpoint, request_id];
+        command.extend_from_slice(command_data);
+        let mut response = vec![0; buffer_size];
+        let mut retries = 0;
+        loop {
+            match device.process_command(&command, &mut response) {
+                Ok(size) => {
+                    assert!(size <= response.len());
+                    break;
+                },
+                Err(NeedBuffer(size)) => {
+                    // The minimal buffer size may be requested first, then the actual size
+                    assert!(retries < 2 && size > response.len());
+                    response.resize(size, 0);
+                    retries += 1;
+                },
+            }
+        }
+
+        let pins = device.pins_mut().pin_set_mut().pins_mut();
+        let pin = &mut pins[request_id as usize % pins.len()];
+        pin.set_input_level(request_id & 0x08 != 0);
+        request_id = request_id.wrapping_add(1);
+
+        device.poll_events();
+        let mut buffer = [0; MAX_STREAM_MESSAGE_SIZE];
+        for _ in 0..4 {
+            if device.stream_ready() {
+                device.poll_streams(&mut buffer);
+            }
+        }
+        let mut buffer = [0; MAX_NOTIFICATION_SIZE];
+        while device.notification_pending() {
+            assert!(device.poll_notifications(&mut buffer) > 0);
+        }
+    }
+}
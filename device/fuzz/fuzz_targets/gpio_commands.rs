@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use deadbug_common::protocol::gpio::GPIO_ENDPOINT;
+
+mod commands;
+
+fuzz_target!(|data: &[u8]| {
+    commands::run_commands(GPIO_ENDPOINT, data);
+});
@@ -0,0 +1,40 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use bbqueue::BBQueue;
+use deadbug_common::protocol::fragment::{MAX_PACKET_SIZE, MAX_COMMAND_SIZE};
+use deadbug_device::packet_processor::{PacketProcessor, PacketConsumer};
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the size of the chunks the data arrives in
+    let (chunk_size, data) = match data.split_first() {
+        Some((size, data)) => (*size as usize % 256 + 1, data),
+        None => return,
+    };
+
+    // Same sizes as in the firmware. The queues are dropped before the buffers.
+    let mut data_buffer = vec![0u8; 512];
+    let mut packet_buffer = vec![0u8; 1024];
+    let data_queue = unsafe { BBQueue::unpinned_new(&mut *(data_buffer.as_mut_slice() as *mut [u8])) };
+    let packet_queue = unsafe { BBQueue::unpinned_new(&mut *(packet_buffer.as_mut_slice() as *mut [u8])) };
+    let (mut data_producer, data_consumer) = data_queue.split();
+    let (packet_producer, packet_consumer) = packet_queue.split();
+    let mut processor = PacketProcessor::new(data_consumer, packet_producer, MAX_PACKET_SIZE, MAX_COMMAND_SIZE);
+    let mut packets = PacketConsumer::new(packet_consumer);
+
+    for chunk in data.chunks(chunk_size) {
+        let mut grant = data_producer.grant(chunk.len()).expect("input queue is full");
+        grant.copy_from_slice(chunk);
+        data_producer.commit(chunk.len(), grant);
+
+        // Every call handles at most one chunk of input
+        for _ in 0..2 * chunk.len() + 4 {
+            processor.process();
+            while let Some(packet) = packets.read() {
+                assert!(!packet.is_empty() && packet.len() <= MAX_COMMAND_SIZE);
+                packets.release_consume(packet);
+            }
+        }
+    }
+    processor.take_overrun();
+});
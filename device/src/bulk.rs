@@ -0,0 +1,276 @@
+//! Framing for the vendor bulk interface
+//!
+//! Every packet is carried by a bulk transfer of its own, so unlike the serial port path no COBS
+//! encoding is needed. Messages are split into fragments in the transmit queue, each prefixed
+//! with its size, and sent as one transfer each. Received transfers are reassembled directly
+//! into the length-prefixed message queue read by `PacketConsumer`.
+
+use bbqueue::{Consumer, Producer, GrantW};
+use core::cmp;
+use core::ops::{Deref, DerefMut};
+use crate::command_processor::MessageProducer;
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::protocol::fragment::{
+    Accepted, FragmentHeader, FragmentSequencer, fragment_count, needs_zero_length_packet,
+    BULK_PACKET_SIZE, MAX_FRAGMENT_PAYLOAD, MAX_PACKET_SIZE,
+};
+
+/// Size prefix and fragment header
+const FRAGMENT_OVERHEAD: usize = 2;
+
+pub struct BulkTxGrantW {
+    data_grant: GrantW,
+    offset: usize,
+}
+
+impl Deref for BulkTxGrantW {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data_grant[self.offset..]
+    }
+}
+
+impl DerefMut for BulkTxGrantW {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data_grant[self.offset..]
+    }
+}
+
+/// Splits messages into size-prefixed fragments
+pub struct BulkTxProducer {
+    data_producer: Producer,
+}
+
+impl BulkTxProducer {
+    pub fn new(data_producer: Producer) -> Self {
+        Self {
+            data_producer,
+        }
+    }
+}
+
+impl MessageProducer for BulkTxProducer {
+    type Grant = BulkTxGrantW;
+
+    fn grant(&mut self, size: usize) -> Option<BulkTxGrantW> {
+        let overhead = fragment_count(size) * FRAGMENT_OVERHEAD;
+        self.data_producer.grant(size + overhead).ok().map(|data_grant| BulkTxGrantW {
+            data_grant,
+            offset: overhead,
+        })
+    }
+
+    fn commit_with_size(&mut self, size: usize, grant: BulkTxGrantW) -> HalResult<()> {
+        let mut data_grant = grant.data_grant;
+        if size == 0 {
+            self.data_producer.commit(0, data_grant);
+            return Ok(());
+        }
+        if size + grant.offset > data_grant.len() {
+            self.data_producer.commit(0, data_grant);
+            return Err(HalErrorKind::InvalidParameter.into());
+        }
+
+        // Move the fragments into place one by one, they always stay behind the raw data
+        let mut written = 0;
+        let mut offset = 0;
+        let mut sequence = 0;
+        while offset < size {
+            let chunk_size = cmp::min(size - offset, MAX_FRAGMENT_PAYLOAD);
+            let header = FragmentHeader {
+                more: offset + chunk_size < size,
+                sequence,
+            };
+            let start = grant.offset + offset;
+            data_grant.copy_within(start..start + chunk_size, written + FRAGMENT_OVERHEAD);
+            data_grant[written] = (1 + chunk_size) as u8;
+            data_grant[written + 1] = header.to_byte();
+            written += FRAGMENT_OVERHEAD + chunk_size;
+            offset += chunk_size;
+            sequence = sequence.wrapping_add(1);
+        }
+        self.data_producer.commit(written, data_grant);
+        Ok(())
+    }
+}
+
+/// Reads the queued fragments as USB packets
+pub struct BulkTxConsumer {
+    data_consumer: Consumer,
+    /// Sent part of the current fragment
+    offset: usize,
+}
+
+impl BulkTxConsumer {
+    pub fn new(data_consumer: Consumer) -> Self {
+        Self {
+            data_consumer,
+            offset: 0,
+        }
+    }
+
+    /// Passes the next USB packet to `write`, which returns false if it wasn't sent
+    ///
+    /// Returns true if a packet was sent.
+    pub fn write_packet<F: FnOnce(&[u8]) -> bool>(&mut self, write: F) -> bool {
+        let grant = match self.data_consumer.read() {
+            Ok(grant) => grant,
+            Err(_) => return false,
+        };
+        let size = grant[0] as usize;
+        let end = cmp::min(self.offset + BULK_PACKET_SIZE, size);
+        if !write(&grant[1 + self.offset..1 + end]) {
+            self.data_consumer.release(0, grant);
+            return false;
+        }
+
+        let last = end - self.offset < BULK_PACKET_SIZE
+            || (end == size && !needs_zero_length_packet(size));
+        if last {
+            self.offset = 0;
+            self.data_consumer.release(1 + size, grant);
+        } else {
+            self.offset = end;
+            self.data_consumer.release(0, grant);
+        }
+        true
+    }
+}
+
+/// Collects USB packets into fragments and reassembles the messages
+pub struct BulkRx {
+    producer: Producer,
+    /// Buffer of the message being reassembled
+    grant: Option<GrantW>,
+    max_message_size: usize,
+    /// Size of the reassembled part of the current message
+    message_size: usize,
+    sequencer: FragmentSequencer,
+    /// Packet being received
+    buffer: [u8; MAX_PACKET_SIZE],
+    size: usize,
+    /// The packet is complete, but waits for space in the message queue
+    pending: bool,
+    /// Some data was dropped because it didn't fit into the buffer, or a message was cut off
+    overrun: bool,
+}
+
+impl BulkRx {
+    pub fn new(producer: Producer, max_message_size: usize) -> Self {
+        Self {
+            producer,
+            grant: None,
+            max_message_size,
+            message_size: 0,
+            sequencer: FragmentSequencer::new(),
+            buffer: [0; MAX_PACKET_SIZE],
+            size: 0,
+            pending: false,
+            overrun: false,
+        }
+    }
+
+    /// Returns true if some data was dropped since the last call
+    pub fn take_overrun(&mut self) -> bool {
+        core::mem::replace(&mut self.overrun, false)
+    }
+
+    /// Returns true if a complete packet waits for space in the message queue
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Returns the buffer for the next USB packet, `None` while a packet waits for space
+    pub fn packet_buffer(&mut self) -> Option<&mut [u8]> {
+        if self.pending {
+            None
+        } else {
+            Some(&mut self.buffer[self.size..])
+        }
+    }
+
+    /// Accounts for a USB packet written into `packet_buffer`
+    pub fn packet_received(&mut self, size: usize) {
+        self.size += size;
+        if size < BULK_PACKET_SIZE || self.size == MAX_PACKET_SIZE {
+            self.pending = true;
+            self.process();
+        }
+    }
+
+    /// Reassembles the pending packet if the message queue has space
+    ///
+    /// Returns false while the packet waits.
+    pub fn process(&mut self) -> bool {
+        if !self.pending {
+            return true;
+        }
+        if self.size == 0 {
+            // Zero-length packet which ended a transfer
+            self.pending = false;
+            return true;
+        }
+        if self.grant.is_none() {
+            match self.producer.grant(2 + self.max_message_size) {
+                Ok(grant) => self.grant = Some(grant),
+                Err(_) => return false,
+            }
+        }
+        self.accept_fragment();
+        self.size = 0;
+        self.pending = false;
+        true
+    }
+
+    fn accept_fragment(&mut self) {
+        let mut grant = match self.grant.take() {
+            Some(grant) => grant,
+            None => return,
+        };
+        let header = FragmentHeader::from_byte(self.buffer[0]);
+        let payload = &self.buffer[1..self.size];
+        let accepted = self.sequencer.accept(header);
+        if let Ok(Accepted { restarted: true, .. }) = accepted {
+            // The previous message was cut off, report it as lost
+            self.message_size = 0;
+            self.overrun = true;
+        }
+        match accepted {
+            Ok(Accepted { complete, .. }) if self.message_size + payload.len() <= self.max_message_size => {
+                let start = 2 + self.message_size;
+                grant[start..start + payload.len()].copy_from_slice(payload);
+                self.message_size += payload.len();
+
+                if !complete {
+                    self.grant = Some(grant);
+                } else if self.message_size > 0 {
+                    // Write the actual message length
+                    let len_bytes = (self.message_size as u16).to_ne_bytes();
+                    grant[..2].copy_from_slice(&len_bytes);
+                    self.producer.commit(2 + self.message_size, grant);
+                    self.message_size = 0;
+                } else {
+                    // Discard zero-length message
+                    self.producer.commit(0, grant);
+                }
+            },
+            Ok(_) => {
+                // Oversized message, discard it
+                self.producer.commit(0, grant);
+                self.discard_message();
+                self.overrun = true;
+            },
+            Err(_) => {
+                // Out-of-order fragment, discard the message
+                self.producer.commit(0, grant);
+                self.discard_message();
+            },
+        }
+    }
+
+    fn discard_message(&mut self) {
+        self.message_size = 0;
+        self.sequencer.reset();
+    }
+}
@@ -5,8 +5,9 @@ enum DecoderState {
     /// State immediate after the zero byte
     Start,
 
-    /// In-progress decoding
-    Decoding(u8),
+    /// In-progress decoding: bytes left in the current block, the block is followed
+    /// by a zero (i.e. its code byte isn't 0xff)
+    Decoding(u8, bool),
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -16,11 +17,11 @@ pub enum DecoderStatus {
     Error,
 }
 
+/// Incremental COBS decoder working in place
 pub struct CobsDecoder {
     state: DecoderState
 }
 
-#[allow(unused)]
 impl CobsDecoder {
     pub fn new() -> Self {
         Self {
@@ -48,25 +49,27 @@ impl CobsDecoder {
                 }
                 DecoderState::Start => {
                     if byte != 0 {
-                        self.state = DecoderState::Decoding(byte - 1)
+                        self.state = DecoderState::Decoding(byte - 1, byte != 0xff)
                     }
                 },
-                DecoderState::Decoding(b) if b == 0 => {
+                DecoderState::Decoding(0, zero) => {
                     if byte == 0 {
                         self.state = DecoderState::Start;
                         return (read_idx, write_idx, DecoderStatus::Finished);
                     } else {
-                        self.state = DecoderState::Decoding(byte - 1);
-                        buffer[write_idx] = 0;
-                        write_idx += 1;
+                        self.state = DecoderState::Decoding(byte - 1, byte != 0xff);
+                        if zero {
+                            buffer[write_idx] = 0;
+                            write_idx += 1;
+                        }
                     }
                 },
-                DecoderState::Decoding(b) => {
+                DecoderState::Decoding(b, zero) => {
                     if byte == 0 {
                         self.state = DecoderState::Start;
                         return (read_idx, write_idx, DecoderStatus::Error);
                     }
-                    self.state = DecoderState::Decoding(b - 1);
+                    self.state = DecoderState::Decoding(b - 1, zero);
                     buffer[write_idx] = byte;
                     write_idx += 1;
                 },
@@ -76,15 +79,31 @@ impl CobsDecoder {
     }
 }
 
-pub fn cobs_encode_in_place(buffer: &mut [u8], data_offset: usize, data_size: usize) -> usize {
+impl Default for CobsDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encodes `header` followed by `data_size` bytes at `data_offset` into the same buffer,
+/// starting at `write_offset`, and appends the zero delimiter.
+///
+/// The data must be located at least two bytes after `write_offset`.
+/// Returns the number of bytes written.
+pub fn cobs_encode_fragment_in_place(buffer: &mut [u8], write_offset: usize, header: u8, data_offset: usize, data_size: usize) -> usize {
     let mut read_idx = data_offset;
-    let mut write_idx = 1;
+    let mut write_idx = write_offset + 1;
     let mut code = 1;
-    let mut code_index = 0;
-    while read_idx < data_offset + data_size {
+    let mut code_index = write_offset;
+    let mut next_byte = Some(header);
+    while let Some(byte) = next_byte {
         if code != 0xff {
-            let byte = buffer[read_idx];
-            read_idx += 1;
+            next_byte = if read_idx < data_offset + data_size {
+                read_idx += 1;
+                Some(buffer[read_idx - 1])
+            } else {
+                None
+            };
             if byte != 0 {
                 buffer[write_idx] = byte;
                 write_idx += 1;
@@ -99,5 +118,5 @@ pub fn cobs_encode_in_place(buffer: &mut [u8], data_offset: usize, data_size: us
     }
     buffer[code_index] = code;
     buffer[write_idx] = 0;
-    write_idx + 1
+    write_idx + 1 - write_offset
 }
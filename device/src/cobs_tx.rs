@@ -0,0 +1,108 @@
+use bbqueue::{Producer, GrantW};
+use core::ops::{Deref, DerefMut};
+use crate::cobs::cobs_encode_fragment_in_place;
+use crate::command_processor::MessageProducer;
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::protocol::fragment::{FragmentHeader, fragment_count, MAX_FRAGMENT_PAYLOAD};
+
+/// Upper bound of the encoding overhead per fragment: fragment header, COBS code bytes and
+/// the zero delimiter
+const FRAGMENT_OVERHEAD: usize = 1 + (MAX_FRAGMENT_PAYLOAD + 1).div_ceil(254) + 1;
+
+pub struct CobsTxGrantW {
+    data_grant: GrantW,
+    offset: usize,
+}
+
+impl Deref for CobsTxGrantW {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data_grant[self.offset..]
+    }
+}
+
+impl DerefMut for CobsTxGrantW {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data_grant[self.offset..]
+    }
+}
+
+pub struct CobsTxProducer {
+    data_producer: Producer,
+}
+
+impl CobsTxProducer {
+    pub fn new(data_producer: Producer) -> Self {
+        Self {
+            data_producer,
+        }
+    }
+
+    pub fn grant(&mut self, size: usize) -> Option<CobsTxGrantW> {
+        let overhead = fragment_count(size) * FRAGMENT_OVERHEAD;
+        if let Ok(data_grant) = self.data_producer.grant(size + overhead) {
+            Some(CobsTxGrantW {
+                data_grant,
+                offset: overhead
+            })
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub fn commit(&mut self, grant: CobsTxGrantW) {
+        let data_size = grant.data_grant.len() - grant.offset;
+        self.commit_with_size_unchecked(data_size, grant)
+    }
+
+    /// Fails with `InvalidParameter` and drops the message if `size` exceeds the grant
+    #[inline(always)]
+    pub fn commit_with_size(&mut self, size: usize, grant: CobsTxGrantW) -> HalResult<()> {
+        if size + grant.offset > grant.data_grant.len() {
+            self.data_producer.commit(0, grant.data_grant);
+            return Err(HalErrorKind::InvalidParameter.into());
+        }
+        self.commit_with_size_unchecked(size, grant);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn commit_with_size_unchecked(&mut self, size: usize, mut grant: CobsTxGrantW) {
+        if size == 0 {
+            self.data_producer.commit(0, grant.data_grant);
+        } else {
+            // Encode fragments one by one, the encoded data always stays behind the raw data
+            let mut encoded_size = 0;
+            let mut offset = 0;
+            let mut sequence = 0;
+            while offset < size {
+                let chunk_size = core::cmp::min(size - offset, MAX_FRAGMENT_PAYLOAD);
+                let header = FragmentHeader {
+                    more: offset + chunk_size < size,
+                    sequence,
+                };
+                encoded_size += cobs_encode_fragment_in_place(
+                    &mut grant.data_grant, encoded_size, header.to_byte(),
+                    grant.offset + offset, chunk_size
+                );
+                offset += chunk_size;
+                sequence = sequence.wrapping_add(1);
+            }
+            self.data_producer.commit(encoded_size, grant.data_grant);
+        }
+    }
+}
+
+impl MessageProducer for CobsTxProducer {
+    type Grant = CobsTxGrantW;
+
+    fn grant(&mut self, size: usize) -> Option<CobsTxGrantW> {
+        CobsTxProducer::grant(self, size)
+    }
+
+    fn commit_with_size(&mut self, size: usize, grant: CobsTxGrantW) -> HalResult<()> {
+        CobsTxProducer::commit_with_size(self, size, grant)
+    }
+}
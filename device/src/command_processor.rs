@@ -0,0 +1,149 @@
+use core::ops::DerefMut;
+use log::{info, warn};
+use deadbug_common::hal::HalResult;
+use crate::cobs_tx::CobsTxProducer;
+use crate::packet_processor::PacketConsumer;
+use crate::pin_allocator::GpioPinSet;
+use crate::device::{Device, NeedBuffer, MIN_RESPONSE_BUFFER_SIZE, MAX_STREAM_MESSAGE_SIZE};
+use crate::notifications::{Notification, MAX_NOTIFICATION_SIZE};
+
+/// Queue of messages sent to the host, encodes them for the transport
+pub trait MessageProducer {
+    type Grant: DerefMut<Target = [u8]>;
+
+    /// Returns a buffer for a message of up to `size` bytes
+    fn grant(&mut self, size: usize) -> Option<Self::Grant>;
+
+    /// Queues the message written into the buffer, nothing is sent if `size` is 0
+    ///
+    /// Fails with `InvalidParameter` and drops the message if `size` exceeds the buffer.
+    fn commit_with_size(&mut self, size: usize, grant: Self::Grant) -> HalResult<()>;
+}
+
+/// Moves messages between the USB queues and the device logic
+pub struct CommandProcessor<S: GpioPinSet, P: MessageProducer = CobsTxProducer> {
+    producer: P,
+    consumer: PacketConsumer,
+    write_grant_request: Option<usize>,
+    device: Device<S>,
+    /// Queued log notification messages, see `log_records`
+    log_records: Option<PacketConsumer>,
+}
+
+impl<S: GpioPinSet, P: MessageProducer> CommandProcessor<S, P> {
+    pub fn new(producer: P, consumer: PacketConsumer, device: Device<S>) -> Self {
+        Self {
+            producer,
+            consumer,
+            write_grant_request: None,
+            device,
+            log_records: None,
+        }
+    }
+
+    /// Sends the log notification messages queued by `LogRecordProducer`
+    pub fn set_log_records(&mut self, consumer: PacketConsumer) {
+        self.log_records = Some(consumer);
+    }
+
+    #[inline(never)]
+    pub fn process(&mut self) {
+        // Handle all queued commands back to back
+        while self.process_next_command() {}
+        self.device.poll_events();
+        self.process_streams();
+        self.process_notifications();
+        self.process_log_records();
+    }
+
+    pub fn device(&self) -> &Device<S> {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device<S> {
+        &mut self.device
+    }
+
+    /// Queues a notification to be sent to the host
+    pub fn notify(&mut self, notification: Notification) {
+        self.device.notify(notification);
+    }
+
+    /// Returns true if a command was consumed
+    fn process_next_command(&mut self) -> bool {
+        if let Some(read_grant) = self.consumer.read() {
+            info!("got grant, len {}", read_grant.len());
+
+            let write_grant_size = self.write_grant_request.unwrap_or(MIN_RESPONSE_BUFFER_SIZE);
+            if let Some(mut write_grant) = self.producer.grant(write_grant_size) {
+                match self.device.process_command(&read_grant, &mut write_grant) {
+                    Ok(size) => {
+                        commit(&mut self.producer, size, write_grant);
+                        self.consumer.release_consume(read_grant);
+                        self.write_grant_request = None;
+                        true
+                    },
+                    Err(NeedBuffer(size)) => {
+                        self.write_grant_request = Some(size);
+                        commit(&mut self.producer, 0, write_grant);
+                        self.consumer.release_unread(read_grant);
+                        false
+                    },
+                }
+            } else {
+                self.consumer.release_unread(read_grant);
+                false
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Sends a data message for the next stream that has credits
+    fn process_streams(&mut self) {
+        if self.device.stream_ready() {
+            if let Some(mut write_grant) = self.producer.grant(MAX_STREAM_MESSAGE_SIZE) {
+                let size = self.device.poll_streams(&mut write_grant);
+                commit(&mut self.producer, size, write_grant);
+            }
+        }
+    }
+
+    /// Sends the next pending notification
+    fn process_notifications(&mut self) {
+        if self.device.notification_pending() {
+            if let Some(mut write_grant) = self.producer.grant(MAX_NOTIFICATION_SIZE) {
+                let size = self.device.poll_notifications(&mut write_grant);
+                commit(&mut self.producer, size, write_grant);
+            }
+        }
+    }
+
+    /// Sends the queued log records that fit into the transmit queue
+    fn process_log_records(&mut self) {
+        let log_records = match &mut self.log_records {
+            Some(log_records) => log_records,
+            None => return,
+        };
+        while let Some(read_grant) = log_records.read() {
+            match self.producer.grant(read_grant.len()) {
+                Some(mut write_grant) => {
+                    write_grant[..read_grant.len()].copy_from_slice(&read_grant);
+                    commit(&mut self.producer, read_grant.len(), write_grant);
+                    log_records.release_consume(read_grant);
+                },
+                None => {
+                    log_records.release_unread(read_grant);
+                    break;
+                },
+            }
+        }
+    }
+}
+
+/// Queues a message, the message is dropped if it doesn't fit its buffer
+fn commit<P: MessageProducer>(producer: &mut P, size: usize, grant: P::Grant) {
+    if producer.commit_with_size(size, grant).is_err() {
+        warn!("dropped a message of {} bytes", size);
+    }
+}
@@ -0,0 +1,575 @@
+//! Transport-independent command processing
+//!
+//! Takes complete command messages and produces complete response, stream data and notification
+//! messages. The firmware feeds it from the USB queues, the simulated device from memory buffers.
+
+use log::{info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use deadbug_common::hal::{HalError, HalResult, HalErrorKind};
+use core::ops::{Deref, DerefMut};
+use crate::pin_allocator::{PinAllocator, GpioPinSet};
+use crate::streams::StreamTable;
+use crate::notifications::{Notification, NotificationQueue};
+use crate::panic_record::PanicRecord;
+use crate::supervisor::{self, Supervisor};
+use deadbug_common::hal::gpio::GpioPin;
+use deadbug_common::protocol::{CommandHeader, DeviceMessageHeader};
+use deadbug_common::protocol::gpio::{GpioPinInformation, GPIO_ENDPOINT};
+use deadbug_common::protocol::notification::{LogLevel, PinEdgeNotification};
+use deadbug_common::protocol::stream::{StreamDataHeader, STREAM_DATA_HEADER_SIZE, MAX_STREAM_DATA_SIZE};
+use deadbug_common::protocol::system::SYSTEM_ENDPOINT;
+use core::{mem, cmp};
+
+/// Serialized size of `CommandHeader`
+pub const COMMAND_HEADER_SIZE: usize = 2;
+
+/// Serialized size of `DeviceMessageHeader::Response(_, Ok(()))`
+pub const RESPONSE_HEADER_SIZE: usize = 3;
+
+/// Minimal response buffer size, enough for any error response
+pub const MIN_RESPONSE_BUFFER_SIZE: usize = 8;
+
+/// Buffer size needed for a stream data message
+pub const MAX_STREAM_MESSAGE_SIZE: usize = STREAM_DATA_HEADER_SIZE + MAX_STREAM_DATA_SIZE;
+
+/// Upper bound of the serialized size of a command, excluding the header
+const MAX_COMMAND_ENCODING_SIZE: usize = 32;
+
+/// The response buffer is too small, the command must be retried with a buffer of at least
+/// the specified size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeedBuffer(pub usize);
+
+enum CommandError {
+    NeedWriteGrant(usize),
+    Hal(HalError),
+}
+
+impl From<HalError> for CommandError {
+    fn from(e: HalError) -> Self {
+        CommandError::Hal(e)
+    }
+}
+
+/// Deserializes a command received from the host
+///
+/// ssmarshal asserts that the data is long enough in debug builds, so the command is
+/// zero-padded first and rejected if the padding was used.
+fn deserialize_command<T: DeserializeOwned>(command: &[u8]) -> HalResult<T> {
+    let mut buffer = [0; MAX_COMMAND_ENCODING_SIZE];
+    let size = cmp::min(command.len(), buffer.len());
+    buffer[..size].copy_from_slice(&command[..size]);
+    let (command, used) = ssmarshal::deserialize(&buffer)?;
+    if used > size {
+        return Err(HalErrorKind::InvalidParameter.into());
+    }
+    Ok(command)
+}
+
+/// Serializes a part of the response payload
+///
+/// The size is checked by `CommandGrantW::check_size` beforehand, a failure is reported to the
+/// host as a protocol error.
+fn serialize_response<T: Serialize>(buffer: &mut [u8], value: &T) -> Result<usize, CommandError> {
+    ssmarshal::serialize(buffer, value).map_err(|_| HalError::from(HalErrorKind::ProtocolError).into())
+}
+
+/// State shared between command targets
+struct CommandContext<'a, S> {
+    pins: &'a mut PinAllocator<S>,
+    streams: &'a mut StreamTable,
+    notifications: &'a mut NotificationQueue,
+    supervisor: &'a mut Supervisor,
+}
+
+pub struct Device<S> {
+    pins: PinAllocator<S>,
+    streams: StreamTable,
+    notifications: NotificationQueue,
+    supervisor: Supervisor,
+    system_target: SystemCommandTarget,
+    gpio_target: GpioCommandTarget,
+}
+
+impl<S: GpioPinSet> Device<S> {
+    /// Fails with `NoResources` if the board exposes more than `MAX_PINS` pins
+    pub fn new(pins: S) -> HalResult<Self> {
+        Ok(Self {
+            pins: PinAllocator::new(pins)?,
+            streams: StreamTable::new(),
+            notifications: NotificationQueue::new(),
+            supervisor: Supervisor::new(),
+            system_target: SystemCommandTarget::new(),
+            gpio_target: GpioCommandTarget::new(),
+        })
+    }
+
+    pub fn pins(&self) -> &PinAllocator<S> {
+        &self.pins
+    }
+
+    pub fn pins_mut(&mut self) -> &mut PinAllocator<S> {
+        &mut self.pins
+    }
+
+    /// Queues a notification to be sent to the host
+    pub fn notify(&mut self, notification: Notification) {
+        self.notifications.push(notification);
+    }
+
+    /// Sets the panic reported to the host by `SystemCommand::TakeLastPanic`
+    pub fn set_last_panic(&mut self, record: &PanicRecord) {
+        self.system_target.last_panic = record.clone();
+    }
+
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    /// Returns the most verbose level of log records forwarded to the host, `None` if none are
+    pub fn log_level(&self) -> Option<LogLevel> {
+        self.system_target.log_level
+    }
+
+    /// Enters the safe state if the host didn't send a command within the keep-alive timeout
+    ///
+    /// `now` is a free-running millisecond counter, it may wrap around.
+    pub fn poll_supervision(&mut self, now: u32) {
+        if self.supervisor.poll(now) {
+            info!("host keep-alive timed out");
+            self.enter_safe_state();
+        }
+    }
+
+    /// Returns true if the device has work without a command from the host
+    ///
+    /// While it does, `poll_supervision` and `CommandProcessor::process` must be called
+    /// periodically: for the keep-alive timeout, watched pins, streams with credits, pending
+    /// notifications and forwarded log records.
+    pub fn needs_polling(&self) -> bool {
+        self.supervisor.timeout() != 0
+            || self.gpio_target.watched_pins != 0
+            || self.streams.any_ready()
+            || self.notification_pending()
+            || self.system_target.log_level.is_some()
+    }
+
+    /// Reverts all pins to their safe state, closes the streams, stops watching pins and
+    /// forwarding log records
+    ///
+    /// Called when the host is gone. The pins are released, so the next host may claim them.
+    pub fn enter_safe_state(&mut self) {
+        for (index, pin) in self.pins.pin_set_mut().pins_mut().iter_mut().enumerate() {
+            let state = self.supervisor.safe_state(index);
+            if let Err(e) = supervisor::apply_safe_state(pin, state) {
+                warn!("pin {} not in safe state {:?}: {:?}", index, state, e);
+            }
+        }
+        self.pins.release_all();
+        self.streams.close_all();
+        self.gpio_target.watched_pins = 0;
+        self.system_target.log_level = None;
+    }
+
+    /// Processes a command message and writes the response message into the buffer
+    ///
+    /// Returns the response size, 0 if the command is too short to carry a request id and gets
+    /// no response. On error the command isn't executed.
+    pub fn process_command(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, NeedBuffer> {
+        if command.len() < COMMAND_HEADER_SIZE {
+            // Invalid packet
+            return Ok(0);
+        }
+        if response.len() < MIN_RESPONSE_BUFFER_SIZE {
+            return Err(NeedBuffer(MIN_RESPONSE_BUFFER_SIZE));
+        }
+
+        self.supervisor.record_activity();
+
+        let header = CommandHeader {
+            endpoint: command[0],
+            request_id: command[1],
+        };
+        let command = &command[COMMAND_HEADER_SIZE..];
+        if command.is_empty() {
+            // Nothing to execute, but the host waits for the response to the request id
+            let response_header = DeviceMessageHeader::Response(header.request_id, Err(HalErrorKind::ProtocolError));
+            return Ok(ssmarshal::serialize(response, &response_header).unwrap_or(0));
+        }
+        match self.dispatch_command(header.endpoint, command, CommandGrantW(response)) {
+            Ok(payload_size) => {
+                let response_header = DeviceMessageHeader::Response(header.request_id, Ok(()));
+                match ssmarshal::serialize(&mut response[..RESPONSE_HEADER_SIZE], &response_header) {
+                    Ok(_) => Ok(RESPONSE_HEADER_SIZE + payload_size),
+                    Err(_) => Ok(0),
+                }
+            },
+            Err(CommandError::NeedWriteGrant(size)) => {
+                Err(NeedBuffer(cmp::max(MIN_RESPONSE_BUFFER_SIZE, RESPONSE_HEADER_SIZE + size)))
+            },
+            Err(CommandError::Hal(e)) => {
+                let response_header = DeviceMessageHeader::Response(header.request_id, Err(e.kind()));
+                Ok(ssmarshal::serialize(response, &response_header).unwrap_or(0))
+            },
+        }
+    }
+
+    fn dispatch_command(&mut self, endpoint: u8, command: &[u8], response: CommandGrantW) -> Result<usize, CommandError> {
+        let mut context = CommandContext {
+            pins: &mut self.pins,
+            streams: &mut self.streams,
+            notifications: &mut self.notifications,
+            supervisor: &mut self.supervisor,
+        };
+        match endpoint {
+            SYSTEM_ENDPOINT => self.system_target.process_command(&mut context, command, response),
+            GPIO_ENDPOINT => self.gpio_target.process_command(&mut context, command, response),
+            _ => Err(CommandError::Hal(HalErrorKind::UnsupportedCommand.into())),
+        }
+    }
+
+    /// Returns true if some stream is allowed to send data
+    pub fn stream_ready(&self) -> bool {
+        self.streams.any_ready()
+    }
+
+    /// Writes a data message for the next stream that has credits, returns the message size
+    ///
+    /// The buffer must hold at least `MAX_STREAM_MESSAGE_SIZE` bytes.
+    pub fn poll_streams(&mut self, buffer: &mut [u8]) -> usize {
+        if buffer.len() < MAX_STREAM_MESSAGE_SIZE {
+            return 0;
+        }
+        let (id, state) = match self.streams.next_ready() {
+            Some(stream) => stream,
+            None => return 0,
+        };
+        let mut context = CommandContext {
+            pins: &mut self.pins,
+            streams: &mut self.streams,
+            notifications: &mut self.notifications,
+            supervisor: &mut self.supervisor,
+        };
+        let data = &mut buffer[STREAM_DATA_HEADER_SIZE..MAX_STREAM_MESSAGE_SIZE];
+        let result = match state.endpoint {
+            GPIO_ENDPOINT => self.gpio_target.poll_stream(&mut context, data),
+            _ => Err(HalErrorKind::UnsupportedCommand.into()),
+        };
+        match result {
+            Ok(0) => 0,
+            Ok(size) => {
+                let header = DeviceMessageHeader::StreamData(StreamDataHeader {
+                    stream: id,
+                    sequence: state.sequence,
+                });
+                if ssmarshal::serialize(&mut buffer[..STREAM_DATA_HEADER_SIZE], &header).is_err() {
+                    return 0;
+                }
+                self.streams.consume(id);
+                STREAM_DATA_HEADER_SIZE + size
+            },
+            Err(e) => {
+                info!("stream {} closed: {:?}", id, e);
+                self.streams.close(id).ok();
+                self.notifications.push(Notification::Error(e.kind()));
+                0
+            },
+        }
+    }
+
+    /// Lets the targets check for events
+    pub fn poll_events(&mut self) {
+        let mut context = CommandContext {
+            pins: &mut self.pins,
+            streams: &mut self.streams,
+            notifications: &mut self.notifications,
+            supervisor: &mut self.supervisor,
+        };
+        self.gpio_target.poll_events(&mut context);
+    }
+
+    /// Returns true if a notification is waiting to be sent
+    pub fn notification_pending(&self) -> bool {
+        self.notifications.peek().is_some()
+    }
+
+    /// Writes the next pending notification message, returns the message size
+    ///
+    /// The buffer must hold at least `MAX_NOTIFICATION_SIZE` bytes, otherwise the notification
+    /// is dropped.
+    pub fn poll_notifications(&mut self, buffer: &mut [u8]) -> usize {
+        if let Some(notification) = self.notifications.peek() {
+            let size = notification.serialize(buffer).unwrap_or(0);
+            self.notifications.pop();
+            size
+        } else {
+            0
+        }
+    }
+}
+
+/// Buffer for the response payload, excluding the response header
+struct CommandGrantW<'a>(&'a mut [u8]);
+
+impl CommandGrantW<'_> {
+    pub fn check_size(&self, size: usize) -> Result<(), CommandError> {
+        if size <= (self.0.len() - RESPONSE_HEADER_SIZE) {
+            Ok(())
+        } else {
+            Err(CommandError::NeedWriteGrant(size))
+        }
+    }
+}
+
+impl Deref for CommandGrantW<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0[RESPONSE_HEADER_SIZE..]
+    }
+}
+
+impl DerefMut for CommandGrantW<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0[RESPONSE_HEADER_SIZE..]
+    }
+}
+
+trait CommandTarget<S> {
+    #[allow(dead_code)]
+    fn get_descriptor(&self) -> u8;
+
+    fn process_command(&mut self, context: &mut CommandContext<S>, command: &[u8], write_grant: CommandGrantW) -> Result<usize, CommandError>;
+
+    /// Writes the next chunk of stream data into the buffer and returns its size
+    fn poll_stream(&mut self, _context: &mut CommandContext<S>, _buffer: &mut [u8]) -> HalResult<usize> {
+        Err(HalErrorKind::UnsupportedCommand.into())
+    }
+
+    /// Checks for events and queues notifications
+    fn poll_events(&mut self, _context: &mut CommandContext<S>) {
+    }
+}
+
+struct SystemCommandTarget {
+    last_panic: PanicRecord,
+    log_level: Option<LogLevel>,
+}
+
+impl SystemCommandTarget {
+    fn new() -> Self {
+        Self {
+            last_panic: PanicRecord::new(),
+            log_level: None,
+        }
+    }
+}
+
+impl<S: GpioPinSet> CommandTarget<S> for SystemCommandTarget {
+    fn get_descriptor(&self) -> u8 {
+        0
+    }
+
+    fn process_command(&mut self, context: &mut CommandContext<S>, command: &[u8], mut write_grant: CommandGrantW) -> Result<usize, CommandError> {
+        use deadbug_common::protocol::system::SystemCommand;
+
+        let command: SystemCommand = deserialize_command(command)?;
+        info!("system command: {:?}", command);
+        match command {
+            SystemCommand::GetPinOwners => {
+                let owners = context.pins.owners();
+                write_grant.check_size(1 + mem::size_of_val(owners))?;
+
+                // PinAllocator limits the number of pins to MAX_PINS
+                write_grant[0] = owners.len() as u8;
+                let mut offset = 1;
+                for owner in owners {
+                    offset += serialize_response(&mut write_grant[offset..], owner)?;
+                }
+                Ok(offset)
+            },
+            SystemCommand::GrantCredits(stream, credits) => {
+                context.streams.grant_credits(stream, credits)?;
+                Ok(0)
+            },
+            SystemCommand::CloseStream(stream) => {
+                context.streams.close(stream)?;
+                Ok(0)
+            },
+            SystemCommand::TakeLastPanic => {
+                let size = match self.last_panic.message() {
+                    Some(message) => {
+                        write_grant.check_size(message.len())?;
+                        write_grant[..message.len()].copy_from_slice(message.as_bytes());
+                        message.len()
+                    },
+                    None => 0,
+                };
+                self.last_panic.clear();
+                Ok(size)
+            },
+            SystemCommand::KeepAlive => Ok(0),
+            SystemCommand::SetKeepAliveTimeout(timeout) => {
+                context.supervisor.set_timeout(timeout);
+                Ok(0)
+            },
+            SystemCommand::SetSafeState(index, state) => {
+                let pin = context.pins.pin(index)?;
+                if !pin.information().supports(state.mode()) {
+                    return Err(HalError::from(HalErrorKind::InvalidGpioMode).into());
+                }
+                context.supervisor.set_safe_state(index as usize, state);
+                Ok(0)
+            },
+            SystemCommand::SetLogLevel(level) => {
+                self.log_level = level;
+                Ok(0)
+            },
+            SystemCommand::GetLastFault => {
+                let fault = self.last_panic.fault().copied();
+                write_grant.check_size(mem::size_of_val(&fault))?;
+                serialize_response(&mut write_grant, &fault)
+            },
+        }
+    }
+}
+
+struct GpioCommandTarget {
+    /// Bit mask of pins watched for level changes
+    watched_pins: u32,
+    /// Last seen levels of the watched pins
+    levels: u32,
+}
+
+impl GpioCommandTarget {
+    fn new() -> Self {
+        Self {
+            watched_pins: 0,
+            levels: 0,
+        }
+    }
+}
+
+impl<S: GpioPinSet> CommandTarget<S> for GpioCommandTarget {
+    fn get_descriptor(&self) -> u8 {
+        0
+    }
+
+    fn process_command(&mut self, context: &mut CommandContext<S>, command: &[u8], mut write_grant: CommandGrantW) -> Result<usize, CommandError> {
+        use deadbug_common::protocol::gpio::GpioCommand;
+
+        let pins = &mut *context.pins;
+        let command: GpioCommand = deserialize_command(command)?;
+        info!("command: {:?}", command);
+        match command {
+            GpioCommand::EnumeratePins => {
+                let n = pins.len();
+                write_grant.check_size(1 + mem::size_of::<GpioPinInformation>() * n)?;
+
+                // PinAllocator limits the number of pins to MAX_PINS
+                write_grant[0] = n as u8;
+                let mut offset = 1;
+                for pin in pins.pins() {
+                    offset += serialize_response(&mut write_grant[offset..], &pin.information())?;
+                }
+                Ok(offset)
+            },
+            GpioCommand::GetPinMode(index) => {
+                write_grant.check_size(2)?;
+                let pin = pins.pin(index)?;
+                let mode = pin.mode();
+                serialize_response(&mut write_grant, &mode)
+            },
+            GpioCommand::SetPinMode(index, mode) => {
+                let pin = pins.pin_mut(index, GPIO_ENDPOINT)?;
+                pin.set_mode(mode)?;
+                Ok(0)
+            },
+            GpioCommand::SetPinValue(index, value) => {
+                let pin = pins.pin_mut(index, GPIO_ENDPOINT)?;
+                pin.set_output(value)?;
+                Ok(0)
+            },
+            GpioCommand::GetPinValue(index) => {
+                write_grant.check_size(1)?;
+                let pin = pins.pin(index)?;
+                let value = pin.get_input()?;
+                write_grant[0] = value as u8;
+                Ok(1)
+            },
+            GpioCommand::ReleasePin(index) => {
+                pins.release(index, GPIO_ENDPOINT)?;
+                Ok(0)
+            },
+            GpioCommand::StartCapture(credits) => {
+                write_grant.check_size(1)?;
+                let stream = context.streams.open(GPIO_ENDPOINT, credits)?;
+                write_grant[0] = stream;
+                Ok(1)
+            },
+            GpioCommand::WatchPin(index, enable) => {
+                let pin = pins.pin(index)?;
+                if index >= 32 {
+                    return Err(HalError::from(HalErrorKind::InvalidParameter).into());
+                }
+                let mask = 1 << index;
+                if enable {
+                    let level = pin.get_input()?;
+                    self.watched_pins |= mask;
+                    self.levels = (self.levels & !mask) | ((level as u32) << index);
+                } else {
+                    self.watched_pins &= !mask;
+                }
+                Ok(0)
+            },
+        }
+    }
+
+    fn poll_events(&mut self, context: &mut CommandContext<S>) {
+        if self.watched_pins == 0 {
+            return;
+        }
+        for (index, pin) in context.pins.pins().iter().enumerate().take(32) {
+            let mask = 1 << index;
+            if self.watched_pins & mask == 0 {
+                continue;
+            }
+            // Stop watching pins which were switched to output
+            let level = match pin.get_input() {
+                Ok(level) => level,
+                Err(_) => {
+                    self.watched_pins &= !mask;
+                    continue;
+                }
+            };
+            if level != (self.levels & mask != 0) {
+                self.levels ^= mask;
+                context.notifications.push(Notification::PinEdge(PinEdgeNotification {
+                    pin: index as u8,
+                    level,
+                }));
+            }
+        }
+    }
+
+    fn poll_stream(&mut self, context: &mut CommandContext<S>, buffer: &mut [u8]) -> HalResult<usize> {
+        let sample_size = context.pins.len().div_ceil(8);
+        if sample_size == 0 {
+            return Err(HalErrorKind::NoResources.into());
+        }
+        let mut size = 0;
+        for sample in buffer.chunks_exact_mut(sample_size) {
+            for b in sample.iter_mut() {
+                *b = 0;
+            }
+            for (i, pin) in context.pins.pins().iter().enumerate() {
+                if pin.get_input().unwrap_or(false) {
+                    sample[i / 8] |= 1 << (i % 8);
+                }
+            }
+            size += sample_size;
+        }
+        Ok(size)
+    }
+}
@@ -0,0 +1,22 @@
+//! Device side of the bridge protocol, independent of the board
+//!
+//! The command logic is used by the firmware and by the simulated device. The framing of
+//! the serial byte stream (COBS decoding, fragment reassembly and encoding of responses in
+//! `bbqueue` buffers) and of the vendor bulk interface is used by the firmware.
+
+#![no_std]
+
+pub mod bulk;
+pub mod cobs;
+pub mod cobs_tx;
+pub mod command_processor;
+pub mod device;
+pub mod log_records;
+pub mod notifications;
+pub mod packet_processor;
+pub mod panic_record;
+pub mod pin_allocator;
+pub mod streams;
+pub mod supervisor;
+
+pub use device::Device;
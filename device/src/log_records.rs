@@ -0,0 +1,113 @@
+//! Log records forwarded to the host as `NotificationKind::Log` notifications
+//!
+//! The logger serializes complete notification messages into a length-prefixed queue, in the
+//! format of the received packet queue, so `CommandProcessor` reads it with `PacketConsumer`.
+
+use bbqueue::Producer;
+use core::fmt::{self, Write};
+use deadbug_common::protocol::DeviceMessageHeader;
+use deadbug_common::protocol::notification::{LogLevel, LogRecordHeader, NotificationKind};
+
+/// Maximum size of a log notification message, longer messages are truncated
+pub const MAX_LOG_RECORD_SIZE: usize = 128;
+
+/// Longer targets are truncated
+const MAX_TARGET_SIZE: usize = 32;
+
+/// Returns true if a record of the level is forwarded with the `max_level` setting
+pub fn forwarded(level: LogLevel, max_level: Option<LogLevel>) -> bool {
+    matches!(max_level, Some(max_level) if level <= max_level)
+}
+
+/// Writes as much of the text as fits, cutting it at a character boundary
+struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    size: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.buffer.len() - self.size;
+        let mut size = s.len().min(free);
+        while !s.is_char_boundary(size) {
+            size -= 1;
+        }
+        self.buffer[self.size..self.size + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.size += size;
+        Ok(())
+    }
+}
+
+/// Serializes a log notification message, returns the message size
+///
+/// The target and the message are truncated to fit into the buffer.
+pub fn serialize_log_record(buffer: &mut [u8], level: LogLevel, timestamp: u32, target: &str, args: fmt::Arguments) -> Result<usize, ssmarshal::Error> {
+    let header_size = ssmarshal::serialize(buffer, &DeviceMessageHeader::Notification(NotificationKind::Log))?;
+    // The target size is known only after it's written
+    let record_header_size = ssmarshal::serialize(&mut buffer[header_size..], &LogRecordHeader {
+        level,
+        timestamp,
+        target_size: 0,
+    })?;
+    let text_start = header_size + record_header_size;
+    let end = buffer.len().min(text_start + MAX_TARGET_SIZE);
+
+    let mut writer = TruncatingWriter {
+        buffer: &mut buffer[text_start..end],
+        size: 0,
+    };
+    writer.write_str(target).ok();
+    let target_size = writer.size;
+
+    let mut writer = TruncatingWriter {
+        buffer: &mut buffer[text_start + target_size..],
+        size: 0,
+    };
+    writer.write_fmt(args).ok();
+    let message_size = writer.size;
+
+    ssmarshal::serialize(&mut buffer[header_size..], &LogRecordHeader {
+        level,
+        timestamp,
+        target_size: target_size as u8,
+    })?;
+    Ok(text_start + target_size + message_size)
+}
+
+/// Queues log records for `CommandProcessor`
+pub struct LogRecordProducer {
+    producer: Producer,
+    /// A record was dropped because the queue was full
+    overrun: bool,
+}
+
+impl LogRecordProducer {
+    pub fn new(producer: Producer) -> Self {
+        Self {
+            producer,
+            overrun: false,
+        }
+    }
+
+    /// Returns true if a record was dropped since the last call
+    pub fn take_overrun(&mut self) -> bool {
+        core::mem::replace(&mut self.overrun, false)
+    }
+
+    /// Queues a message serialized by `serialize_log_record`
+    ///
+    /// The caller formats the record beforehand, so that a logger sharing the producer
+    /// between interrupt priorities only has to lock it for the copy.
+    pub fn write(&mut self, message: &[u8]) {
+        let mut grant = match self.producer.grant(2 + message.len()) {
+            Ok(grant) => grant,
+            Err(_) => {
+                self.overrun = true;
+                return;
+            },
+        };
+        grant[..2].copy_from_slice(&(message.len() as u16).to_ne_bytes());
+        grant[2..].copy_from_slice(message);
+        self.producer.commit(2 + message.len(), grant);
+    }
+}
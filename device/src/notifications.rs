@@ -0,0 +1,87 @@
+use deadbug_common::hal::HalErrorKind;
+use deadbug_common::protocol::DeviceMessageHeader;
+use deadbug_common::protocol::notification::{NotificationKind, PinEdgeNotification, BufferKind};
+
+/// Maximum serialized size of a notification message
+pub const MAX_NOTIFICATION_SIZE: usize = 8;
+
+const QUEUE_SIZE: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum Notification {
+    PinEdge(PinEdgeNotification),
+    BufferOverrun(BufferKind),
+    Error(HalErrorKind),
+}
+
+impl Notification {
+    /// Serializes the message header and the payload, returns the message size
+    pub fn serialize(&self, buffer: &mut [u8]) -> Result<usize, ssmarshal::Error> {
+        let kind = match self {
+            Notification::PinEdge(_) => NotificationKind::PinEdge,
+            Notification::BufferOverrun(_) => NotificationKind::BufferOverrun,
+            Notification::Error(_) => NotificationKind::Error,
+        };
+        let header_size = ssmarshal::serialize(buffer, &DeviceMessageHeader::Notification(kind))?;
+        let payload = &mut buffer[header_size..];
+        let payload_size = match self {
+            Notification::PinEdge(edge) => ssmarshal::serialize(payload, edge)?,
+            Notification::BufferOverrun(buffer) => ssmarshal::serialize(payload, buffer)?,
+            Notification::Error(kind) => ssmarshal::serialize(payload, kind)?,
+        };
+        Ok(header_size + payload_size)
+    }
+}
+
+/// Notifications waiting to be sent
+///
+/// If the queue is full, new notifications are dropped and reported with a single
+/// `BufferKind::Notifications` overrun notification once the queue is drained.
+#[derive(Default)]
+pub struct NotificationQueue {
+    items: [Option<Notification>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    overrun: bool,
+}
+
+impl NotificationQueue {
+    pub fn new() -> Self {
+        Self {
+            items: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            overrun: false,
+        }
+    }
+
+    pub fn push(&mut self, notification: Notification) {
+        if self.len == QUEUE_SIZE {
+            self.overrun = true;
+        } else {
+            self.items[(self.head + self.len) % QUEUE_SIZE] = Some(notification);
+            self.len += 1;
+        }
+    }
+
+    pub fn peek(&self) -> Option<Notification> {
+        if self.len > 0 {
+            self.items[self.head]
+        } else if self.overrun {
+            Some(Notification::BufferOverrun(BufferKind::Notifications))
+        } else {
+            None
+        }
+    }
+
+    /// Removes the notification returned by `peek`
+    pub fn pop(&mut self) {
+        if self.len > 0 {
+            self.items[self.head] = None;
+            self.head = (self.head + 1) % QUEUE_SIZE;
+            self.len -= 1;
+        } else {
+            self.overrun = false;
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use bbqueue::{Consumer, Producer, GrantW, GrantR};
+use core::mem;
+use core::ops::Deref;
+
+use deadbug_common::protocol::fragment::{Accepted, FragmentHeader, FragmentSequencer};
+
+#[derive(PartialEq)]
+enum PacketProcessorState {
+    Discarding,
+    WaitingForGrant,
+    Processing(GrantW, usize),
+}
+
+/// Decodes COBS-framed fragments and reassembles them into length-prefixed messages
+pub struct PacketProcessor {
+    consumer: Consumer,
+    producer: Producer,
+    state: PacketProcessorState,
+    max_data_size: usize,
+    max_message_size: usize,
+    /// Size of the reassembled part of the current message
+    message_size: usize,
+    sequencer: FragmentSequencer,
+    /// Some data was dropped because it didn't fit into the buffer, or a message was cut off
+    overrun: bool,
+}
+
+impl PacketProcessor {
+    pub fn new(consumer: Consumer, producer: Producer, max_packet_size: usize, max_message_size: usize) -> Self {
+        Self {
+            consumer,
+            producer,
+            state: PacketProcessorState::Discarding,
+            max_data_size: cobs::max_encoding_length(max_packet_size) + 1,
+            max_message_size,
+            message_size: 0,
+            sequencer: FragmentSequencer::new(),
+            overrun: false,
+        }
+    }
+
+    /// Returns true if some data was dropped since the last call
+    pub fn take_overrun(&mut self) -> bool {
+        mem::replace(&mut self.overrun, false)
+    }
+
+    fn discard_message(&mut self) {
+        self.message_size = 0;
+        self.sequencer.reset();
+    }
+
+    /// Processes the next chunk of received data
+    ///
+    /// Returns false if there was nothing to do, i.e. no data was received or the packet queue
+    /// is full.
+    pub fn process(&mut self) -> bool {
+        if let Ok(grant_r) = self.consumer.read() {
+            let zero_pos = grant_r.iter().enumerate().find(|&(_, v)| *v == 0).map(|(i, _)| i);
+            let chunk_size = zero_pos.map(|i| i + 1).unwrap_or(grant_r.len());
+
+            match &mut self.state {
+                PacketProcessorState::Discarding => {
+                    if zero_pos.is_some() {
+                        self.state = PacketProcessorState::WaitingForGrant;
+                    }
+                    self.consumer.release(chunk_size, grant_r);
+                },
+                PacketProcessorState::WaitingForGrant => {
+                    self.consumer.release(0, grant_r);
+                    match self.producer.grant(2 + self.max_message_size + self.max_data_size) {
+                        Ok(grant_w) => self.state = PacketProcessorState::Processing(grant_w, 2),
+                        Err(_) => return false,
+                    }
+                },
+                PacketProcessorState::Processing(_, _) => {
+                    let state = mem::replace(&mut self.state, PacketProcessorState::WaitingForGrant);
+                    if let PacketProcessorState::Processing(mut grant_w, data_size) = state {
+                        let new_data_size = data_size + chunk_size;
+                        let fragment_start = 2 + self.message_size;
+
+                        if new_data_size <= grant_w.len() {
+                            // Copy raw data
+                            grant_w[data_size..data_size + chunk_size].copy_from_slice(&grant_r[..chunk_size]);
+
+                            if zero_pos.is_none() {
+                                // Continue processing, update state
+                                self.state = PacketProcessorState::Processing(grant_w, new_data_size);
+                            } else {
+                                // Zero byte found, decode
+                                match cobs::decode_in_place(&mut grant_w[fragment_start..new_data_size - 1]) {
+                                    Ok(0) => {
+                                        // Skip zero-length packet
+                                        self.state = PacketProcessorState::Processing(grant_w, fragment_start);
+                                    },
+                                    Ok(packet_size) => {
+                                        let header = FragmentHeader::from_byte(grant_w[fragment_start]);
+                                        let payload_size = packet_size - 1;
+                                        let accepted = self.sequencer.accept(header);
+                                        if let Ok(Accepted { restarted: true, .. }) = accepted {
+                                            // The previous message was cut off, report it as lost
+                                            self.message_size = 0;
+                                            self.overrun = true;
+                                        }
+                                        match accepted {
+                                            Ok(Accepted { complete, .. }) if self.message_size + payload_size <= self.max_message_size => {
+                                                // Strip the fragment header
+                                                grant_w.copy_within(fragment_start + 1..fragment_start + packet_size, 2 + self.message_size);
+                                                self.message_size += payload_size;
+
+                                                if !complete {
+                                                    self.state = PacketProcessorState::Processing(grant_w, 2 + self.message_size);
+                                                } else if self.message_size > 0 {
+                                                    // Write the actual message length
+                                                    let len_bytes = (self.message_size as u16).to_ne_bytes();
+                                                    grant_w[..2].copy_from_slice(&len_bytes);
+
+                                                    // Commit the message
+                                                    self.producer.commit(2 + self.message_size, grant_w);
+                                                    self.message_size = 0;
+                                                } else {
+                                                    // Discard zero-length message
+                                                    self.producer.commit(0, grant_w);
+                                                }
+                                            },
+                                            Ok(_) => {
+                                                // Oversized message, discard it
+                                                self.producer.commit(0, grant_w);
+                                                self.discard_message();
+                                                self.overrun = true;
+                                            },
+                                            Err(_) => {
+                                                // Out-of-order fragment, discard the message
+                                                self.producer.commit(0, grant_w);
+                                                self.discard_message();
+                                            },
+                                        }
+                                    },
+                                    Err(_) => {
+                                        // Decoding error, discard the message
+                                        self.producer.commit(0, grant_w);
+                                        self.discard_message();
+                                    }
+                                }
+                            }
+                        } else {
+                            self.producer.commit(0, grant_w);
+                            self.discard_message();
+                            self.overrun = true;
+                            if zero_pos.is_none() {
+                                // Skip the rest of the packet
+                                self.state = PacketProcessorState::Discarding;
+                            }
+                        }
+
+                        // Input data chunk is either processed or discarded, release it
+                        self.consumer.release(chunk_size, grant_r);
+                    } else {
+                        unreachable!();
+                    }
+                },
+            };
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct PacketConsumerGrantR {
+    grant: GrantR,
+    packet_size: usize,
+}
+
+impl Deref for PacketConsumerGrantR {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.grant[2..2 + self.packet_size]
+    }
+}
+
+pub struct PacketConsumer {
+    consumer: Consumer,
+}
+
+
+impl PacketConsumer {
+    pub fn new(consumer: Consumer) -> Self {
+        Self {
+            consumer
+        }
+    }
+
+    pub fn read(&mut self) -> Option<PacketConsumerGrantR> {
+        if let Ok(grant) = self.consumer.read() {
+            if grant.len() >= 2 {
+                let packet_size = u16::from_ne_bytes([grant[0], grant[1]]) as usize;
+                if 2 + packet_size <= grant.len() {
+                    return Some(PacketConsumerGrantR {
+                        grant,
+                        packet_size,
+                    })
+                }
+            }
+
+            self.consumer.release(0, grant);
+        }
+        None
+    }
+
+    pub fn release_consume(&mut self, grant: PacketConsumerGrantR) {
+        self.consumer.release(2 + grant.packet_size, grant.grant);
+    }
+
+    pub fn release_unread(&mut self, grant: PacketConsumerGrantR) {
+        self.consumer.release(0, grant.grant);
+    }
+}
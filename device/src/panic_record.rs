@@ -0,0 +1,111 @@
+//! Panic message kept over a reset
+//!
+//! The firmware places a record in RAM that isn't initialized on startup, writes the panic
+//! message into it and resets. After the reset the message is handed to `Device`, which reports
+//! it to the host. A hard fault records the fault registers along with a message.
+
+use core::{cmp, fmt, str};
+use deadbug_common::protocol::system::FaultRegisters;
+
+/// Maximum size of the stored message, longer messages are truncated
+pub const MAX_PANIC_MESSAGE_SIZE: usize = 120;
+
+/// Marks a valid record, anything else is left over from power-on
+const PANIC_RECORD_MAGIC: u32 = 0x9a1c_dead;
+/// Marks valid fault registers
+const FAULT_RECORD_MAGIC: u32 = 0xfa17_dead;
+
+#[derive(Clone)]
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    size: u32,
+    message: [u8; MAX_PANIC_MESSAGE_SIZE],
+    fault_magic: u32,
+    fault: FaultRegisters,
+}
+
+impl PanicRecord {
+    pub const fn new() -> Self {
+        Self {
+            magic: 0,
+            size: 0,
+            message: [0; MAX_PANIC_MESSAGE_SIZE],
+            fault_magic: 0,
+            fault: FaultRegisters {
+                r0: 0,
+                r1: 0,
+                r2: 0,
+                r3: 0,
+                r12: 0,
+                lr: 0,
+                pc: 0,
+                xpsr: 0,
+                cfsr: 0,
+                hfsr: 0,
+                mmfar: 0,
+                bfar: 0,
+            },
+        }
+    }
+
+    /// Starts a new empty message without fault registers
+    pub fn start(&mut self) {
+        self.magic = PANIC_RECORD_MAGIC;
+        self.size = 0;
+        self.fault_magic = 0;
+    }
+
+    /// Invalidates the record
+    pub fn clear(&mut self) {
+        self.magic = 0;
+        self.size = 0;
+        self.fault_magic = 0;
+    }
+
+    /// Stores the registers of a hard fault, the message is started separately
+    pub fn set_fault(&mut self, registers: FaultRegisters) {
+        self.fault = registers;
+        self.fault_magic = FAULT_RECORD_MAGIC;
+    }
+
+    /// Returns the fault registers if the record holds a hard fault
+    pub fn fault(&self) -> Option<&FaultRegisters> {
+        if self.magic != PANIC_RECORD_MAGIC || self.fault_magic != FAULT_RECORD_MAGIC {
+            return None;
+        }
+        Some(&self.fault)
+    }
+
+    /// Returns the message if the record is valid
+    ///
+    /// Truncation may have split a character, the message ends before it.
+    pub fn message(&self) -> Option<&str> {
+        if self.magic != PANIC_RECORD_MAGIC {
+            return None;
+        }
+        let size = cmp::min(self.size as usize, MAX_PANIC_MESSAGE_SIZE);
+        let message = &self.message[..size];
+        match str::from_utf8(message) {
+            Ok(message) => Some(message),
+            Err(e) => str::from_utf8(&message[..e.valid_up_to()]).ok(),
+        }
+    }
+}
+
+impl Default for PanicRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Appends to the message, silently truncating it
+impl fmt::Write for PanicRecord {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let offset = cmp::min(self.size as usize, MAX_PANIC_MESSAGE_SIZE);
+        let size = cmp::min(s.len(), MAX_PANIC_MESSAGE_SIZE - offset);
+        self.message[offset..offset + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.size = (offset + size) as u32;
+        Ok(())
+    }
+}
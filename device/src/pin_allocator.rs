@@ -0,0 +1,92 @@
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::{GpioPin, GpioPinMode};
+
+/// Maximum number of pins exposed by a board
+pub const MAX_PINS: usize = 32;
+
+/// Pins exposed by a board, in enumeration order
+pub trait GpioPinSet {
+    type Pin: GpioPin;
+
+    fn pins(&self) -> &[Self::Pin];
+
+    fn pins_mut(&mut self) -> &mut [Self::Pin];
+}
+
+/// Keeps track of the endpoint owning each board pin
+///
+/// Any endpoint may inspect a pin, but only the owner may reconfigure it.
+/// A free pin is claimed by the first endpoint that reconfigures it.
+pub struct PinAllocator<S> {
+    pins: S,
+    owners: [Option<u8>; MAX_PINS],
+}
+
+impl<S: GpioPinSet> PinAllocator<S> {
+    /// Fails with `NoResources` if the board exposes more than `MAX_PINS` pins
+    pub fn new(pins: S) -> HalResult<Self> {
+        if pins.pins().len() > MAX_PINS {
+            return Err(HalErrorKind::NoResources.into());
+        }
+        Ok(Self {
+            pins,
+            owners: [None; MAX_PINS],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.pins.pins().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pins(&self) -> &[S::Pin] {
+        self.pins.pins()
+    }
+
+    /// Returns the pin set, bypassing the ownership checks
+    pub fn pin_set_mut(&mut self) -> &mut S {
+        &mut self.pins
+    }
+
+    pub fn owners(&self) -> &[Option<u8>] {
+        &self.owners[..self.len()]
+    }
+
+    pub fn pin(&self, index: u8) -> HalResult<&S::Pin> {
+        self.pins.pins().get(index as usize).ok_or_else(|| HalErrorKind::InvalidParameter.into())
+    }
+
+    /// Claims the pin for the endpoint and returns it for reconfiguration
+    pub fn pin_mut(&mut self, index: u8, endpoint: u8) -> HalResult<&mut S::Pin> {
+        self.claim(index, endpoint)?;
+        self.pins.pins_mut().get_mut(index as usize).ok_or_else(|| HalErrorKind::InvalidParameter.into())
+    }
+
+    pub fn claim(&mut self, index: u8, endpoint: u8) -> HalResult<()> {
+        let count = self.len();
+        let owner = self.owners[..count].get_mut(index as usize).ok_or(HalErrorKind::InvalidParameter)?;
+        match owner {
+            Some(e) if *e != endpoint => Err(HalErrorKind::PinBusy.into()),
+            _ => {
+                *owner = Some(endpoint);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reverts the pin to floating input and makes it free
+    pub fn release(&mut self, index: u8, endpoint: u8) -> HalResult<()> {
+        let pin = self.pin_mut(index, endpoint)?;
+        pin.set_mode(GpioPinMode::FloatingInput)?;
+        self.owners[index as usize] = None;
+        Ok(())
+    }
+
+    /// Makes all pins free, leaving them in their current mode
+    pub fn release_all(&mut self) {
+        self.owners = [None; MAX_PINS];
+    }
+}
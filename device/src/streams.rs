@@ -0,0 +1,88 @@
+use deadbug_common::hal::{HalResult, HalErrorKind};
+
+const MAX_STREAMS: usize = 4;
+
+#[derive(Clone, Copy)]
+pub struct StreamState {
+    pub endpoint: u8,
+    pub credits: u16,
+    pub sequence: u16,
+}
+
+/// Open streams and their flow control state
+#[derive(Default)]
+pub struct StreamTable {
+    streams: [Option<StreamState>; MAX_STREAMS],
+    /// Stream to check first on the next poll, for round-robin scheduling
+    next_poll: usize,
+}
+
+impl StreamTable {
+    pub fn new() -> Self {
+        Self {
+            streams: [None; MAX_STREAMS],
+            next_poll: 0,
+        }
+    }
+
+    pub fn open(&mut self, endpoint: u8, credits: u16) -> HalResult<u8> {
+        let (id, slot) = self.streams.iter_mut().enumerate()
+            .find(|(_, s)| s.is_none())
+            .ok_or(HalErrorKind::NoResources)?;
+        *slot = Some(StreamState {
+            endpoint,
+            credits,
+            sequence: 0,
+        });
+        Ok(id as u8)
+    }
+
+    pub fn close(&mut self, id: u8) -> HalResult<()> {
+        let slot = self.streams.get_mut(id as usize).ok_or(HalErrorKind::InvalidParameter)?;
+        slot.take().ok_or(HalErrorKind::InvalidParameter)?;
+        Ok(())
+    }
+
+    pub fn close_all(&mut self) {
+        self.streams = [None; MAX_STREAMS];
+    }
+
+    pub fn grant_credits(&mut self, id: u8, credits: u16) -> HalResult<()> {
+        let stream = self.get_mut(id)?;
+        stream.credits = stream.credits.saturating_add(credits);
+        Ok(())
+    }
+
+    fn get_mut(&mut self, id: u8) -> HalResult<&mut StreamState> {
+        self.streams.get_mut(id as usize)
+            .and_then(|s| s.as_mut())
+            .ok_or_else(|| HalErrorKind::InvalidParameter.into())
+    }
+
+    /// Returns true if any stream is allowed to send data
+    pub fn any_ready(&self) -> bool {
+        self.streams.iter().flatten().any(|state| state.credits > 0)
+    }
+
+    /// Returns the next stream which is allowed to send data
+    pub fn next_ready(&mut self) -> Option<(u8, StreamState)> {
+        for i in 0..MAX_STREAMS {
+            let id = (self.next_poll + i) % MAX_STREAMS;
+            if let Some(state) = self.streams[id] {
+                if state.credits > 0 {
+                    self.next_poll = (id + 1) % MAX_STREAMS;
+                    return Some((id as u8, state));
+                }
+            }
+        }
+        None
+    }
+
+    /// Accounts for a sent data message
+    pub fn consume(&mut self, id: u8) {
+        if let Ok(stream) = self.get_mut(id) {
+            stream.credits -= 1;
+            stream.sequence = stream.sequence.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Host connection supervision
+//!
+//! Outputs left driven by a host that went away may damage the attached hardware. The host
+//! proves it's alive by sending commands, `SystemCommand::KeepAlive` when it has nothing else
+//! to do. If it stays silent for longer than the keep-alive timeout, the pins revert to their
+//! configured safe state.
+
+use deadbug_common::hal::HalResult;
+use deadbug_common::hal::gpio::{GpioPin, GpioPinMode};
+use deadbug_common::protocol::system::SafePinState;
+use crate::pin_allocator::MAX_PINS;
+
+pub struct Supervisor {
+    safe_states: [SafePinState; MAX_PINS],
+    /// Keep-alive timeout in milliseconds, 0 if disabled
+    timeout: u16,
+    /// Time of the last command seen by `poll`
+    last_activity: u32,
+    /// A command was received since the last poll
+    activity: bool,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            safe_states: [SafePinState::FloatingInput; MAX_PINS],
+            timeout: 0,
+            last_activity: 0,
+            activity: false,
+        }
+    }
+
+    pub fn safe_state(&self, index: usize) -> SafePinState {
+        self.safe_states[index]
+    }
+
+    /// The caller checks that the pin exists
+    pub fn set_safe_state(&mut self, index: usize, state: SafePinState) {
+        self.safe_states[index] = state;
+    }
+
+    pub fn timeout(&self) -> u16 {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: u16) {
+        self.timeout = timeout;
+    }
+
+    /// Notes that a command was received
+    pub fn record_activity(&mut self) {
+        self.activity = true;
+    }
+
+    /// Returns true if the keep-alive timeout expired
+    ///
+    /// The timeout is disabled when it expires, the next host enables it again.
+    /// `now` is a free-running millisecond counter, it may wrap around.
+    pub fn poll(&mut self, now: u32) -> bool {
+        if self.activity {
+            self.activity = false;
+            self.last_activity = now;
+            return false;
+        }
+        if self.timeout != 0 && now.wrapping_sub(self.last_activity) >= u32::from(self.timeout) {
+            self.timeout = 0;
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Puts the pin into the safe state
+///
+/// Pins which don't support the output mode are left floating.
+pub fn apply_safe_state<P: GpioPin>(pin: &mut P, state: SafePinState) -> HalResult<()> {
+    let mode = if pin.information().supports(state.mode()) {
+        state.mode()
+    } else {
+        GpioPinMode::FloatingInput
+    };
+    pin.set_mode(mode)?;
+    match (mode, state) {
+        (GpioPinMode::PushPullOutput, SafePinState::OutputHigh) => pin.set_output(true),
+        (GpioPinMode::PushPullOutput, _) => pin.set_output(false),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,230 @@
+use proptest::prelude::*;
+use deadbug_common::protocol::fragment::{FragmentHeader, BULK_PACKET_SIZE, MAX_COMMAND_SIZE};
+use deadbug_device::bulk::{BulkRx, BulkTxConsumer, BulkTxProducer};
+use deadbug_device::command_processor::MessageProducer;
+use deadbug_device::packet_processor::PacketConsumer;
+
+mod common;
+
+use common::queue;
+
+/// Sends messages through the transmit queue, returns the USB packets
+fn send(messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let (producer, consumer) = queue(2048);
+    let mut producer = BulkTxProducer::new(producer);
+    let mut consumer = BulkTxConsumer::new(consumer);
+    for message in messages {
+        let mut grant = producer.grant(message.len()).unwrap();
+        grant[..message.len()].copy_from_slice(message);
+        producer.commit_with_size(message.len(), grant).unwrap();
+    }
+    let mut packets = Vec::new();
+    while consumer.write_packet(|packet| {
+        packets.push(packet.to_vec());
+        true
+    }) {}
+    packets
+}
+
+/// Splits USB packets into transfers
+fn transfers(packets: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut transfers = Vec::new();
+    let mut transfer = Vec::new();
+    for packet in packets {
+        assert!(packet.len() <= BULK_PACKET_SIZE);
+        transfer.extend_from_slice(packet);
+        if packet.len() < BULK_PACKET_SIZE || transfer.len() == 128 {
+            transfers.push(std::mem::take(&mut transfer));
+        }
+    }
+    assert!(transfer.is_empty(), "incomplete transfer");
+    transfers
+}
+
+struct Receiver {
+    rx: BulkRx,
+    messages: PacketConsumer,
+}
+
+impl Receiver {
+    fn new(queue_size: usize) -> Self {
+        let (producer, consumer) = queue(queue_size);
+        Self {
+            rx: BulkRx::new(producer, MAX_COMMAND_SIZE),
+            messages: PacketConsumer::new(consumer),
+        }
+    }
+
+    /// Returns false if the packet wasn't accepted
+    fn receive(&mut self, packet: &[u8]) -> bool {
+        match self.rx.packet_buffer() {
+            Some(buffer) => {
+                buffer[..packet.len()].copy_from_slice(packet);
+                self.rx.packet_received(packet.len());
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn take_messages(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(grant) = self.messages.read() {
+            messages.push(grant.to_vec());
+            self.messages.release_consume(grant);
+        }
+        messages
+    }
+}
+
+/// Splits a transfer into USB packets the way the host does
+fn host_packets(transfer: &[u8]) -> Vec<Vec<u8>> {
+    let mut packets: Vec<_> = transfer.chunks(BULK_PACKET_SIZE).map(|chunk| chunk.to_vec()).collect();
+    if transfer.len().is_multiple_of(BULK_PACKET_SIZE) && transfer.len() < 128 {
+        packets.push(Vec::new());
+    }
+    packets
+}
+
+fn fragment(more: bool, sequence: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![FragmentHeader { more, sequence }.to_byte()];
+    data.extend_from_slice(payload);
+    data
+}
+
+#[test]
+fn one_transfer_per_fragment() {
+    let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    let packets = send(std::slice::from_ref(&message));
+    let sizes: Vec<_> = packets.iter().map(Vec::len).collect();
+    assert_eq!(sizes, vec![64, 64, 64, 64, 47]);
+
+    let transfers = transfers(&packets);
+    assert_eq!(transfers.len(), 3);
+    assert_eq!(transfers[0], fragment(true, 0, &message[..127]));
+    assert_eq!(transfers[2], fragment(false, 2, &message[254..]));
+}
+
+#[test]
+fn zero_length_packet_after_full_packet() {
+    let packets = send(&[vec![7; 63], vec![8; 3]]);
+    let sizes: Vec<_> = packets.iter().map(Vec::len).collect();
+    assert_eq!(sizes, vec![64, 0, 4]);
+}
+
+#[test]
+fn discard_empty_commit() {
+    let (producer, consumer) = queue(256);
+    let mut producer = BulkTxProducer::new(producer);
+    let mut consumer = BulkTxConsumer::new(consumer);
+    let grant = producer.grant(16).unwrap();
+    producer.commit_with_size(0, grant).unwrap();
+    assert!(!consumer.write_packet(|_| true));
+}
+
+#[test]
+fn reject_oversized_commit() {
+    let (producer, consumer) = queue(256);
+    let mut producer = BulkTxProducer::new(producer);
+    let mut consumer = BulkTxConsumer::new(consumer);
+    let grant = producer.grant(4).unwrap();
+    assert!(producer.commit_with_size(200, grant).is_err());
+    assert!(!consumer.write_packet(|_| true));
+}
+
+#[test]
+fn retry_unsent_packet() {
+    let (producer, consumer) = queue(256);
+    let mut producer = BulkTxProducer::new(producer);
+    let mut consumer = BulkTxConsumer::new(consumer);
+    let mut grant = producer.grant(2).unwrap();
+    grant[..2].copy_from_slice(&[1, 2]);
+    producer.commit_with_size(2, grant).unwrap();
+
+    assert!(!consumer.write_packet(|_| false));
+    let mut sent = Vec::new();
+    assert!(consumer.write_packet(|packet| {
+        sent = packet.to_vec();
+        true
+    }));
+    assert_eq!(sent, fragment(false, 0, &[1, 2]));
+}
+
+#[test]
+fn receive_multi_fragment_message() {
+    let mut receiver = Receiver::new(1024);
+    let message: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    for transfer in [fragment(true, 0, &message[..127]), fragment(false, 1, &message[127..])].iter() {
+        for packet in host_packets(transfer) {
+            assert!(receiver.receive(&packet));
+        }
+    }
+    assert_eq!(receiver.take_messages(), vec![message]);
+}
+
+#[test]
+fn discard_out_of_order_fragment() {
+    let mut receiver = Receiver::new(1024);
+    assert!(receiver.receive(&fragment(true, 0, &[1])));
+    assert!(receiver.receive(&fragment(false, 2, &[2])));
+    assert!(receiver.receive(&fragment(false, 0, &[3])));
+    assert_eq!(receiver.take_messages(), vec![vec![3]]);
+    assert!(!receiver.rx.take_overrun());
+}
+
+#[test]
+fn restart_interrupted_message() {
+    let mut receiver = Receiver::new(1024);
+    assert!(receiver.receive(&fragment(true, 0, &[1])));
+    assert!(receiver.receive(&fragment(true, 1, &[2])));
+    // The sequence restarts: the partial message is dropped and reported
+    assert!(receiver.receive(&fragment(false, 0, &[3])));
+    assert!(receiver.receive(&fragment(false, 0, &[4])));
+    assert_eq!(receiver.take_messages(), vec![vec![3], vec![4]]);
+    assert!(receiver.rx.take_overrun());
+    assert!(!receiver.rx.take_overrun());
+}
+
+#[test]
+fn report_oversized_message() {
+    let mut receiver = Receiver::new(1024);
+    for sequence in 0..3 {
+        for packet in host_packets(&fragment(true, sequence, &[0; 127])) {
+            receiver.receive(&packet);
+        }
+    }
+    assert!(receiver.take_messages().is_empty());
+    assert!(receiver.rx.take_overrun());
+}
+
+#[test]
+fn hold_packet_until_queue_has_space() {
+    // Three reassembly buffers fit before the queue has to wrap
+    let mut receiver = Receiver::new(600);
+    for i in 0..4 {
+        assert!(receiver.receive(&fragment(false, 0, &[i; 127])));
+    }
+    assert!(!receiver.receive(&fragment(false, 0, &[4])));
+    assert!(!receiver.rx.process());
+    assert_eq!(receiver.take_messages().len(), 3);
+
+    assert!(receiver.rx.process());
+    assert!(receiver.receive(&fragment(false, 0, &[4])));
+    assert_eq!(receiver.take_messages(), vec![vec![3; 127]]);
+    assert!(receiver.rx.process());
+    assert_eq!(receiver.take_messages(), vec![vec![4]]);
+}
+
+proptest! {
+    #[test]
+    fn roundtrip(messages in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..MAX_COMMAND_SIZE), 1..6)) {
+        let packets = send(&messages);
+        let mut receiver = Receiver::new(2048);
+        let mut received = Vec::new();
+        for packet in &packets {
+            prop_assert!(receiver.receive(packet));
+            received.extend(receiver.take_messages());
+        }
+        prop_assert_eq!(received, messages);
+    }
+}
@@ -0,0 +1,154 @@
+use proptest::prelude::*;
+use deadbug_device::cobs::{CobsDecoder, DecoderStatus, cobs_encode_fragment_in_place};
+
+mod common;
+
+use common::cobs_encode;
+
+/// Feeds the chunks to the decoder, returns the decoded frames and the number of errors
+fn decode_chunks(decoder: &mut CobsDecoder, chunks: &[&[u8]]) -> (Vec<Vec<u8>>, usize) {
+    let mut frames = Vec::new();
+    let mut errors = 0;
+    let mut frame = Vec::new();
+    for chunk in chunks {
+        let mut buffer = chunk.to_vec();
+        let mut offset = 0;
+        while offset < buffer.len() {
+            let (raw_size, data_size, status) = decoder.decode(&mut buffer[offset..]);
+            frame.extend_from_slice(&buffer[offset..offset + data_size]);
+            offset += raw_size;
+            match status {
+                DecoderStatus::InProgress => {},
+                DecoderStatus::Finished => frames.push(std::mem::take(&mut frame)),
+                DecoderStatus::Error => {
+                    errors += 1;
+                    frame.clear();
+                },
+            }
+        }
+    }
+    (frames, errors)
+}
+
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![0];
+    encoded.extend(cobs_encode(data));
+    encoded.push(0);
+    encoded
+}
+
+#[test]
+fn decode_single_frame() {
+    let mut decoder = CobsDecoder::new();
+    let data = frame(&[1, 0, 2, 3, 0, 0]);
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![vec![1, 0, 2, 3, 0, 0]], 0));
+}
+
+#[test]
+fn discard_until_first_zero() {
+    let mut decoder = CobsDecoder::new();
+    let mut data = vec![3, 1, 2];
+    data.extend(frame(&[4, 5]));
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![vec![4, 5]], 0));
+}
+
+#[test]
+fn skip_empty_frames() {
+    let mut decoder = CobsDecoder::new();
+    let mut data = vec![0, 0, 0];
+    data.extend(frame(&[7]));
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![vec![7]], 0));
+}
+
+#[test]
+fn decode_partial_chunks() {
+    let mut decoder = CobsDecoder::new();
+    let data = frame(&[1, 2, 0, 3]);
+    let chunks: Vec<_> = data.chunks(1).collect();
+    assert_eq!(decode_chunks(&mut decoder, &chunks), (vec![vec![1, 2, 0, 3]], 0));
+}
+
+#[test]
+fn decode_back_to_back_frames() {
+    let mut decoder = CobsDecoder::new();
+    let mut data = frame(&[1]);
+    data.extend_from_slice(&cobs_encode(&[2, 0]));
+    data.push(0);
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![vec![1], vec![2, 0]], 0));
+}
+
+#[test]
+fn decode_truncated_block() {
+    let mut decoder = CobsDecoder::new();
+    // The code byte announces 4 bytes, the frame ends after 2
+    let mut data = vec![0, 5, 1, 2, 0];
+    data.extend(cobs_encode(&[9]));
+    data.push(0);
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![vec![9]], 1));
+}
+
+#[test]
+fn decode_long_runs() {
+    let mut decoder = CobsDecoder::new();
+    let message: Vec<u8> = (0..600).map(|i| (i % 255 + 1) as u8).collect();
+    let data = frame(&message);
+    assert_eq!(decode_chunks(&mut decoder, &[&data]), (vec![message], 0));
+}
+
+#[test]
+fn encode_fragment_in_place() {
+    let mut buffer = vec![0xaa; 16];
+    buffer[4..8].copy_from_slice(&[1, 0, 2, 0]);
+    let size = cobs_encode_fragment_in_place(&mut buffer, 2, 0x85, 4, 4);
+    assert_eq!(&buffer[2..2 + size], &[3, 0x85, 1, 2, 2, 1, 0]);
+}
+
+proptest! {
+    #[test]
+    fn decoder_roundtrip(
+        messages in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..600), 1..4),
+        chunk_size in 1usize..64,
+    ) {
+        let mut data = vec![0];
+        for message in &messages {
+            data.extend(cobs_encode(message));
+            data.push(0);
+        }
+        let chunks: Vec<_> = data.chunks(chunk_size).collect();
+        let mut decoder = CobsDecoder::new();
+        let expected: Vec<_> = messages.into_iter().filter(|message| !message.is_empty()).collect();
+        prop_assert_eq!(decode_chunks(&mut decoder, &chunks), (expected, 0));
+    }
+
+    #[test]
+    fn decoder_survives_garbage(data in prop::collection::vec(any::<u8>(), 0..512), chunk_size in 1usize..64) {
+        let mut decoder = CobsDecoder::new();
+        let chunks: Vec<_> = data.chunks(chunk_size).collect();
+        decode_chunks(&mut decoder, &chunks);
+
+        // The decoder recovers on the next frame
+        let (frames, _) = decode_chunks(&mut decoder, &[&frame(&[1, 2, 3])]);
+        prop_assert_eq!(frames.last(), Some(&vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn encode_in_place_roundtrip(
+        header in any::<u8>(),
+        data in prop::collection::vec(any::<u8>(), 0..128),
+        write_offset in 0usize..8,
+        gap in 2usize..8,
+    ) {
+        let data_offset = write_offset + gap;
+        let mut buffer = vec![0x55; data_offset + data.len() + 8];
+        buffer[data_offset..data_offset + data.len()].copy_from_slice(&data);
+        let size = cobs_encode_fragment_in_place(&mut buffer, write_offset, header, data_offset, data.len());
+
+        let encoded = &buffer[write_offset..write_offset + size];
+        prop_assert_eq!(encoded.last(), Some(&0));
+        prop_assert!(!encoded[..size - 1].contains(&0));
+        let mut decoded = vec![0; size];
+        let decoded_size = cobs::decode(&encoded[..size - 1], &mut decoded).unwrap();
+        prop_assert_eq!(decoded[0], header);
+        prop_assert_eq!(&decoded[1..decoded_size], &data[..]);
+    }
+}
@@ -0,0 +1,79 @@
+use proptest::prelude::*;
+use deadbug_device::cobs_tx::CobsTxProducer;
+
+mod common;
+
+use common::{queue, drain, decode_messages};
+
+#[test]
+fn encode_message() {
+    let (producer, mut consumer) = queue(256);
+    let mut producer = CobsTxProducer::new(producer);
+
+    let mut grant = producer.grant(4).unwrap();
+    grant.copy_from_slice(&[1, 0, 0, 2]);
+    producer.commit(grant);
+    assert_eq!(drain(&mut consumer), vec![1, 2, 1, 1, 2, 2, 0]);
+}
+
+#[test]
+fn commit_part_of_grant() {
+    let (producer, mut consumer) = queue(256);
+    let mut producer = CobsTxProducer::new(producer);
+
+    let mut grant = producer.grant(16).unwrap();
+    grant[..3].copy_from_slice(&[7, 8, 9]);
+    producer.commit_with_size(3, grant).unwrap();
+    assert_eq!(decode_messages(&drain(&mut consumer)), vec![vec![7, 8, 9]]);
+
+    // Nothing is sent for empty commits
+    let grant = producer.grant(16).unwrap();
+    producer.commit_with_size(0, grant).unwrap();
+    assert!(drain(&mut consumer).is_empty());
+}
+
+#[test]
+fn reject_oversized_commit() {
+    let (producer, mut consumer) = queue(256);
+    let mut producer = CobsTxProducer::new(producer);
+
+    let grant = producer.grant(4).unwrap();
+    assert!(producer.commit_with_size(200, grant).is_err());
+    assert!(drain(&mut consumer).is_empty());
+
+    // The queue is still usable
+    let mut grant = producer.grant(4).unwrap();
+    grant.copy_from_slice(&[1, 2, 3, 4]);
+    producer.commit(grant);
+    assert_eq!(decode_messages(&drain(&mut consumer)), vec![vec![1, 2, 3, 4]]);
+}
+
+#[test]
+fn grant_includes_overhead() {
+    let (producer, mut consumer) = queue(64);
+    let mut producer = CobsTxProducer::new(producer);
+
+    // The data fits, but the encoded fragment doesn't
+    assert!(producer.grant(62).is_none());
+    let mut grant = producer.grant(32).unwrap();
+    grant.copy_from_slice(&[0x11; 32]);
+    producer.commit(grant);
+    assert_eq!(decode_messages(&drain(&mut consumer)), vec![vec![0x11; 32]]);
+}
+
+proptest! {
+    #[test]
+    fn encode_messages(messages in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..600), 1..8)) {
+        let (producer, mut consumer) = queue(2048);
+        let mut producer = CobsTxProducer::new(producer);
+
+        let mut data = Vec::new();
+        for message in &messages {
+            let mut grant = producer.grant(message.len()).unwrap();
+            grant.copy_from_slice(message);
+            producer.commit(grant);
+            data.extend(drain(&mut consumer));
+        }
+        prop_assert_eq!(decode_messages(&data), messages);
+    }
+}
@@ -0,0 +1,284 @@
+use bbqueue::{Producer, Consumer};
+use proptest::prelude::*;
+use serde::Serialize;
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::{GpioPin, GpioPinMode};
+use deadbug_common::protocol::DeviceMessageHeader;
+use deadbug_common::protocol::fragment::{MAX_PACKET_SIZE, MAX_COMMAND_SIZE};
+use deadbug_common::protocol::notification::{LogLevel, NotificationKind};
+use deadbug_common::protocol::gpio::{GpioCommand, GpioPinInformation, GpioPinLabel, GpioPinModes, GPIO_ENDPOINT};
+use deadbug_device::Device;
+use deadbug_device::cobs_tx::CobsTxProducer;
+use deadbug_device::command_processor::CommandProcessor;
+use deadbug_device::device::MIN_RESPONSE_BUFFER_SIZE;
+use deadbug_device::log_records::{serialize_log_record, LogRecordProducer, MAX_LOG_RECORD_SIZE};
+use deadbug_device::packet_processor::{PacketProcessor, PacketConsumer};
+use deadbug_device::pin_allocator::{GpioPinSet, MAX_PINS};
+
+mod common;
+
+use common::{queue, push, drain, encode_message, decode_messages};
+
+struct TestPin {
+    info: GpioPinInformation,
+    mode: GpioPinMode,
+}
+
+impl GpioPin for TestPin {
+    fn information(&self) -> GpioPinInformation {
+        self.info
+    }
+
+    fn mode(&self) -> GpioPinMode {
+        self.mode
+    }
+
+    fn set_mode(&mut self, mode: GpioPinMode) -> HalResult<()> {
+        if !self.info.supports(mode) {
+            return Err(HalErrorKind::InvalidGpioMode.into());
+        }
+        self.mode = mode;
+        Ok(())
+    }
+
+    fn set_output(&mut self, _value: bool) -> HalResult<()> {
+        Ok(())
+    }
+
+    fn get_input(&self) -> HalResult<bool> {
+        Ok(false)
+    }
+}
+
+struct TestPinSet(Vec<TestPin>);
+
+impl TestPinSet {
+    fn new(count: u8) -> Self {
+        TestPinSet((0..count).map(|index| TestPin {
+            info: GpioPinInformation {
+                index_major: b'A',
+                index_minor: index,
+                label: GpioPinLabel::new(""),
+                modes: GpioPinModes::FLOATING_INPUT.union(GpioPinModes::PUSH_PULL_OUTPUT),
+                alternate_functions: 0,
+            },
+            mode: GpioPinMode::FloatingInput,
+        }).collect())
+    }
+}
+
+impl GpioPinSet for TestPinSet {
+    type Pin = TestPin;
+
+    fn pins(&self) -> &[TestPin] {
+        &self.0
+    }
+
+    fn pins_mut(&mut self) -> &mut [TestPin] {
+        &mut self.0
+    }
+}
+
+/// The receive and transmit path of the firmware without the USB device
+struct Firmware {
+    rx: Producer,
+    packet_processor: PacketProcessor,
+    command_processor: CommandProcessor<TestPinSet>,
+    tx: Consumer,
+}
+
+impl Firmware {
+    fn new(pin_count: u8, tx_size: usize) -> Self {
+        let (mut rx, rx_data_consumer) = queue(512);
+        let (rx_packet_producer, rx_packet_consumer) = queue(1024);
+        let (tx_data_producer, tx) = queue(tx_size);
+        // Start of the first packet
+        push(&mut rx, &[0]);
+        Self {
+            rx,
+            packet_processor: PacketProcessor::new(rx_data_consumer, rx_packet_producer, MAX_PACKET_SIZE, MAX_COMMAND_SIZE),
+            command_processor: CommandProcessor::new(
+                CobsTxProducer::new(tx_data_producer),
+                PacketConsumer::new(rx_packet_consumer),
+                Device::new(TestPinSet::new(pin_count)).unwrap(),
+            ),
+            tx,
+        }
+    }
+
+    fn send<T: Serialize>(&mut self, request_id: u8, command: &T) {
+        let mut message = vec![GPIO_ENDPOINT, request_id];
+        let mut buffer = [0; 64];
+        let size = ssmarshal::serialize(&mut buffer, command).unwrap();
+        message.extend_from_slice(&buffer[..size]);
+        self.send_raw(&message);
+    }
+
+    fn send_raw(&mut self, message: &[u8]) {
+        assert!(push(&mut self.rx, &encode_message(message)));
+        // Reassemble, but don't process commands yet
+        for _ in 0..64 {
+            self.packet_processor.process();
+        }
+    }
+
+    /// Runs one iteration of the main loop, returns the sent messages
+    fn process(&mut self) -> Vec<Vec<u8>> {
+        self.command_processor.process();
+        self.sent()
+    }
+
+    fn sent(&mut self) -> Vec<Vec<u8>> {
+        decode_messages(&drain(&mut self.tx))
+    }
+}
+
+fn response_id(message: &[u8]) -> u8 {
+    match ssmarshal::deserialize(message).unwrap().0 {
+        DeviceMessageHeader::Response(request_id, Ok(())) => request_id,
+        header => panic!("unexpected message {:?}", header),
+    }
+}
+
+#[test]
+fn process_back_to_back_commands() {
+    let mut firmware = Firmware::new(4, 512);
+    for index in 0..4 {
+        firmware.send(index, &GpioCommand::SetPinMode(index, GpioPinMode::PushPullOutput));
+    }
+    let responses = firmware.process();
+    let ids: Vec<_> = responses.iter().map(|response| response_id(response)).collect();
+    assert_eq!(ids, vec![0, 1, 2, 3]);
+    assert!(firmware.process().is_empty());
+
+    let pins = firmware.command_processor.device().pins();
+    assert!((0..4).all(|index| pins.pin(index).unwrap().mode() == GpioPinMode::PushPullOutput));
+}
+
+#[test]
+fn skip_malformed_commands() {
+    let mut firmware = Firmware::new(4, 512);
+    firmware.send_raw(&[GPIO_ENDPOINT]);
+    firmware.send(7, &GpioCommand::GetPinMode(0));
+    let responses = firmware.process();
+    assert_eq!(responses.len(), 1);
+    assert_eq!(response_id(&responses[0]), 7);
+}
+
+#[test]
+fn reject_truncated_commands() {
+    let mut firmware = Firmware::new(4, 512);
+    // SetPinMode without the mode
+    firmware.send_raw(&[GPIO_ENDPOINT, 3, 2, 0]);
+    let responses = firmware.process();
+    assert_eq!(responses.len(), 1);
+    match ssmarshal::deserialize(&responses[0]).unwrap().0 {
+        DeviceMessageHeader::Response(3, Err(HalErrorKind::InvalidParameter)) => {},
+        header => panic!("unexpected message {:?}", header),
+    }
+}
+
+#[test]
+fn retry_with_larger_grant() {
+    let mut firmware = Firmware::new(8, 1024);
+    firmware.send(1, &GpioCommand::EnumeratePins);
+    firmware.send(2, &GpioCommand::GetPinMode(0));
+
+    // The response doesn't fit into the default grant, nothing is sent
+    assert!(firmware.process().is_empty());
+
+    let responses = firmware.process();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(response_id(&responses[0]), 1);
+    assert!(responses[0].len() > MIN_RESPONSE_BUFFER_SIZE);
+    assert_eq!(responses[0][3], 8);
+    assert_eq!(response_id(&responses[1]), 2);
+}
+
+#[test]
+fn wait_for_tx_space() {
+    let mut firmware = Firmware::new(4, 64);
+    for request_id in 0..10 {
+        firmware.send(request_id, &GpioCommand::GetPinMode(0));
+    }
+
+    // The host doesn't read the responses, processing stops when the queue is full
+    firmware.command_processor.process();
+    firmware.command_processor.process();
+    let mut responses = firmware.sent();
+    assert!(!responses.is_empty() && responses.len() < 10);
+
+    while responses.len() < 10 {
+        let sent = firmware.process();
+        assert!(!sent.is_empty());
+        responses.extend(sent);
+    }
+    let ids: Vec<_> = responses.iter().map(|response| response_id(response)).collect();
+    assert_eq!(ids, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn reject_too_many_pins() {
+    let error = Device::new(TestPinSet::new(MAX_PINS as u8 + 1)).err().unwrap();
+    assert!(matches!(error.kind(), HalErrorKind::NoResources));
+}
+
+#[test]
+fn poll_only_with_pending_work() {
+    let mut firmware = Firmware::new(4, 512);
+    assert!(!firmware.command_processor.device().needs_polling());
+    firmware.send(1, &GpioCommand::WatchPin(2, true));
+    assert_eq!(firmware.process().len(), 1);
+    assert!(firmware.command_processor.device().needs_polling());
+    firmware.send(2, &GpioCommand::WatchPin(2, false));
+    assert_eq!(firmware.process().len(), 1);
+    assert!(!firmware.command_processor.device().needs_polling());
+}
+
+#[test]
+fn send_log_records() {
+    let mut firmware = Firmware::new(4, 512);
+    let (producer, consumer) = queue(512);
+    let mut log_records = LogRecordProducer::new(producer);
+    firmware.command_processor.set_log_records(PacketConsumer::new(consumer));
+    for index in 0..2 {
+        let mut buffer = [0; MAX_LOG_RECORD_SIZE];
+        let size = serialize_log_record(&mut buffer, LogLevel::Info, index, "", format_args!("record {}", index)).unwrap();
+        log_records.write(&buffer[..size]);
+    }
+
+    let messages = firmware.process();
+    assert_eq!(messages.len(), 2);
+    for message in &messages {
+        match ssmarshal::deserialize(message).unwrap().0 {
+            DeviceMessageHeader::Notification(NotificationKind::Log) => {},
+            header => panic!("unexpected message {:?}", header),
+        }
+    }
+    assert!(firmware.process().is_empty());
+}
+
+proptest! {
+    #[test]
+    fn respond_to_every_command(commands in prop::collection::vec((0u8..10, any::<bool>()), 1..16)) {
+        let mut firmware = Firmware::new(8, 1024);
+        let mut responses = Vec::new();
+        for (request_id, (pin, enumerate)) in commands.iter().enumerate() {
+            if *enumerate {
+                firmware.send(request_id as u8, &GpioCommand::EnumeratePins);
+            } else {
+                firmware.send(request_id as u8, &GpioCommand::GetPinMode(*pin));
+            }
+            responses.extend(firmware.process());
+        }
+        for _ in 0..commands.len() {
+            responses.extend(firmware.process());
+        }
+
+        let ids: Vec<_> = responses.iter().map(|response| match ssmarshal::deserialize(response).unwrap().0 {
+            DeviceMessageHeader::Response(request_id, _) => request_id,
+            header => panic!("unexpected message {:?}", header),
+        }).collect();
+        prop_assert_eq!(ids, (0..commands.len() as u8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,89 @@
+#![allow(dead_code)]
+
+use bbqueue::{BBQueue, Consumer, Producer};
+use deadbug_common::protocol::fragment::{FragmentHeader, MAX_FRAGMENT_PAYLOAD};
+
+/// Creates a queue with a leaked buffer
+pub fn queue(size: usize) -> (Producer, Consumer) {
+    let buffer = Box::leak(vec![0; size].into_boxed_slice());
+    let queue = Box::leak(Box::new(unsafe { BBQueue::unpinned_new(buffer) }));
+    queue.split()
+}
+
+/// Appends data to the queue, returns false if it doesn't fit
+pub fn push(producer: &mut Producer, data: &[u8]) -> bool {
+    match producer.grant(data.len()) {
+        Ok(mut grant) => {
+            grant.copy_from_slice(data);
+            producer.commit(data.len(), grant);
+            true
+        },
+        Err(_) => false,
+    }
+}
+
+/// Takes all data from the queue
+pub fn drain(consumer: &mut Consumer) -> Vec<u8> {
+    let mut data = Vec::new();
+    // The readable data may wrap around, read twice
+    for _ in 0..2 {
+        if let Ok(grant) = consumer.read() {
+            data.extend_from_slice(&grant);
+            let size = grant.len();
+            consumer.release(size, grant);
+        }
+    }
+    data
+}
+
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![0; cobs::max_encoding_length(data.len())];
+    let size = cobs::encode(data, &mut encoded);
+    encoded.truncate(size);
+    encoded
+}
+
+/// Encodes a message as COBS-framed fragments, each followed by a zero byte
+pub fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut chunks: Vec<_> = message.chunks(MAX_FRAGMENT_PAYLOAD).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let count = chunks.len();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let header = FragmentHeader {
+            more: index + 1 < count,
+            sequence: index as u8,
+        };
+        let mut packet = vec![header.to_byte()];
+        packet.extend_from_slice(chunk);
+        data.extend(cobs_encode(&packet));
+        data.push(0);
+    }
+    data
+}
+
+/// Decodes COBS-framed fragments and reassembles them into messages
+pub fn decode_messages(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut message = Vec::new();
+    let mut next_sequence = 0;
+    for frame in data.split(|b| *b == 0).filter(|frame| !frame.is_empty()) {
+        let mut packet = vec![0; frame.len()];
+        let size = cobs::decode(frame, &mut packet).expect("invalid COBS frame");
+        assert!(size >= 1, "packet without a fragment header");
+        assert!(size <= 1 + MAX_FRAGMENT_PAYLOAD, "oversized packet");
+        let header = FragmentHeader::from_byte(packet[0]);
+        assert_eq!(header.sequence, next_sequence);
+        message.extend_from_slice(&packet[1..size]);
+        if header.more {
+            next_sequence += 1;
+        } else {
+            messages.push(std::mem::take(&mut message));
+            next_sequence = 0;
+        }
+    }
+    assert!(message.is_empty(), "incomplete message");
+    messages
+}
@@ -0,0 +1,36 @@
+use serde::Serialize;
+use deadbug_common::hal::HalErrorKind;
+use deadbug_common::protocol::{CommandHeader, DeviceMessageHeader};
+use deadbug_common::protocol::stream::{StreamDataHeader, STREAM_DATA_HEADER_SIZE};
+use deadbug_device::device::{COMMAND_HEADER_SIZE, RESPONSE_HEADER_SIZE, MIN_RESPONSE_BUFFER_SIZE};
+
+fn serialized_size<T: Serialize>(value: &T) -> usize {
+    let mut buffer = [0; 64];
+    ssmarshal::serialize(&mut buffer, value).unwrap()
+}
+
+#[test]
+fn command_header_size() {
+    let header = CommandHeader {
+        endpoint: 0xff,
+        request_id: 0xff,
+    };
+    assert_eq!(serialized_size(&header), COMMAND_HEADER_SIZE);
+}
+
+#[test]
+fn response_header_size() {
+    assert_eq!(serialized_size(&DeviceMessageHeader::Response(0xff, Ok(()))), RESPONSE_HEADER_SIZE);
+    // Error responses are written without a payload
+    let error = DeviceMessageHeader::Response(0xff, Err(HalErrorKind::Other(0xff)));
+    assert!(serialized_size(&error) <= MIN_RESPONSE_BUFFER_SIZE);
+}
+
+#[test]
+fn stream_data_header_size() {
+    let header = DeviceMessageHeader::StreamData(StreamDataHeader {
+        stream: 0xff,
+        sequence: 0xffff,
+    });
+    assert_eq!(serialized_size(&header), STREAM_DATA_HEADER_SIZE);
+}
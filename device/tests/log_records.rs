@@ -0,0 +1,75 @@
+use deadbug_common::protocol::DeviceMessageHeader;
+use deadbug_common::protocol::notification::{LogLevel, LogRecord, NotificationKind};
+use deadbug_device::log_records::{forwarded, serialize_log_record, LogRecordProducer, MAX_LOG_RECORD_SIZE};
+use deadbug_device::packet_processor::PacketConsumer;
+
+mod common;
+
+use common::queue;
+
+/// Returns the payload of a log notification message
+fn payload(message: &[u8]) -> &[u8] {
+    let (header, size) = ssmarshal::deserialize(message).unwrap();
+    match header {
+        DeviceMessageHeader::Notification(NotificationKind::Log) => &message[size..],
+        header => panic!("unexpected message {:?}", header),
+    }
+}
+
+fn message(level: LogLevel, timestamp: u32, target: &str, args: std::fmt::Arguments) -> Vec<u8> {
+    let mut buffer = [0; MAX_LOG_RECORD_SIZE];
+    let size = serialize_log_record(&mut buffer, level, timestamp, target, args).unwrap();
+    buffer[..size].to_vec()
+}
+
+#[test]
+fn serialize_record() {
+    let mut buffer = [0; MAX_LOG_RECORD_SIZE];
+    let size = serialize_log_record(&mut buffer, LogLevel::Warn, 1234, "deadbug::device", format_args!("pin {} busy", 3)).unwrap();
+    let record = LogRecord::decode(payload(&buffer[..size])).unwrap();
+    assert_eq!(record, LogRecord {
+        level: LogLevel::Warn,
+        timestamp: 1234,
+        target: "deadbug::device".to_string(),
+        message: "pin 3 busy".to_string(),
+    });
+}
+
+#[test]
+fn truncate_at_character_boundary() {
+    // Headers, the truncated target and 9 bytes of the message
+    let mut buffer = [0; 8 + 32 + 9];
+    let target = "t".repeat(40);
+    let size = serialize_log_record(&mut buffer, LogLevel::Info, 0, &target, format_args!("{}", "ä".repeat(40))).unwrap();
+    let record = LogRecord::decode(payload(&buffer[..size])).unwrap();
+    assert_eq!(record.target, "t".repeat(32));
+    assert_eq!(record.message, "ä".repeat(4));
+}
+
+#[test]
+fn queue_records() {
+    // The messages take 16 and 17 bytes with the size prefix, the third one doesn't fit
+    let (producer, consumer) = queue(40);
+    let mut producer = LogRecordProducer::new(producer);
+    let mut consumer = PacketConsumer::new(consumer);
+    producer.write(&message(LogLevel::Error, 5, "a", format_args!("first")));
+    producer.write(&message(LogLevel::Debug, 6, "b", format_args!("second")));
+    producer.write(&message(LogLevel::Debug, 7, "c", format_args!("dropped")));
+    assert!(producer.take_overrun());
+    assert!(!producer.take_overrun());
+
+    let mut messages = Vec::new();
+    while let Some(grant) = consumer.read() {
+        messages.push(LogRecord::decode(payload(&grant)).unwrap().message);
+        consumer.release_consume(grant);
+    }
+    assert_eq!(messages, vec!["first", "second"]);
+}
+
+#[test]
+fn filter_by_level() {
+    assert!(!forwarded(LogLevel::Error, None));
+    assert!(forwarded(LogLevel::Error, Some(LogLevel::Info)));
+    assert!(forwarded(LogLevel::Info, Some(LogLevel::Info)));
+    assert!(!forwarded(LogLevel::Debug, Some(LogLevel::Info)));
+}
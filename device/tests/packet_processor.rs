@@ -0,0 +1,235 @@
+use bbqueue::Producer;
+use proptest::prelude::*;
+use deadbug_common::protocol::fragment::{FragmentHeader, MAX_PACKET_SIZE, MAX_COMMAND_SIZE};
+use deadbug_device::packet_processor::{PacketProcessor, PacketConsumer};
+
+mod common;
+
+use common::{queue, push, cobs_encode, encode_message};
+
+struct Receiver {
+    input: Producer,
+    processor: PacketProcessor,
+    packets: PacketConsumer,
+}
+
+impl Receiver {
+    fn new() -> Self {
+        Self::with_packet_queue(1024)
+    }
+
+    fn with_packet_queue(size: usize) -> Self {
+        let (input, data_consumer) = queue(512);
+        let (packet_producer, packet_consumer) = queue(size);
+        Self {
+            input,
+            processor: PacketProcessor::new(data_consumer, packet_producer, MAX_PACKET_SIZE, MAX_COMMAND_SIZE),
+            packets: PacketConsumer::new(packet_consumer),
+        }
+    }
+
+    /// Feeds the data in chunks of the specified size, returns the reassembled messages
+    fn receive(&mut self, data: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            assert!(push(&mut self.input, chunk));
+            while self.processor.process() {
+                messages.extend(self.take_packets());
+            }
+        }
+        messages
+    }
+
+    fn take_packets(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(grant) = self.packets.read() {
+            messages.push(grant.to_vec());
+            self.packets.release_consume(grant);
+        }
+        messages
+    }
+}
+
+/// Encodes a raw packet with the delimiter
+fn packet(data: &[u8]) -> Vec<u8> {
+    let mut encoded = cobs_encode(data);
+    encoded.push(0);
+    encoded
+}
+
+fn fragment(more: bool, sequence: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![FragmentHeader { more, sequence }.to_byte()];
+    data.extend_from_slice(payload);
+    packet(&data)
+}
+
+#[test]
+fn receive_single_packet() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    data.extend(encode_message(&[1, 0, 2]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![1, 0, 2]]);
+    assert!(!receiver.processor.take_overrun());
+}
+
+#[test]
+fn wait_for_packet_queue_space() {
+    // Room for two reassembly grants of 388 bytes, the fifth message doesn't fit after four
+    let mut receiver = Receiver::with_packet_queue(778);
+    let mut data = vec![0];
+    for index in 0..5 {
+        data.extend(encode_message(&[index; 98]));
+    }
+    assert!(push(&mut receiver.input, &data));
+
+    // Nobody takes the packets, processing stops when the queue is full
+    let mut steps = 0;
+    while receiver.processor.process() {
+        steps += 1;
+        assert!(steps < 1000);
+    }
+    let mut messages = receiver.take_packets();
+    assert_eq!(messages.len(), 4);
+
+    while receiver.processor.process() {
+        messages.extend(receiver.take_packets());
+    }
+    assert_eq!(messages, (0..5).map(|index| vec![index; 98]).collect::<Vec<_>>());
+    assert!(!receiver.processor.process());
+}
+
+#[test]
+fn discard_until_first_zero() {
+    let mut receiver = Receiver::new();
+    let mut data = fragment(false, 0, &[1]);
+    data.extend(fragment(false, 0, &[2]));
+    // The first packet may be a tail of something else
+    assert_eq!(receiver.receive(&data, 64), vec![vec![2]]);
+}
+
+#[test]
+fn receive_partial_chunks() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    data.extend(encode_message(&[5; 200]));
+    assert_eq!(receiver.receive(&data, 1), vec![vec![5; 200]]);
+}
+
+#[test]
+fn receive_back_to_back_packets() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    for i in 1..=5 {
+        data.extend(encode_message(&[i; 3]));
+    }
+    let expected: Vec<_> = (1..=5).map(|i| vec![i; 3]).collect();
+    assert_eq!(receiver.receive(&data, data.len()), expected);
+}
+
+#[test]
+fn skip_empty_packets_and_messages() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0, 0, 0];
+    data.extend(encode_message(&[]));
+    data.extend(encode_message(&[1]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![1]]);
+}
+
+#[test]
+fn drop_oversized_packet() {
+    let mut receiver = Receiver::new();
+    // Too large even for the raw data buffer
+    let mut oversized = vec![0x42; 400];
+    oversized[0] = FragmentHeader { more: false, sequence: 0 }.to_byte();
+    let mut data = vec![0];
+    data.extend(packet(&oversized));
+    data.extend(encode_message(&[1, 2]));
+    assert_eq!(receiver.receive(&data, 16), vec![vec![1, 2]]);
+    assert!(receiver.processor.take_overrun());
+    assert!(!receiver.processor.take_overrun());
+
+    // The end of the oversized packet and the next one are in the same chunk
+    let mut data = packet(&oversized);
+    data.extend(encode_message(&[3]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![3]]);
+}
+
+#[test]
+fn drop_oversized_message() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    data.extend(fragment(true, 0, &[1; 127]));
+    data.extend(fragment(true, 1, &[1; 127]));
+    data.extend(fragment(false, 2, &[1; 127]));
+    data.extend(encode_message(&[2]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![2]]);
+    assert!(receiver.processor.take_overrun());
+}
+
+#[test]
+fn drop_undecodable_packet() {
+    let mut receiver = Receiver::new();
+    // The code byte points past the end of the packet
+    let mut data = vec![0, 5, 1, 0];
+    data.extend(encode_message(&[1]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![1]]);
+    assert!(!receiver.processor.take_overrun());
+}
+
+#[test]
+fn drop_out_of_order_fragments() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    data.extend(fragment(true, 0, &[1]));
+    data.extend(fragment(false, 2, &[2]));
+    data.extend(fragment(false, 1, &[3]));
+    data.extend(encode_message(&[4]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![4]]);
+}
+
+#[test]
+fn restart_interrupted_message() {
+    let mut receiver = Receiver::new();
+    let mut data = vec![0];
+    data.extend(fragment(true, 0, &[1]));
+    data.extend(fragment(true, 1, &[2]));
+    // The sequence restarts: the partial message is dropped and reported
+    data.extend(fragment(true, 0, &[3]));
+    data.extend(fragment(false, 1, &[4]));
+    data.extend(encode_message(&[5]));
+    assert_eq!(receiver.receive(&data, 64), vec![vec![3, 4], vec![5]]);
+    assert!(receiver.processor.take_overrun());
+    assert!(!receiver.processor.take_overrun());
+}
+
+proptest! {
+    #[test]
+    fn receive_messages(
+        messages in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..=MAX_COMMAND_SIZE), 1..8),
+        chunk_size in 1usize..256,
+    ) {
+        let mut receiver = Receiver::new();
+        let mut data = vec![0];
+        for message in &messages {
+            data.extend(encode_message(message));
+        }
+        prop_assert_eq!(receiver.receive(&data, chunk_size), messages);
+        prop_assert!(!receiver.processor.take_overrun());
+    }
+
+    #[test]
+    fn recover_after_garbage(
+        garbage in prop::collection::vec(any::<u8>(), 0..512),
+        message in prop::collection::vec(any::<u8>(), 1..=MAX_COMMAND_SIZE),
+        chunk_size in 1usize..256,
+    ) {
+        let mut receiver = Receiver::new();
+        let mut data = garbage;
+        data.push(0);
+        // The first copy may complete a message started by the garbage
+        data.extend(encode_message(&message));
+        data.extend(encode_message(&message));
+        let messages = receiver.receive(&data, chunk_size);
+        prop_assert_eq!(messages.last(), Some(&message));
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt::Write;
+use deadbug_common::protocol::system::FaultRegisters;
+use deadbug_device::panic_record::{PanicRecord, MAX_PANIC_MESSAGE_SIZE};
+
+#[test]
+fn record_message() {
+    let mut record = PanicRecord::new();
+    assert_eq!(record.message(), None);
+
+    record.start();
+    assert_eq!(record.message(), Some(""));
+    let file = "src/main.rs";
+    write!(record, "panicked at {}:{}", file, 42).unwrap();
+    assert_eq!(record.message(), Some("panicked at src/main.rs:42"));
+
+    record.clear();
+    assert_eq!(record.message(), None);
+}
+
+#[test]
+fn truncate_long_message() {
+    let mut record = PanicRecord::new();
+    record.start();
+    let prefix = "x".repeat(MAX_PANIC_MESSAGE_SIZE - 1);
+    write!(record, "{}ÿ and more", prefix).unwrap();
+    // The two-byte character doesn't fit
+    assert_eq!(record.message(), Some(prefix.as_str()));
+}
+
+#[test]
+fn record_fault() {
+    let registers = FaultRegisters {
+        pc: 0x0800_1234,
+        lr: 0x0800_1001,
+        cfsr: 1 << 25,
+        ..FaultRegisters::default()
+    };
+    let mut record = PanicRecord::new();
+    record.start();
+    assert_eq!(record.fault(), None);
+    write!(record, "hard fault at {:#010x}", registers.pc).unwrap();
+    record.set_fault(registers);
+    assert_eq!(record.message(), Some("hard fault at 0x08001234"));
+    assert_eq!(record.fault(), Some(&registers));
+
+    // A panic after the reset doesn't report the old registers
+    record.start();
+    assert_eq!(record.fault(), None);
+    record.set_fault(registers);
+    record.clear();
+    assert_eq!(record.fault(), None);
+}
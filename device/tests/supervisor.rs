@@ -0,0 +1,35 @@
+use deadbug_device::supervisor::Supervisor;
+
+#[test]
+fn disabled_by_default() {
+    let mut supervisor = Supervisor::new();
+    assert!(!supervisor.poll(0));
+    assert!(!supervisor.poll(u32::MAX));
+}
+
+#[test]
+fn expire_without_activity() {
+    let mut supervisor = Supervisor::new();
+    supervisor.set_timeout(100);
+    supervisor.record_activity();
+    assert!(!supervisor.poll(1000));
+    assert!(!supervisor.poll(1099));
+    supervisor.record_activity();
+    assert!(!supervisor.poll(1150));
+    assert!(!supervisor.poll(1249));
+    assert!(supervisor.poll(1250));
+
+    // Expires only once
+    assert_eq!(supervisor.timeout(), 0);
+    assert!(!supervisor.poll(5000));
+}
+
+#[test]
+fn counter_wraps_around() {
+    let mut supervisor = Supervisor::new();
+    supervisor.set_timeout(100);
+    supervisor.record_activity();
+    assert!(!supervisor.poll(u32::MAX - 49));
+    assert!(!supervisor.poll(49));
+    assert!(supervisor.poll(50));
+}
@@ -1,57 +1,269 @@
 use stm32_usbd::UsbBusType;
-use usbd_serial::USB_CLASS_CDC;
 use usb_device::prelude::*;
 use usb_device::bus::UsbBusAllocator;
 use bbqueue::BBQueue;
-use crate::cobs_tx::CobsTxProducer;
-use crate::packet_processor::{PacketProcessor, PacketConsumer};
+use core::ptr::addr_of_mut;
 use crate::targets::BoardGpioPinSet;
-use crate::command_processor::{CommandProcessor, GpioCommandTarget};
-use crate::dumb_serial::QueuedSerial;
+use crate::log_forwarding;
+use crate::log_interface::LogInterface;
+use crate::ms_os_descriptors::{MsOsDescriptors, WinUsbFunction};
+use deadbug_device::Device;
+use deadbug_device::command_processor::CommandProcessor;
+use deadbug_device::packet_processor::PacketConsumer;
+use deadbug_device::notifications::Notification;
+use deadbug_device::panic_record::PanicRecord;
+use stm32_log::info;
+use deadbug_common::protocol::notification::BufferKind;
+use deadbug_common::protocol::usb::{USB_VID, USB_PID};
+
+#[cfg(feature = "cdc")]
+use {
+    crate::dumb_serial::QueuedSerial,
+    deadbug_device::cobs_tx::CobsTxProducer,
+    deadbug_device::packet_processor::PacketProcessor,
+    deadbug_common::protocol::fragment::{MAX_PACKET_SIZE, MAX_COMMAND_SIZE},
+};
+#[cfg(not(feature = "cdc"))]
+use {
+    crate::bulk_interface::BulkInterface,
+    deadbug_device::bulk::BulkTxProducer,
+};
+
+/// Vendor bulk interface, or the serial port with the `cdc` feature
+#[cfg(feature = "cdc")]
+type Transport = QueuedSerial<'static, UsbBusType>;
+#[cfg(not(feature = "cdc"))]
+type Transport = BulkInterface<'static, UsbBusType>;
+
+#[cfg(feature = "cdc")]
+type TxProducer = CobsTxProducer;
+#[cfg(not(feature = "cdc"))]
+type TxProducer = BulkTxProducer;
 
 pub struct AppDevices {
-    pub bus: UsbBusAllocator<UsbBusType>,
+    pub bus: &'static UsbBusAllocator<UsbBusType>,
     pub pins: BoardGpioPinSet,
+    /// Reported to the host
+    pub last_panic: PanicRecord,
 }
 
+#[cfg(feature = "cdc")]
 static mut RX_DATA_BUFFER: [u8; 512] = [0; 512];
-static mut RX_PACKET_BUFFER: [u8; 512] = [0; 512];
+static mut RX_PACKET_BUFFER: [u8; 1024] = [0; 1024];
 static mut TX_DATA_BUFFER: [u8; 512] = [0; 512];
+static mut LOG_RECORD_BUFFER: [u8; 512] = [0; 512];
+
+/// Interface GUIDs registered by WinUSB
+#[cfg(not(feature = "cdc"))]
+const COMMAND_INTERFACE_GUID: &str = "{9f3a6c21-5d0e-4b7a-8c4f-2e61d0b7a935}";
+const LOG_INTERFACE_GUID: &str = "{4e8b2d17-a36c-4f05-b1d9-7c20e5f48a63}";
+
+/// USB device with the transport and log classes, serviced from the USB interrupt
+pub struct Usb {
+    device: UsbDevice<'static, UsbBusType>,
+    transport: Transport,
+    log: LogInterface<'static, UsbBusType>,
+    ms_os: MsOsDescriptors,
+}
+
+impl Usb {
+    /// Handles the USB events and moves data between the endpoints and the queues
+    ///
+    /// Returns true if received data waits in an endpoint for space in the queues. The
+    /// interrupt keeps firing until the data is read.
+    pub fn poll(&mut self) -> bool {
+        self.device.poll(&mut [&mut self.transport, &mut self.log, &mut self.ms_os]);
+        self.process();
+        self.rx_held()
+    }
+
+    /// Moves data between the endpoints and the queues without waiting for an event
+    pub fn process(&mut self) {
+        self.process_transport();
+        self.log.process();
+    }
+
+    #[cfg(feature = "cdc")]
+    fn process_transport(&mut self) {
+        self.transport.process().ok();
+    }
+
+    #[cfg(not(feature = "cdc"))]
+    fn process_transport(&mut self) {
+        self.transport.process();
+    }
+
+    #[cfg(feature = "cdc")]
+    fn rx_held(&self) -> bool {
+        self.transport.read_blocked()
+    }
+
+    #[cfg(not(feature = "cdc"))]
+    fn rx_held(&self) -> bool {
+        self.transport.rx_held()
+    }
+
+    /// Returns true while a host uses the device
+    #[cfg(feature = "cdc")]
+    pub fn connected(&self) -> bool {
+        self.transport.dtr()
+    }
+
+    #[cfg(not(feature = "cdc"))]
+    pub fn connected(&self) -> bool {
+        self.device.state() == UsbDeviceState::Configured
+    }
+
+    /// Returns true if a received command was dropped since the last call
+    ///
+    /// Overruns of the serial byte stream are reported by the packet processor.
+    #[cfg(feature = "cdc")]
+    pub fn take_overrun(&mut self) -> bool {
+        false
+    }
+
+    #[cfg(not(feature = "cdc"))]
+    pub fn take_overrun(&mut self) -> bool {
+        self.transport.take_overrun()
+    }
+}
+
+/// Command processing, runs when data was received or sent and on every tick
+pub struct App {
+    #[cfg(feature = "cdc")]
+    packet_processor: PacketProcessor,
+    proc: CommandProcessor<BoardGpioPinSet, TxProducer>,
+    connected: bool,
+}
+
+impl App {
+    /// Handles all received commands and sends the responses, stream data and notifications
+    /// that fit into the transmit queue
+    ///
+    /// `now` is the millisecond counter for the keep-alive supervision.
+    pub fn process(&mut self, connected: bool, rx_overrun: bool, now: u32) {
+        // The host closed the port or went away
+        if self.connected && !connected {
+            info!("host disconnected");
+            self.proc.device_mut().enter_safe_state();
+        }
+        self.connected = connected;
+        self.proc.device_mut().poll_supervision(now);
+
+        if rx_overrun {
+            self.proc.notify(Notification::BufferOverrun(BufferKind::CommandRx));
+        }
+        if log_forwarding::take_overrun() {
+            self.proc.notify(Notification::BufferOverrun(BufferKind::LogRecords));
+        }
+        loop {
+            let received = self.reassemble_packets();
+            // Frees space for more packets
+            self.proc.process();
+            if !received {
+                break;
+            }
+        }
+        log_forwarding::set_level(self.proc.device().log_level());
+    }
+
+    /// Returns true if `process` must run periodically, not only on USB events
+    pub fn needs_polling(&self) -> bool {
+        self.proc.device().needs_polling()
+    }
 
-pub fn app_run(devices: AppDevices) -> ! {
+    /// Decodes the serial byte stream into the packet queue
+    ///
+    /// Returns true if anything was received.
+    #[cfg(feature = "cdc")]
+    fn reassemble_packets(&mut self) -> bool {
+        let mut received = false;
+        while self.packet_processor.process() {
+            received = true;
+        }
+        if self.packet_processor.take_overrun() {
+            self.proc.notify(Notification::BufferOverrun(BufferKind::CommandRx));
+        }
+        received
+    }
+
+    /// The bulk interface writes the packets into the packet queue itself
+    #[cfg(not(feature = "cdc"))]
+    fn reassemble_packets(&mut self) -> bool {
+        false
+    }
+}
+
+/// Builds the queues and the USB device
+///
+/// Must be called once, the queues use static buffers.
+pub fn app_init(devices: AppDevices) -> (Usb, App) {
     let usb_bus = devices.bus;
 
     // Build queues
-    let rx_data_queue = unsafe { BBQueue::unpinned_new(&mut RX_DATA_BUFFER) };
-    let rx_packet_queue = unsafe { BBQueue::unpinned_new(&mut RX_PACKET_BUFFER) };
-    let tx_data_queue = unsafe { BBQueue::unpinned_new(&mut TX_DATA_BUFFER) };
-    let (rx_data_producer, rx_data_consumer) = rx_data_queue.split();
+    let rx_packet_queue = unsafe { BBQueue::unpinned_new(&mut *addr_of_mut!(RX_PACKET_BUFFER)) };
+    let tx_data_queue = unsafe { BBQueue::unpinned_new(&mut *addr_of_mut!(TX_DATA_BUFFER)) };
     let (rx_packet_producer, rx_packet_consumer) = rx_packet_queue.split();
     let (tx_data_producer, tx_data_consumer) = tx_data_queue.split();
-    let mut packet_processor = PacketProcessor::new(rx_data_consumer, rx_packet_producer, 128);
     let packet_consumer = PacketConsumer::new(rx_packet_consumer);
-    let packet_producer = CobsTxProducer::new(tx_data_producer);
+    let packet_producer = TxProducer::new(tx_data_producer);
+
+    #[cfg(feature = "cdc")]
+    let (transport, packet_processor) = {
+        let rx_data_queue = unsafe { BBQueue::unpinned_new(&mut *addr_of_mut!(RX_DATA_BUFFER)) };
+        let (rx_data_producer, rx_data_consumer) = rx_data_queue.split();
+        let packet_processor = PacketProcessor::new(rx_data_consumer, rx_packet_producer, MAX_PACKET_SIZE, MAX_COMMAND_SIZE);
+        (QueuedSerial::new(usb_bus, rx_data_producer, tx_data_consumer), packet_processor)
+    };
+    #[cfg(not(feature = "cdc"))]
+    let transport = BulkInterface::new(usb_bus, rx_packet_producer, tx_data_consumer);
+
+    // The board pin count is checked at compile time
+    let mut device = Device::new(devices.pins).expect("the board pins fit the device");
+    device.set_last_panic(&devices.last_panic);
+    let mut proc = CommandProcessor::new(packet_producer, packet_consumer, device);
 
-    let gpio_target = GpioCommandTarget::new(devices.pins);
-    let mut proc = CommandProcessor::new(packet_producer, packet_consumer, gpio_target);
+    let log_record_queue = unsafe { BBQueue::unpinned_new(&mut *addr_of_mut!(LOG_RECORD_BUFFER)) };
+    let (log_record_producer, log_record_consumer) = log_record_queue.split();
+    log_forwarding::init(log_record_producer);
+    proc.set_log_records(PacketConsumer::new(log_record_consumer));
 
-    //let mut serial = SmartSerial::new(&usb_bus, rx_data_producer, tx_data_consumer);
-    let mut serial = QueuedSerial::new(&usb_bus, rx_data_producer, tx_data_consumer);
+    // The log interface is allocated last, after the interfaces of the transport
+    let log = LogInterface::new(usb_bus);
+    #[cfg(not(feature = "cdc"))]
+    let ms_os = MsOsDescriptors::new(&[
+        WinUsbFunction { interface: transport.interface(), guid: COMMAND_INTERFACE_GUID },
+        WinUsbFunction { interface: log.interface(), guid: LOG_INTERFACE_GUID },
+    ]);
+    // The serial port uses the CDC driver
+    #[cfg(feature = "cdc")]
+    let ms_os = MsOsDescriptors::new(&[
+        WinUsbFunction { interface: log.interface(), guid: LOG_INTERFACE_GUID },
+    ]);
 
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+    let builder = UsbDeviceBuilder::new(usb_bus, UsbVidPid(USB_VID, USB_PID))
         .manufacturer("Fake company")
+        .serial_number("TEST");
+    // The serial port interfaces are grouped by an interface association descriptor
+    #[cfg(feature = "cdc")]
+    let builder = builder
         .product("Serial port")
-        .serial_number("TEST")
-        .device_class(USB_CLASS_CDC)
-        .build();
+        .composite_with_iads();
+    #[cfg(not(feature = "cdc"))]
+    let builder = builder.product("deadbug");
+    let usb_dev = builder.build();
 
-    loop {
-        log::logger().flush();
-
-        if usb_dev.poll(&mut [&mut serial]) {
-        }
-        serial.process();
-        packet_processor.process();
-        proc.process();
-    }
+    let usb = Usb {
+        device: usb_dev,
+        transport,
+        log,
+        ms_os,
+    };
+    let app = App {
+        #[cfg(feature = "cdc")]
+        packet_processor,
+        proc,
+        connected: false,
+    };
+    (usb, app)
 }
@@ -0,0 +1,92 @@
+use usb_device::class_prelude::*;
+use usb_device::Result;
+use bbqueue::{Producer, Consumer};
+use deadbug_device::bulk::{BulkRx, BulkTxConsumer};
+use deadbug_common::protocol::fragment::{BULK_PACKET_SIZE, MAX_COMMAND_SIZE};
+use deadbug_common::protocol::usb::{USB_CLASS_VENDOR_SPECIFIC, COMMAND_INTERFACE_SUBCLASS};
+
+/// Vendor-specific interface with a pair of bulk endpoints
+///
+/// Every fragment is sent as a bulk transfer of its own, so the packet boundaries replace the COBS
+/// framing of the serial port.
+pub struct BulkInterface<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    rx: BulkRx,
+    tx: BulkTxConsumer,
+}
+
+impl<'a, B: UsbBus> BulkInterface<'a, B> {
+    /// Creates the interface, received messages are written into the `producer` packet queue
+    pub fn new(alloc: &'a UsbBusAllocator<B>, producer: Producer, consumer: Consumer) -> Self {
+        Self {
+            interface: alloc.interface(),
+            read_ep: alloc.bulk(BULK_PACKET_SIZE as u16),
+            write_ep: alloc.bulk(BULK_PACKET_SIZE as u16),
+            rx: BulkRx::new(producer, MAX_COMMAND_SIZE),
+            tx: BulkTxConsumer::new(consumer),
+        }
+    }
+
+    pub fn interface(&self) -> InterfaceNumber {
+        self.interface
+    }
+
+    /// Returns true if a received message was dropped since the last call
+    pub fn take_overrun(&mut self) -> bool {
+        self.rx.take_overrun()
+    }
+
+    /// Returns true if a received packet waits for space in the packet queue
+    ///
+    /// The endpoint NAKs the host meanwhile, but its interrupt stays pending.
+    pub fn rx_held(&self) -> bool {
+        self.rx.is_pending()
+    }
+
+    /// Moves data between the endpoints and the queues
+    pub fn process(&mut self) {
+        self.read_packets();
+        self.write_packets();
+    }
+
+    fn read_packets(&mut self) {
+        while self.rx.process() {
+            let buffer = match self.rx.packet_buffer() {
+                Some(buffer) => buffer,
+                None => break,
+            };
+            match self.read_ep.read(buffer) {
+                Ok(size) => self.rx.packet_received(size),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn write_packets(&mut self) {
+        let write_ep = &self.write_ep;
+        while self.tx.write_packet(|packet| write_ep.write(packet).is_ok()) {}
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass<B> for BulkInterface<'a, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, USB_CLASS_VENDOR_SPECIFIC, COMMAND_INTERFACE_SUBCLASS, 0)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.read_ep.address() {
+            self.read_packets();
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() {
+            self.write_packets();
+        }
+    }
+}
@@ -8,6 +8,8 @@ pub struct QueuedSerial<'a, B: UsbBus> {
     producer: Producer,
     consumer: Consumer,
     write_state: WriteState,
+    /// Reading stopped because the queue was full
+    read_blocked: bool,
 }
 
 /// If this many full size packets have been sent in a row, a short packet will be sent so that the
@@ -38,7 +40,8 @@ impl<'a, B: UsbBus> QueuedSerial<'a, B>
             inner: CdcAcmClass::new(alloc, 64),
             producer,
             consumer,
-            write_state: WriteState::Idle
+            write_state: WriteState::Idle,
+            read_blocked: false,
         }
     }
 
@@ -51,6 +54,9 @@ impl<'a, B: UsbBus> QueuedSerial<'a, B>
     /// Gets the RTS (ready to send) state
     pub fn rts(&self) -> bool { self.inner.rts() }
 
+    /// Returns true if received data may wait in the endpoint for space in the queue
+    pub fn read_blocked(&self) -> bool { self.read_blocked }
+
     /// Returns Ok(size) if packet (even empty) was sent
     /// Returns Err(UsbError::WouldBlock) if there is no data in queue
     fn flush_write(&mut self) -> Result<usize> {
@@ -102,6 +108,7 @@ impl<'a, B: UsbBus> QueuedSerial<'a, B>
     pub fn process(&mut self) -> Result<()> {
         let max_packet_size = self.inner.max_packet_size() as usize;
 
+        self.read_blocked = true;
         while let Ok(mut grant) = self.producer.grant(max_packet_size) {
             let r = self.inner.read_packet(&mut grant);
             let read_size = *r.as_ref().unwrap_or(&0);
@@ -109,7 +116,10 @@ impl<'a, B: UsbBus> QueuedSerial<'a, B>
 
             match r {
                 Ok(_) => continue,
-                Err(UsbError::WouldBlock) => break,
+                Err(UsbError::WouldBlock) => {
+                    self.read_blocked = false;
+                    break;
+                },
                 Err(e) => return Err(e),
             }
         }
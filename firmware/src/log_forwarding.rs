@@ -0,0 +1,49 @@
+//! Forwarding of log records to the host through the command protocol
+
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{self, Mutex};
+use bbqueue::Producer;
+use log::Record;
+use deadbug_common::protocol::notification::LogLevel;
+use deadbug_device::log_records::{forwarded, serialize_log_record, LogRecordProducer, MAX_LOG_RECORD_SIZE};
+use crate::systick;
+
+static PRODUCER: Mutex<RefCell<Option<LogRecordProducer>>> = Mutex::new(RefCell::new(None));
+/// Mirrors `Device::log_level`, the hook can't reach the device
+static MAX_LEVEL: Mutex<Cell<Option<LogLevel>>> = Mutex::new(Cell::new(None));
+
+/// Starts queueing the records the host asks for, `CommandProcessor` sends them
+pub fn init(producer: Producer) {
+    interrupt::free(|cs| PRODUCER.borrow(cs).replace(Some(LogRecordProducer::new(producer))));
+    stm32_log::set_hook(forward);
+}
+
+pub fn set_level(level: Option<LogLevel>) {
+    interrupt::free(|cs| MAX_LEVEL.borrow(cs).set(level));
+}
+
+/// Returns true if a record was dropped since the last call
+pub fn take_overrun() -> bool {
+    interrupt::free(|cs| match PRODUCER.borrow(cs).borrow_mut().as_mut() {
+        Some(producer) => producer.take_overrun(),
+        None => false,
+    })
+}
+
+fn forward(record: &Record) {
+    let level = record.level().into();
+    if !interrupt::free(|cs| forwarded(level, MAX_LEVEL.borrow(cs).get())) {
+        return;
+    }
+    // Formatting takes long, the interrupts are only disabled to queue the finished record
+    let mut buffer = [0; MAX_LOG_RECORD_SIZE];
+    let size = match serialize_log_record(&mut buffer, level, systick::now_ms(), record.target(), *record.args()) {
+        Ok(size) => size,
+        Err(_) => return,
+    };
+    interrupt::free(|cs| {
+        if let Some(producer) = PRODUCER.borrow(cs).borrow_mut().as_mut() {
+            producer.write(&buffer[..size]);
+        }
+    });
+}
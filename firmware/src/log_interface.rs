@@ -0,0 +1,77 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::Result;
+use deadbug_common::protocol::fragment::BULK_PACKET_SIZE;
+use deadbug_common::protocol::usb::{USB_CLASS_VENDOR_SPECIFIC, LOG_INTERFACE_SUBCLASS, LOG_REQUEST_ENABLE};
+
+/// Vendor-specific interface sending the `stm32-log` output to the host
+///
+/// The log goes to the UART until the host enables this interface with `LOG_REQUEST_ENABLE`,
+/// and again after a bus reset. The host reads it packet by packet, so transfers don't need to
+/// end with a short packet.
+pub struct LogInterface<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    write_ep: EndpointIn<'a, B>,
+    enabled: bool,
+}
+
+impl<'a, B: UsbBus> LogInterface<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            write_ep: alloc.bulk(BULK_PACKET_SIZE as u16),
+            enabled: false,
+        }
+    }
+
+    pub fn interface(&self) -> InterfaceNumber {
+        self.interface
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        stm32_log::redirect(enabled);
+    }
+
+    /// Starts sending the buffered log if the endpoint is idle
+    pub fn process(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let write_ep = &self.write_ep;
+        stm32_log::drain(|data| {
+            let size = core::cmp::min(data.len(), BULK_PACKET_SIZE);
+            write_ep.write(&data[..size]).unwrap_or(0)
+        });
+    }
+}
+
+impl<'a, B: UsbBus> UsbClass<B> for LogInterface<'a, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, USB_CLASS_VENDOR_SPECIFIC, LOG_INTERFACE_SUBCLASS, 0)?;
+        writer.endpoint(&self.write_ep)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.set_enabled(false);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Interface
+            && req.index == u16::from(u8::from(self.interface))
+            && req.request == LOG_REQUEST_ENABLE
+        {
+            self.set_enabled(req.value != 0);
+            xfer.accept().ok();
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() {
+            self.process();
+        }
+    }
+}
@@ -1,80 +1,192 @@
 #![no_std]
 #![no_main]
+// Triggered by the code generated by the RTIC 0.5 macros, static_mut_refs by the resource
+// accesses. The app has to be in the crate root and takes no attributes, so they are allowed
+// for the crate root and warned about again in the modules below.
+#![allow(static_mut_refs, non_local_definitions, unexpected_cfgs)]
 
 //extern crate panic_semihosting;
 
-use cortex_m::asm::delay;
-use cortex_m_rt::entry;
-use stm32_usbd::UsbBus;
-use stm32f3xx_hal::{prelude::*, stm32, hal::digital::v2::OutputPin};
-use log::{info, error};
-use core::panic::PanicInfo;
+use cortex_m::asm::{delay, wfi};
+use cortex_m::peripheral::NVIC;
+use rtic::app;
+use stm32_usbd::{UsbBus, UsbBusType};
+use stm32f3xx_hal::{prelude::*, stm32, stm32::Interrupt, hal::digital::v2::OutputPin};
+use usb_device::bus::UsbBusAllocator;
+use stm32_log::{info, error};
 
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
 mod app;
-mod cobs;
-mod cobs_tx;
-mod command_processor;
+#[cfg(not(feature = "cdc"))]
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod bulk_interface;
+#[cfg(feature = "cdc")]
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
 mod dumb_serial;
-mod packet_processor;
-#[allow(unused)]
-mod smart_serial;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod log_forwarding;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod log_interface;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod ms_os_descriptors;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod persistent_panic;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod systick;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
 mod targets;
+#[warn(static_mut_refs, non_local_definitions, unexpected_cfgs)]
+mod watchdog;
 
 use targets::f3_disco::BoardGpioPinSet;
 
+/// Longest time the idle loop may be kept from running, i.e. the longest task run
+const WATCHDOG_TIMEOUT_MS: u32 = 500;
+
 fn configure_usb_clock() {
     let rcc = unsafe { &*stm32::RCC::ptr() };
     rcc.cfgr.modify(|_, w| w.usbpre().set_bit());
 }
 
-#[panic_handler]
-fn panic_handler(panic_info: &PanicInfo) -> ! {
-    error!("panic! {}", panic_info);
-    loop {
-        log::logger().flush();
+// The USB interrupts poll the device and trigger `process`, which handles the commands.
+// SysTick triggers it too while there is periodic work, e.g. pin watching and the keep-alive
+// timeout. The UART interrupt
+// writes the log, the idle loop only feeds the watchdog and sleeps.
+#[app(device = stm32f3xx_hal::stm32, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        usb: app::Usb,
+        app: app::App,
+        watchdog: watchdog::Watchdog,
     }
-}
-
-#[entry]
-fn main() -> ! {
-    let dp = stm32::Peripherals::take().unwrap();
-
-    let mut flash = dp.FLASH.constrain();
-    let mut rcc = dp.RCC.constrain();
 
-    let clocks = rcc
-        .cfgr
-        .sysclk(48.mhz())
-        .pclk1(24.mhz())
-        .pclk2(24.mhz())
-        .freeze(&mut flash.acr);
-
-    let gpioc = dp.GPIOC.split(&mut rcc.ahb);
-    stm32_log::configure(dp.USART1, gpioc.pc4, gpioc.pc5, 115_200.bps(), clocks);
-    log::set_max_level(log::LevelFilter::Trace);
-    //log::set_max_level(log::LevelFilter::Error);
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
+        static mut LOG_BUFFER: [u8; 4096] = [0; 4096];
+
+        let dp = cx.device;
+
+        let mut flash = dp.FLASH.constrain();
+        let mut rcc = dp.RCC.constrain();
+
+        let clocks = rcc
+            .cfgr
+            .sysclk(48.mhz())
+            .pclk1(24.mhz())
+            .pclk2(24.mhz())
+            .freeze(&mut flash.acr);
+
+        let gpioc = dp.GPIOC.split(&mut rcc.ahb);
+        stm32_log::configure(dp.USART1, gpioc.pc4, gpioc.pc5, 115_200.bps(), clocks, LOG_BUFFER);
+        stm32_log::set_timestamp_source(systick::now_ms);
+        stm32_log::set_format(stm32_log::Format {
+            timestamp: true,
+            level: true,
+            target: false,
+        });
+        // The records the host asks for reach the hook whatever the UART levels are
+        log::set_max_level(log::LevelFilter::Trace);
+        stm32_log::set_level(log::LevelFilter::Info);
+
+        info!("========================================");
+        error!("clocks: sysclk={}, hclk={}", clocks.sysclk().0, clocks.hclk().0);
+
+        let last_panic = persistent_panic::take_last_panic();
+        if let Some(message) = last_panic.message() {
+            error!("reset after panic: {}", message);
+        }
+        if let Some(fault) = last_panic.fault() {
+            error!("fault registers: lr={:#010x} cfsr={:#010x} hfsr={:#010x}", fault.lr, fault.cfsr, fault.hfsr);
+        }
+        if watchdog::take_reset_flag() {
+            error!("reset by watchdog");
+        }
+        systick::start(cx.core.SYST, clocks.hclk().0);
+        let watchdog = watchdog::Watchdog::start(dp.IWDG, &dp.DBGMCU, WATCHDOG_TIMEOUT_MS);
+
+        let mut gpioa = dp.GPIOA.split(&mut rcc.ahb);
+
+        // F3 Discovery board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        let _ = usb_dp.set_low();
+        delay(clocks.sysclk().0 / 100);
+
+        let usb_dm = gpioa.pa11.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
+        let usb_dp = usb_dp.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
+
+        configure_usb_clock();
+
+        *USB_BUS = Some(UsbBus::new(dp.USB, (usb_dm, usb_dp)));
+
+        let devices = app::AppDevices {
+            bus: USB_BUS.as_ref().unwrap(),
+            pins: BoardGpioPinSet::new(),
+            last_panic,
+        };
+        let (usb, app) = app::app_init(devices);
+
+        init::LateResources {
+            usb,
+            app,
+            watchdog,
+        }
+    }
 
-    info!("========================================");
-    error!("clocks: sysclk={}, hclk={}", clocks.sysclk().0, clocks.hclk().0);
+    #[idle(resources = [watchdog])]
+    fn idle(cx: idle::Context) -> ! {
+        loop {
+            // Not fed while a task hangs
+            cx.resources.watchdog.feed();
+            wfi();
+        }
+    }
 
-    let mut gpioa = dp.GPIOA.split(&mut rcc.ahb);
+    /// Writes the log to the UART, unless the host reads it from the USB log interface
+    #[task(binds = USART1_EXTI25, priority = 1)]
+    fn log_uart(_: log_uart::Context) {
+        stm32_log::on_interrupt();
+    }
 
-    // F3 Discovery board has a pull-up resistor on the D+ line.
-    // Pull the D+ pin down to send a RESET condition to the USB bus.
-    let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
-    let _ = usb_dp.set_low();
-    delay(clocks.sysclk().0 / 100);
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, resources = [usb], spawn = [process])]
+    fn usb_lp(cx: usb_lp::Context) {
+        if cx.resources.usb.poll() {
+            // Would fire again right away, `process` unmasks it after freeing queue space
+            NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+        }
+        cx.spawn.process().ok();
+    }
 
-    let usb_dm = gpioa.pa11.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
-    let usb_dp = usb_dp.into_af14(&mut gpioa.moder, &mut gpioa.afrh);
+    #[task(binds = USB_HP_CAN_TX, priority = 2, resources = [usb], spawn = [process])]
+    fn usb_hp(cx: usb_hp::Context) {
+        if cx.resources.usb.poll() {
+            NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+        }
+        cx.spawn.process().ok();
+    }
 
-    configure_usb_clock();
+    #[task(binds = SysTick, priority = 2, spawn = [process])]
+    fn tick(cx: tick::Context) {
+        // Otherwise the USB interrupts bring the next work, the core sleeps until then
+        if systick::tick() {
+            cx.spawn.process().ok();
+        }
+    }
 
-    let usb_bus = UsbBus::new(dp.USB, (usb_dm, usb_dp));
+    /// A failed spawn means a run is already pending, that run picks up the new work
+    #[task(priority = 1, resources = [usb, app])]
+    fn process(cx: process::Context) {
+        let mut usb = cx.resources.usb;
+        let (connected, overrun) = usb.lock(|usb| (usb.connected(), usb.take_overrun()));
+        cx.resources.app.process(connected, overrun, systick::now_ms());
+        systick::set_polling(cx.resources.app.needs_polling());
+        // Start sending the responses and take the data held back by a full queue
+        usb.lock(|usb| usb.process());
+        unsafe { NVIC::unmask(Interrupt::USB_LP_CAN_RX0) };
+    }
 
-    let devices = app::AppDevices {
-        bus: usb_bus,
-        pins: BoardGpioPinSet::new(),
-    };
-    app::app_run(devices)
-}
+    extern "C" {
+        fn SPI3();
+    }
+};
@@ -0,0 +1,163 @@
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::Result;
+
+const CAPABILITY_TYPE_PLATFORM: u8 = 0x05;
+
+/// Vendor request which returns the MS OS 2.0 descriptor set, chosen freely
+const MS_VENDOR_CODE: u8 = 0x20;
+/// `wIndex` of the descriptor set request
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+/// Windows 8.1, the first version with MS OS 2.0 descriptors
+const MS_WINDOWS_VERSION: [u8; 4] = [0x00, 0x00, 0x03, 0x06];
+
+/// The MS OS 2.0 platform capability, D8DD60DF-4589-4CC7-9CD2-659D9E648A9F
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c,
+    0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+const SET_HEADER_DESCRIPTOR: u8 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u8 = 0x01;
+const SUBSET_HEADER_FUNCTION: u8 = 0x02;
+const FEATURE_COMPATIBLE_ID: u8 = 0x03;
+const FEATURE_REG_PROPERTY: u8 = 0x04;
+const REG_MULTI_SZ: u8 = 0x07;
+
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+const GUID_LENGTH: usize = 38;
+
+const HEADER_LENGTH: usize = 10;
+const SUBSET_HEADER_LENGTH: usize = 8;
+const COMPATIBLE_ID_LENGTH: usize = 20;
+/// UTF-16 with a terminating null
+const PROPERTY_NAME_LENGTH: usize = (PROPERTY_NAME.len() + 1) * 2;
+/// UTF-16 REG_MULTI_SZ, terminated by two nulls
+const PROPERTY_DATA_LENGTH: usize = (GUID_LENGTH + 2) * 2;
+const REGISTRY_PROPERTY_LENGTH: usize = 10 + PROPERTY_NAME_LENGTH + PROPERTY_DATA_LENGTH;
+const FUNCTION_LENGTH: usize = SUBSET_HEADER_LENGTH + COMPATIBLE_ID_LENGTH + REGISTRY_PROPERTY_LENGTH;
+
+const MAX_FUNCTIONS: usize = 2;
+
+static mut DESCRIPTOR_SET: [u8; HEADER_LENGTH + SUBSET_HEADER_LENGTH + MAX_FUNCTIONS * FUNCTION_LENGTH] =
+    [0; HEADER_LENGTH + SUBSET_HEADER_LENGTH + MAX_FUNCTIONS * FUNCTION_LENGTH];
+
+/// Interface of a composite device bound to the WinUSB driver
+pub struct WinUsbFunction {
+    pub interface: InterfaceNumber,
+    /// Interface GUID applications use to find the interface, in braces
+    pub guid: &'static str,
+}
+
+/// Appends the fields of a descriptor
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn u8(&mut self, value: u8) {
+        self.buf[self.position] = value;
+        self.position += 1;
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.position..self.position + data.len()].copy_from_slice(data);
+        self.position += data.len();
+    }
+
+    /// Writes an ASCII string as UTF-16
+    fn utf16(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.u16(u16::from(byte));
+        }
+    }
+}
+
+/// MS OS 2.0 descriptors, bind WinUSB to the vendor interfaces without an INF file
+///
+/// Doesn't have interfaces of its own, it only answers the BOS and descriptor set requests.
+pub struct MsOsDescriptors {
+    set: &'static [u8],
+}
+
+impl MsOsDescriptors {
+    /// Must be called once, the descriptor set is kept in a static buffer
+    pub fn new(functions: &[WinUsbFunction]) -> Self {
+        assert!(functions.len() <= MAX_FUNCTIONS);
+        let length = HEADER_LENGTH + SUBSET_HEADER_LENGTH + functions.len() * FUNCTION_LENGTH;
+        let buf = unsafe { &mut DESCRIPTOR_SET[..length] };
+        let mut writer = Writer { buf, position: 0 };
+
+        writer.u16(HEADER_LENGTH as u16);
+        writer.u16(u16::from(SET_HEADER_DESCRIPTOR));
+        writer.bytes(&MS_WINDOWS_VERSION);
+        writer.u16(length as u16);
+
+        writer.u16(SUBSET_HEADER_LENGTH as u16);
+        writer.u16(u16::from(SUBSET_HEADER_CONFIGURATION));
+        // Index of the configuration
+        writer.u8(0);
+        writer.u8(0);
+        writer.u16((length - HEADER_LENGTH) as u16);
+
+        for function in functions {
+            assert_eq!(function.guid.len(), GUID_LENGTH);
+
+            writer.u16(SUBSET_HEADER_LENGTH as u16);
+            writer.u16(u16::from(SUBSET_HEADER_FUNCTION));
+            writer.u8(function.interface.into());
+            writer.u8(0);
+            writer.u16(FUNCTION_LENGTH as u16);
+
+            // The sub-compatible ID stays empty
+            writer.u16(COMPATIBLE_ID_LENGTH as u16);
+            writer.u16(u16::from(FEATURE_COMPATIBLE_ID));
+            writer.bytes(b"WINUSB\0\0");
+            writer.bytes(&[0; 8]);
+
+            writer.u16(REGISTRY_PROPERTY_LENGTH as u16);
+            writer.u16(u16::from(FEATURE_REG_PROPERTY));
+            writer.u16(u16::from(REG_MULTI_SZ));
+            writer.u16(PROPERTY_NAME_LENGTH as u16);
+            writer.utf16(PROPERTY_NAME);
+            writer.u16(0);
+            writer.u16(PROPERTY_DATA_LENGTH as u16);
+            writer.utf16(function.guid);
+            writer.u16(0);
+            writer.u16(0);
+        }
+        assert_eq!(writer.position, length);
+
+        Self {
+            set: unsafe { &DESCRIPTOR_SET[..length] },
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MsOsDescriptors {
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        let mut data = [0; 24];
+        data[1..17].copy_from_slice(&MS_OS_20_PLATFORM_UUID);
+        data[17..21].copy_from_slice(&MS_WINDOWS_VERSION);
+        data[21..23].copy_from_slice(&(self.set.len() as u16).to_le_bytes());
+        data[23] = MS_VENDOR_CODE;
+        // bAltEnumCode is 0
+        writer.capability(CAPABILITY_TYPE_PLATFORM, &data)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == MS_VENDOR_CODE
+            && req.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            xfer.accept_with_static(self.set).ok();
+        }
+    }
+}
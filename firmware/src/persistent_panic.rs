@@ -0,0 +1,83 @@
+//! Panic and hard fault handlers keeping the report over a reset
+
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use deadbug_common::protocol::system::FaultRegisters;
+use deadbug_device::panic_record::PanicRecord;
+use log::error;
+
+/// Not initialized on startup, so the record survives the reset
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+fn record() -> &'static mut PanicRecord {
+    // Any RAM contents form a valid record, the magic number tells whether it holds a message
+    unsafe { &mut *(addr_of_mut!(PANIC_RECORD) as *mut PanicRecord) }
+}
+
+/// Takes the panic message and fault registers recorded before the last reset
+pub fn take_last_panic() -> PanicRecord {
+    let record = record();
+    let last_panic = record.clone();
+    record.clear();
+    last_panic
+}
+
+#[panic_handler]
+fn panic_handler(panic_info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    // Skip straight to the reset if logging the message panics too
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let record = record();
+        record.start();
+        write!(record, "{}", panic_info).ok();
+
+        error!("panic! {}", panic_info);
+        log::logger().flush();
+    }
+    SCB::sys_reset()
+}
+
+#[exception]
+fn HardFault(frame: &ExceptionFrame) -> ! {
+    let scb = unsafe { &*SCB::ptr() };
+    let registers = FaultRegisters {
+        r0: frame.r0,
+        r1: frame.r1,
+        r2: frame.r2,
+        r3: frame.r3,
+        r12: frame.r12,
+        lr: frame.lr,
+        pc: frame.pc,
+        xpsr: frame.xpsr,
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        mmfar: scb.mmfar.read(),
+        bfar: scb.bfar.read(),
+    };
+    // A fault in the panic handler leaves its report
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let record = record();
+        record.start();
+        write!(record, "hard fault at {:#010x}", registers.pc).ok();
+        record.set_fault(registers);
+
+        error!(
+            "hard fault! pc={:#010x} lr={:#010x} xpsr={:#010x} cfsr={:#010x} hfsr={:#010x} mmfar={:#010x} bfar={:#010x}",
+            registers.pc, registers.lr, registers.xpsr, registers.cfsr, registers.hfsr, registers.mmfar, registers.bfar,
+        );
+        error!(
+            "r0={:#010x} r1={:#010x} r2={:#010x} r3={:#010x} r12={:#010x}",
+            registers.r0, registers.r1, registers.r2, registers.r3, registers.r12,
+        );
+        log::logger().flush();
+    }
+    SCB::sys_reset()
+}
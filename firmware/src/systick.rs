@@ -0,0 +1,34 @@
+//! Millisecond time base for the host supervision
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::peripheral::SYST;
+use cortex_m::peripheral::syst::SystClkSource;
+
+static MILLISECONDS: AtomicU32 = AtomicU32::new(0);
+/// The device has work to do without a command from the host
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+/// Starts the SysTick interrupt at 1 kHz
+pub fn start(mut syst: SYST, hclk: u32) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(hclk / 1000 - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    syst.enable_interrupt();
+}
+
+/// Returns the milliseconds since `start`, wraps around after 49 days
+pub fn now_ms() -> u32 {
+    MILLISECONDS.load(Ordering::Relaxed)
+}
+
+/// Sets whether the ticks should run the device processing
+pub fn set_polling(polling: bool) {
+    POLLING.store(polling, Ordering::Relaxed);
+}
+
+/// Called from the SysTick interrupt, returns true if the device processing should run
+pub fn tick() -> bool {
+    MILLISECONDS.fetch_add(1, Ordering::Relaxed);
+    POLLING.load(Ordering::Relaxed)
+}
@@ -1,19 +1,22 @@
 use deadbug_common::hal::gpio::{GpioPin, GpioPinMode};
 use deadbug_common::hal::{HalResult, HalErrorKind};
 use stm32f3xx_hal::stm32;
-use deadbug_common::protocol::gpio::GpioPinInformation;
+use deadbug_common::protocol::gpio::{GpioPinInformation, GpioPinLabel, GpioPinModes};
+use deadbug_device::pin_allocator::{GpioPinSet, MAX_PINS};
 
 pub struct BoardGpioPin {
     index: u8,
+    label: &'static str,
     mode: GpioPinMode,
 }
 
 impl BoardGpioPin {
-    pub(crate) fn new(peripheral: u8, pin_index: u8) -> Self {
+    pub(crate) fn new(peripheral: u8, pin_index: u8, label: &'static str) -> Self {
         assert!(peripheral < 6);
         assert!(pin_index < 16);
         Self {
             index: (peripheral << 4) | (pin_index & 0xf),
+            label,
             mode: GpioPinMode::FloatingInput,
         }
     }
@@ -47,6 +50,9 @@ impl GpioPin for BoardGpioPin {
         GpioPinInformation {
             index_major: self.peripheral() + b'A',
             index_minor: self.pin_index(),
+            label: GpioPinLabel::new(self.label),
+            modes: GpioPinModes::FLOATING_INPUT.union(GpioPinModes::PUSH_PULL_OUTPUT),
+            alternate_functions: 0,
         }
     }
 
@@ -116,8 +122,13 @@ impl GpioPin for BoardGpioPin {
     }
 }
 
+pub const BOARD_GPIO_PIN_COUNT: usize = 8;
+
+// The device can't be created if the pins don't fit the pin allocator
+const _: () = assert!(BOARD_GPIO_PIN_COUNT <= MAX_PINS);
+
 pub struct BoardGpioPinSet {
-    pins: [BoardGpioPin; 8]
+    pins: [BoardGpioPin; BOARD_GPIO_PIN_COUNT]
 }
 
 impl BoardGpioPinSet {
@@ -128,40 +139,30 @@ impl BoardGpioPinSet {
         rcc.ahbrstr.modify(|_, w| w.ioperst().clear_bit());
 
         let pins = [
-            BoardGpioPin::new(4, 8),  // PE8, blue led
-            BoardGpioPin::new(4, 9),  // PE9, red led
-            BoardGpioPin::new(4, 10), // PE10, orange led
-            BoardGpioPin::new(4, 11), // PE11, green led
-            BoardGpioPin::new(4, 12), // PE12, blue led
-            BoardGpioPin::new(4, 13), // PE13, red led
-            BoardGpioPin::new(4, 14), // PE14, orange led
-            BoardGpioPin::new(4, 15), // PE15, green led
+            BoardGpioPin::new(4, 8, "LD4"),  // PE8, blue led
+            BoardGpioPin::new(4, 9, "LD3"),  // PE9, red led
+            BoardGpioPin::new(4, 10, "LD5"), // PE10, orange led
+            BoardGpioPin::new(4, 11, "LD7"), // PE11, green led
+            BoardGpioPin::new(4, 12, "LD9"), // PE12, blue led
+            BoardGpioPin::new(4, 13, "LD10"), // PE13, red led
+            BoardGpioPin::new(4, 14, "LD8"), // PE14, orange led
+            BoardGpioPin::new(4, 15, "LD6"), // PE15, green led
         ];
 
         Self {
             pins,
         }
     }
-
-    pub fn len(&self) -> usize {
-        self.pins.len()
-    }
 }
 
-impl<'a> IntoIterator for &'a BoardGpioPinSet {
-    type Item = &'a BoardGpioPin;
-    type IntoIter = core::slice::Iter<'a, BoardGpioPin>;
+impl GpioPinSet for BoardGpioPinSet {
+    type Pin = BoardGpioPin;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.pins.iter()
+    fn pins(&self) -> &[BoardGpioPin] {
+        &self.pins
     }
-}
-
-impl<'a> IntoIterator for &'a mut BoardGpioPinSet {
-    type Item = &'a mut BoardGpioPin;
-    type IntoIter = core::slice::IterMut<'a, BoardGpioPin>;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.pins.iter_mut()
+    fn pins_mut(&mut self) -> &mut [BoardGpioPin] {
+        &mut self.pins
     }
 }
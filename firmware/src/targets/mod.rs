@@ -1,3 +1,3 @@
 pub mod f3_disco;
 
-pub use f3_disco::{BoardGpioPin, BoardGpioPinSet};
+pub use f3_disco::BoardGpioPinSet;
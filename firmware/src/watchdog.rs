@@ -0,0 +1,52 @@
+//! Independent watchdog
+//!
+//! Runs from the LSI oscillator, so it resets the MCU even if the main clock stops. The main
+//! loop feeds it on every iteration.
+
+use core::cmp;
+use stm32f3xx_hal::stm32::{DBGMCU, IWDG, RCC};
+
+/// Nominal LSI frequency, the actual one is between 30 and 50 kHz
+const LSI_FREQUENCY: u32 = 40_000;
+
+/// Watchdog clock divider, see `PR` below
+const PRESCALER: u32 = 32;
+
+/// Maximum value of the 12-bit reload register
+const MAX_RELOAD: u32 = 0xfff;
+
+pub struct Watchdog {
+    iwdg: IWDG,
+}
+
+impl Watchdog {
+    /// Starts the watchdog, it can't be stopped afterwards
+    ///
+    /// The timeout is approximate, the LSI is not calibrated.
+    pub fn start(iwdg: IWDG, dbgmcu: &DBGMCU, timeout_ms: u32) -> Self {
+        // Don't reset while the core is halted by a debugger
+        dbgmcu.apb1_fz.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+
+        let reload = cmp::min(timeout_ms * (LSI_FREQUENCY / 1000) / PRESCALER, MAX_RELOAD) as u16;
+        iwdg.kr.write(|w| w.key().start());
+        iwdg.kr.write(|w| w.key().enable());
+        iwdg.pr.write(|w| w.pr().divide_by32());
+        iwdg.rlr.write(|w| w.rl().bits(reload));
+        while iwdg.sr.read().pvu().bit_is_set() || iwdg.sr.read().rvu().bit_is_set() {}
+        iwdg.kr.write(|w| w.key().reset());
+
+        Self { iwdg }
+    }
+
+    pub fn feed(&mut self) {
+        self.iwdg.kr.write(|w| w.key().reset());
+    }
+}
+
+/// Returns true if the last reset was caused by the watchdog, clears the reset flags
+pub fn take_reset_flag() -> bool {
+    let rcc = unsafe { &*RCC::ptr() };
+    let flag = rcc.csr.read().iwdgrstf().bit_is_set();
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+    flag
+}
@@ -0,0 +1,127 @@
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::{GpioPin, GpioPinMode};
+use deadbug_common::protocol::gpio::{GpioPinInformation, GpioPinLabel, GpioPinModes};
+use deadbug_device::pin_allocator::GpioPinSet;
+
+/// Simulated pin
+///
+/// The level seen in input mode is set by the test, the level driven in output mode
+/// is recorded.
+pub struct SimPin {
+    info: GpioPinInformation,
+    mode: GpioPinMode,
+    input: bool,
+    output: bool,
+}
+
+impl SimPin {
+    pub fn new(info: GpioPinInformation) -> Self {
+        Self {
+            info,
+            mode: GpioPinMode::FloatingInput,
+            input: false,
+            output: false,
+        }
+    }
+
+    /// Sets the level seen by the pin in input mode
+    pub fn set_input_level(&mut self, level: bool) {
+        self.input = level;
+    }
+
+    /// Returns the level driven by the pin, `None` if it's not an output
+    pub fn output_level(&self) -> Option<bool> {
+        match self.mode {
+            GpioPinMode::PushPullOutput => Some(self.output),
+            _ => None,
+        }
+    }
+}
+
+impl GpioPin for SimPin {
+    fn information(&self) -> GpioPinInformation {
+        self.info
+    }
+
+    fn mode(&self) -> GpioPinMode {
+        self.mode
+    }
+
+    fn set_mode(&mut self, mode: GpioPinMode) -> HalResult<()> {
+        if !self.info.supports(mode) {
+            return Err(HalErrorKind::InvalidGpioMode.into());
+        }
+        self.mode = mode;
+        Ok(())
+    }
+
+    fn set_output(&mut self, value: bool) -> HalResult<()> {
+        if self.mode == GpioPinMode::PushPullOutput {
+            self.output = value;
+            Ok(())
+        } else {
+            Err(HalErrorKind::InvalidGpioMode.into())
+        }
+    }
+
+    fn get_input(&self) -> HalResult<bool> {
+        if self.mode == GpioPinMode::FloatingInput {
+            Ok(self.input)
+        } else {
+            Err(HalErrorKind::InvalidGpioMode.into())
+        }
+    }
+}
+
+pub struct SimPinSet {
+    pins: Vec<SimPin>,
+}
+
+impl SimPinSet {
+    pub fn new(pins: Vec<SimPin>) -> Self {
+        Self {
+            pins,
+        }
+    }
+
+    /// Returns the information of all pins in enumeration order
+    pub fn information(&self) -> Vec<GpioPinInformation> {
+        self.pins.iter().map(|pin| pin.info).collect()
+    }
+
+    /// Pins of the STM32F3 Discovery board, as exposed by the firmware
+    pub fn f3_discovery() -> Self {
+        let leds = [
+            (8, "LD4"),
+            (9, "LD3"),
+            (10, "LD5"),
+            (11, "LD7"),
+            (12, "LD9"),
+            (13, "LD10"),
+            (14, "LD8"),
+            (15, "LD6"),
+        ];
+        let pins = leds.iter().map(|(index, label)| {
+            SimPin::new(GpioPinInformation {
+                index_major: b'E',
+                index_minor: *index,
+                label: GpioPinLabel::new(label),
+                modes: GpioPinModes::FLOATING_INPUT.union(GpioPinModes::PUSH_PULL_OUTPUT),
+                alternate_functions: 0,
+            })
+        }).collect();
+        Self::new(pins)
+    }
+}
+
+impl GpioPinSet for SimPinSet {
+    type Pin = SimPin;
+
+    fn pins(&self) -> &[SimPin] {
+        &self.pins
+    }
+
+    fn pins_mut(&mut self) -> &mut [SimPin] {
+        &mut self.pins
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use deadbug_common::hal::HalResult;
+use deadbug_common::hal::gpio::GpioPinMode;
+use deadbug_common::hal::gpio::GpioPin;
+use deadbug_common::protocol::channels::PacketChannel;
+use deadbug_common::protocol::fragment::{self, FragmentError, Reassembler, MAX_COMMAND_SIZE};
+use deadbug_common::protocol::notification::{BufferKind, LogLevel};
+use deadbug_common::protocol::system::FaultRegisters;
+use deadbug_device::Device;
+use deadbug_device::pin_allocator::GpioPinSet;
+use deadbug_device::device::{NeedBuffer, MIN_RESPONSE_BUFFER_SIZE, MAX_STREAM_MESSAGE_SIZE};
+use deadbug_device::notifications::{Notification, MAX_NOTIFICATION_SIZE};
+use deadbug_device::panic_record::PanicRecord;
+use deadbug_device::log_records::{forwarded, serialize_log_record, MAX_LOG_RECORD_SIZE};
+use crate::board::SimPinSet;
+
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct SimState {
+    device: Device<SimPinSet>,
+    reassembler: Reassembler,
+    /// Packets waiting to be read by the host
+    outgoing: VecDeque<Vec<u8>>,
+    read_timeout: Duration,
+    /// Time base of the keep-alive supervision
+    started: Instant,
+}
+
+impl SimState {
+    fn receive(&mut self, packet: &[u8]) {
+        let result = self.reassembler.push(packet);
+        if self.reassembler.take_lost() {
+            self.device.notify(Notification::BufferOverrun(BufferKind::CommandRx));
+        }
+        match result {
+            Ok(Some(command)) => self.process_command(&command),
+            Ok(None) => {},
+            Err(FragmentError::Overflow) => {
+                self.device.notify(Notification::BufferOverrun(BufferKind::CommandRx));
+            },
+            Err(FragmentError::UnexpectedSequence) => {},
+        }
+    }
+
+    fn process_command(&mut self, command: &[u8]) {
+        let mut response = vec![0; MIN_RESPONSE_BUFFER_SIZE];
+        loop {
+            match self.device.process_command(command, &mut response) {
+                Ok(size) => {
+                    self.send(&response[..size]);
+                    return;
+                },
+                Err(NeedBuffer(size)) => response.resize(size, 0),
+            }
+        }
+    }
+
+    /// Runs the periodic part of the device loop
+    fn poll(&mut self) {
+        // The firmware counter wraps around too
+        self.device.poll_supervision(self.started.elapsed().as_millis() as u32);
+        self.device.poll_events();
+
+        let mut buffer = [0; MAX_STREAM_MESSAGE_SIZE];
+        while self.device.stream_ready() {
+            let size = self.device.poll_streams(&mut buffer);
+            if size == 0 {
+                break;
+            }
+            self.send(&buffer[..size]);
+        }
+
+        let mut buffer = [0; MAX_NOTIFICATION_SIZE];
+        while self.device.notification_pending() {
+            let size = self.device.poll_notifications(&mut buffer);
+            self.send(&buffer[..size]);
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        if !message.is_empty() {
+            self.outgoing.extend(fragment::split_message(message));
+        }
+    }
+}
+
+struct SimShared {
+    state: Mutex<SimState>,
+    changed: Condvar,
+}
+
+/// In-process simulated device
+///
+/// Speaks the fragmented packet protocol, i.e. it's used with the host through
+/// `FragmentedChannel`. Clones are handles to the same device, so one handle can be used
+/// for reading and another one for writing.
+#[derive(Clone)]
+pub struct SimulatedDevice {
+    shared: Arc<SimShared>,
+}
+
+impl SimulatedDevice {
+    /// Fails with `NoResources` if there are more pins than the device supports
+    pub fn new(pins: SimPinSet) -> HalResult<Self> {
+        let state = SimState {
+            device: Device::new(pins)?,
+            reassembler: Reassembler::new(MAX_COMMAND_SIZE),
+            outgoing: VecDeque::new(),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            started: Instant::now(),
+        };
+        Ok(Self {
+            shared: Arc::new(SimShared {
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Creates a device with the pins of the STM32F3 Discovery board
+    pub fn f3_discovery() -> Self {
+        Self::new(SimPinSet::f3_discovery()).expect("the board pins fit the device")
+    }
+
+    /// Sets how long `read_packet` waits for a packet before failing with `TimedOut`
+    pub fn set_read_timeout(&self, timeout: Duration) {
+        self.shared.state.lock().unwrap().read_timeout = timeout;
+    }
+
+    /// Sets the level seen by the pin in input mode
+    pub fn set_input_level(&self, index: u8, level: bool) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(pin) = state.device.pins_mut().pin_set_mut().pins_mut().get_mut(index as usize) {
+            pin.set_input_level(level);
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Returns the level driven by the pin, `None` if it's not an output
+    pub fn output_level(&self, index: u8) -> Option<bool> {
+        let state = self.shared.state.lock().unwrap();
+        state.device.pins().pin(index).ok().and_then(|pin| pin.output_level())
+    }
+
+    pub fn pin_mode(&self, index: u8) -> Option<GpioPinMode> {
+        let state = self.shared.state.lock().unwrap();
+        state.device.pins().pin(index).ok().map(|pin| pin.mode())
+    }
+
+    /// Queues a notification as if it was raised by the firmware
+    pub fn notify(&self, notification: Notification) {
+        self.shared.state.lock().unwrap().device.notify(notification);
+        self.shared.changed.notify_all();
+    }
+
+    /// Sends a log record as if it was logged by the firmware
+    ///
+    /// Like the firmware, it's dropped unless the host asked for records of the level.
+    pub fn log(&self, level: LogLevel, target: &str, message: &str) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !forwarded(level, state.device.log_level()) {
+            return;
+        }
+        let timestamp = state.started.elapsed().as_millis() as u32;
+        let mut buffer = [0; MAX_LOG_RECORD_SIZE];
+        if let Ok(size) = serialize_log_record(&mut buffer, level, timestamp, target, format_args!("{}", message)) {
+            state.send(&buffer[..size]);
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Sets the panic message as if the firmware was reset after a panic
+    pub fn set_last_panic(&self, message: &str) {
+        let mut record = PanicRecord::new();
+        record.start();
+        record.write_str(message).ok();
+        self.shared.state.lock().unwrap().device.set_last_panic(&record);
+    }
+
+    /// Sets the message and the registers as if the firmware was reset after a hard fault
+    pub fn set_last_fault(&self, message: &str, registers: FaultRegisters) {
+        let mut record = PanicRecord::new();
+        record.start();
+        record.write_str(message).ok();
+        record.set_fault(registers);
+        self.shared.state.lock().unwrap().device.set_last_panic(&record);
+    }
+}
+
+impl PacketChannel for SimulatedDevice {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut state = self.shared.state.lock().unwrap();
+        let deadline = Instant::now() + state.read_timeout;
+        loop {
+            state.poll();
+            if let Some(packet) = state.outgoing.pop_front() {
+                return Ok(packet);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receive(data);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+//! Simulated bridge device
+//!
+//! Runs the same command processing as the firmware against simulated pins, so the host side
+//! can be tested without hardware.
+
+mod board;
+mod device;
+
+pub use board::{SimPin, SimPinSet};
+pub use device::SimulatedDevice;
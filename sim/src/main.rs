@@ -0,0 +1,190 @@
+//! Virtual bridge device on a pseudo-terminal
+//!
+//! Point the host tool at the printed path. Input levels of the simulated pins are controlled
+//! with `high <pin>` and `low <pin>` lines on stdin, `status` prints the pin states.
+
+use std::io::{self, BufRead, Read, Write};
+use std::{env, process, thread};
+use deadbug_common::protocol::channels::PacketChannel;
+use deadbug_common::protocol::gpio::{GpioPinInformation, GpioPinLabel, GpioPinModes};
+use deadbug_sim::{SimPin, SimPinSet, SimulatedDevice};
+
+#[cfg(unix)]
+mod pty;
+
+const USAGE: &str = "usage: deadbug-sim [--pin <port name>[:<label>]]... [--link <path>]
+
+Without --pin options the pins of the STM32F3 Discovery board are simulated.";
+
+struct Options {
+    pins: Vec<GpioPinInformation>,
+    link: Option<String>,
+}
+
+/// Parses pin specifications like "PA0:BUTTON"
+fn parse_pin(spec: &str) -> Option<GpioPinInformation> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next()?.to_ascii_uppercase();
+    let label = parts.next().unwrap_or(&name);
+    let mut chars = name.chars();
+    if chars.next()? != 'P' {
+        return None;
+    }
+    let port = chars.next().filter(|c| c.is_ascii_uppercase())?;
+    let number = chars.as_str().parse().ok().filter(|n| *n < 16)?;
+    Some(GpioPinInformation {
+        index_major: port as u8,
+        index_minor: number,
+        label: GpioPinLabel::new(label),
+        modes: GpioPinModes::FLOATING_INPUT.union(GpioPinModes::PUSH_PULL_OUTPUT),
+        alternate_functions: 0,
+    })
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        pins: Vec::new(),
+        link: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pin" => {
+                let spec = args.next().ok_or("--pin needs a value")?;
+                let pin = parse_pin(&spec).ok_or_else(|| format!("invalid pin: {}", spec))?;
+                options.pins.push(pin);
+            },
+            "--link" => {
+                options.link = Some(args.next().ok_or("--link needs a value")?);
+            },
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Finds the pin by its label or port name
+fn find_pin(pins: &[GpioPinInformation], name: &str) -> Option<u8> {
+    pins.iter().position(|info| {
+        let port_name = format!("P{}{}", info.port(), info.index_minor);
+        info.label.as_str().eq_ignore_ascii_case(name) || port_name.eq_ignore_ascii_case(name)
+    }).map(|index| index as u8)
+}
+
+fn run_console(device: SimulatedDevice, pins: Vec<GpioPinInformation>) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [level @ "high", name] | [level @ "low", name] => {
+                match find_pin(&pins, name) {
+                    Some(index) => device.set_input_level(index, *level == "high"),
+                    None => println!("unknown pin: {}", name),
+                }
+            },
+            ["status"] => {
+                for (index, info) in pins.iter().enumerate() {
+                    let index = index as u8;
+                    println!("P{}{} {:<8} {:?} output={:?}", info.port(), info.index_minor, info.label.as_str(),
+                        device.pin_mode(index), device.output_level(index));
+                }
+            },
+            [] => {},
+            _ => println!("commands: high <pin>, low <pin>, status"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let (pin_set, pins) = if options.pins.is_empty() {
+        let pin_set = SimPinSet::f3_discovery();
+        let pins = pin_set.information();
+        (pin_set, pins)
+    } else {
+        let pin_set = SimPinSet::new(options.pins.iter().map(|info| SimPin::new(*info)).collect());
+        (pin_set, options.pins)
+    };
+    let device = match SimulatedDevice::new(pin_set) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("can't create the device: {:?}", e);
+            process::exit(2);
+        }
+    };
+
+    let pty = pty::Pty::open().expect("can't allocate a pseudo-terminal");
+    let path = match options.link {
+        Some(link) => {
+            std::fs::remove_file(&link).ok();
+            std::os::unix::fs::symlink(&pty.path, &link).expect("can't create the link");
+            link
+        },
+        None => pty.path.clone(),
+    };
+    println!("simulated device at {}", path);
+
+    // Host to device: split the byte stream into COBS frames
+    let mut master = pty.master.try_clone().unwrap();
+    let mut writer = device.clone();
+    thread::spawn(move || {
+        let mut frame = Vec::new();
+        let mut buf = [0; 256];
+        loop {
+            let size = master.read(&mut buf).expect("read failed");
+            for &b in &buf[..size] {
+                if b != 0 {
+                    frame.push(b);
+                    continue;
+                }
+                if !frame.is_empty() {
+                    if let Ok(packet) = cobs::decode_vec(&frame) {
+                        writer.write_packet(&packet).ok();
+                    }
+                    frame.clear();
+                }
+            }
+        }
+    });
+
+    // Device to host
+    let mut master = pty.master.try_clone().unwrap();
+    let mut reader = device.clone();
+    thread::spawn(move || {
+        loop {
+            match reader.read_packet() {
+                Ok(packet) => {
+                    let mut data = cobs::encode_vec(&packet);
+                    data.push(0);
+                    master.write_all(&data).expect("write failed");
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => panic!("device failed: {}", e),
+            }
+        }
+    });
+
+    run_console(device, pins);
+    // Keep serving after stdin is closed
+    loop {
+        thread::park();
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("deadbug-sim needs pseudo-terminal support");
+    process::exit(1);
+}
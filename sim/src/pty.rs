@@ -0,0 +1,54 @@
+//! Pseudo-terminal allocation
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+
+pub struct Pty {
+    /// Device side of the terminal
+    pub master: File,
+    /// Kept open, so that the terminal survives host reconnects
+    _slave: File,
+    /// Path opened by the host
+    pub path: String,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+impl Pty {
+    /// Allocates a terminal in raw mode
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let master_fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            let master = File::from_raw_fd(master_fd);
+            check(libc::grantpt(master_fd))?;
+            check(libc::unlockpt(master_fd))?;
+
+            let mut name = [0 as libc::c_char; 128];
+            check(libc::ptsname_r(master_fd, name.as_mut_ptr(), name.len()))?;
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+
+            let slave_fd = check(libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY))?;
+            let slave = File::from_raw_fd(slave_fd);
+
+            // The wire protocol is binary, disable echo and line editing
+            let mut termios: libc::termios = std::mem::zeroed();
+            check(libc::tcgetattr(slave_fd, &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave_fd, libc::TCSANOW, &termios))?;
+
+            Ok(Self {
+                master,
+                _slave: slave,
+                path,
+            })
+        }
+    }
+}
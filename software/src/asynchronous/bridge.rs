@@ -0,0 +1,167 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use deadbug_common::hal::HalResult;
+use deadbug_common::protocol::async_channels::{AsyncCommandChannel, AsyncDeviceChannel, AsyncEndpointChannel};
+use deadbug_common::protocol::channels::FragmentedChannel;
+use deadbug_common::protocol::serialize_vec;
+use deadbug_common::protocol::notification::NotificationDispatcher;
+use deadbug_common::protocol::gpio::GPIO_ENDPOINT;
+use deadbug_common::protocol::system::{SafePinState, SystemCommand, SYSTEM_ENDPOINT};
+use crate::asynchronous::gpio::AsyncGpioPeripheral;
+use crate::asynchronous::serial::AsyncCobsChannel;
+use crate::crash_report::CrashReport;
+use crate::bridge::{deserialize_list, deserialize_panic_message, deserialize_response, keep_alive_millis, log_level, LogForwarder, StreamWindow};
+
+pub struct AsyncBridgeDevice {
+    channel: Arc<dyn AsyncCommandChannel>,
+    log_forwarder: Mutex<Option<LogForwarder>>,
+}
+
+impl AsyncBridgeDevice {
+    pub fn new<C: AsyncCommandChannel + 'static>(channel: C) -> Self {
+        Self {
+            channel: Arc::new(channel),
+            log_forwarder: Mutex::new(None),
+        }
+    }
+
+    /// Opens the device connected to the serial port, must be called within a tokio runtime
+    pub fn open(path: &str) -> io::Result<Self> {
+        let port = AsyncCobsChannel::open(path)?;
+        Ok(Self::new(AsyncDeviceChannel::new(FragmentedChannel::new(port))))
+    }
+
+    pub async fn gpio(&self) -> HalResult<AsyncGpioPeripheral> {
+        let ep_channel = AsyncEndpointChannel::new(self.channel.clone(), GPIO_ENDPOINT);
+        AsyncGpioPeripheral::probe(ep_channel).await
+    }
+
+    /// Returns the dispatcher of notifications sent by the device
+    pub fn notifications(&self) -> NotificationDispatcher {
+        self.channel.notifications()
+    }
+
+    /// Returns the endpoint owning each pin, in pin enumeration order
+    pub async fn pin_owners(&self) -> HalResult<Vec<Option<u8>>> {
+        let command = serialize_vec(&SystemCommand::GetPinOwners);
+        let response = self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        deserialize_list(&response)
+    }
+
+    /// Returns the message of the panic that caused the last device reset
+    ///
+    /// The device forgets the message, it's returned only once.
+    pub async fn take_last_panic(&self) -> HalResult<Option<String>> {
+        let command = serialize_vec(&SystemCommand::TakeLastPanic);
+        let response = self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        deserialize_panic_message(response)
+    }
+
+    /// Returns the report of the panic or hard fault that caused the last device reset
+    ///
+    /// The device forgets it like `take_last_panic`.
+    pub async fn take_last_crash(&self) -> HalResult<Option<CrashReport>> {
+        let command = serialize_vec(&SystemCommand::GetLastFault);
+        let response = self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        let fault = deserialize_response(&response)?;
+        Ok(self.take_last_panic().await?.map(|message| CrashReport {
+            message,
+            fault,
+        }))
+    }
+
+    /// Resets the keep-alive timer of the device
+    ///
+    /// Any command does, this is for hosts that have nothing else to send.
+    pub async fn keep_alive(&self) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::KeepAlive);
+        self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        Ok(())
+    }
+
+    /// Sets how long the device waits for a command before reverting the pins to their safe
+    /// state, `None` disables the timeout
+    ///
+    /// The timeout is disabled once it expires. It's limited to 65535 ms.
+    pub async fn set_keep_alive_timeout(&self, timeout: Option<Duration>) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::SetKeepAliveTimeout(keep_alive_millis(timeout)));
+        self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        Ok(())
+    }
+
+    /// Sets the state the pin reverts to when the host is gone
+    ///
+    /// The pin is identified by its index in pin enumeration order.
+    pub async fn set_safe_state(&self, pin: u8, state: SafePinState) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::SetSafeState(pin, state));
+        self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        Ok(())
+    }
+
+    /// Forwards the device log records up to `level` to the `log` facade of the host
+    ///
+    /// See `BridgeDevice::forward_log`.
+    pub async fn forward_log(&self, level: log::LevelFilter) -> HalResult<()> {
+        let level = log_level(level);
+        LogForwarder::update(&mut self.log_forwarder.lock().unwrap(), level, &self.notifications());
+        let command = serialize_vec(&SystemCommand::SetLogLevel(level));
+        self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        Ok(())
+    }
+}
+
+/// Host side of an open stream
+///
+/// Returns credits to the device as the data is consumed. The stream is closed by `close`,
+/// or in a spawned task if it's dropped within a tokio runtime.
+pub struct AsyncDataStream {
+    channel: Arc<dyn AsyncCommandChannel>,
+    window: StreamWindow,
+    closed: bool,
+}
+
+impl AsyncDataStream {
+    pub(crate) fn new(channel: Arc<dyn AsyncCommandChannel>, stream: u8, window: u16) -> Self {
+        Self {
+            channel,
+            window: StreamWindow::new(stream, window),
+            closed: false,
+        }
+    }
+
+    /// Returns the next chunk of stream data
+    pub async fn read(&mut self) -> HalResult<Vec<u8>> {
+        let packet = self.channel.read_stream(self.window.stream).await?;
+        if let Some(command) = self.window.receive(packet.sequence) {
+            self.channel.command(SYSTEM_ENDPOINT, &command).await?;
+        }
+        Ok(packet.data)
+    }
+
+    /// Returns the number of data messages lost so far
+    pub fn lost_messages(&self) -> usize {
+        self.window.lost_messages()
+    }
+
+    pub async fn close(mut self) -> HalResult<()> {
+        self.closed = true;
+        self.channel.command(SYSTEM_ENDPOINT, &self.window.close_command()).await?;
+        Ok(())
+    }
+}
+
+impl Drop for AsyncDataStream {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let channel = self.channel.clone();
+            let command = self.window.close_command();
+            handle.spawn(async move {
+                channel.command(SYSTEM_ENDPOINT, &command).await.ok();
+            });
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Async digital I/O traits, modeled after `embedded_hal::digital::v2`
+//!
+//! Every access is a round trip to the device, so the methods are async.
+
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait OutputPin {
+    type Error;
+
+    async fn set_low(&mut self) -> Result<(), Self::Error>;
+
+    async fn set_high(&mut self) -> Result<(), Self::Error>;
+}
+
+#[async_trait]
+pub trait InputPin {
+    type Error;
+
+    async fn is_high(&self) -> Result<bool, Self::Error>;
+
+    async fn is_low(&self) -> Result<bool, Self::Error>;
+}
@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use deadbug_common::hal::{HalError, HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::GpioPinMode;
+use deadbug_common::protocol::async_channels::AsyncEndpointChannel;
+use deadbug_common::protocol::serialize_vec;
+use deadbug_common::protocol::gpio::{GpioCommand, GpioPinInformation};
+use serde::de::DeserializeOwned;
+use crate::asynchronous::bridge::AsyncDataStream;
+use crate::asynchronous::digital;
+use crate::bridge::{deserialize_list, deserialize_response};
+use crate::gpio::{EnumeratedPin, PinMap};
+
+struct AsyncGpioBridge {
+    channel: AsyncEndpointChannel,
+}
+
+impl AsyncGpioBridge {
+    async fn enumerate(&self) -> HalResult<Vec<GpioPinInformation>> {
+        let command = GpioCommand::EnumeratePins;
+        let response = self.channel.command(&serialize_vec(&command)).await?;
+        deserialize_list(&response)
+    }
+
+    async fn simple_command<R: DeserializeOwned>(&self, command: GpioCommand) -> HalResult<R> {
+        let response = self.channel.command(&serialize_vec(&command)).await?;
+        deserialize_response(&response)
+    }
+
+    async fn get_pin_mode(&self, index: u8) -> HalResult<GpioPinMode> {
+        self.simple_command(GpioCommand::GetPinMode(index)).await
+    }
+
+    async fn set_pin_mode(&self, index: u8, mode: GpioPinMode) -> HalResult<()> {
+        self.simple_command(GpioCommand::SetPinMode(index, mode)).await
+    }
+
+    async fn get_pin_value(&self, index: u8) -> HalResult<bool> {
+        self.simple_command(GpioCommand::GetPinValue(index)).await
+    }
+
+    async fn set_pin_value(&self, index: u8, value: bool) -> HalResult<()> {
+        self.simple_command(GpioCommand::SetPinValue(index, value)).await
+    }
+
+    async fn release_pin(&self, index: u8) -> HalResult<()> {
+        self.simple_command(GpioCommand::ReleasePin(index)).await
+    }
+
+    async fn start_capture(&self, credits: u16) -> HalResult<u8> {
+        self.simple_command(GpioCommand::StartCapture(credits)).await
+    }
+
+    async fn watch_pin(&self, index: u8, enable: bool) -> HalResult<()> {
+        self.simple_command(GpioCommand::WatchPin(index, enable)).await
+    }
+}
+
+pub struct AsyncGpioPeripheral {
+    bridge: Arc<AsyncGpioBridge>,
+    pins: PinMap<AsyncGpioPin>,
+}
+
+impl AsyncGpioPeripheral {
+    pub(crate) async fn probe(channel: AsyncEndpointChannel) -> HalResult<Self> {
+        let bridge = Arc::new(AsyncGpioBridge {
+            channel,
+        });
+        let pin_info = bridge.enumerate().await?;
+
+        let pins = pin_info.iter().enumerate().map(|(i, info)| {
+            AsyncGpioPin {
+                bridge: bridge.clone(),
+                index: i as u8,
+                info: *info,
+            }
+        });
+
+        Ok(Self {
+            pins: PinMap::new(pins),
+            bridge,
+        })
+    }
+
+    /// Starts sampling levels of all pins, see `GpioPeripheral::start_capture`
+    pub async fn start_capture(&self, window: u16) -> HalResult<AsyncDataStream> {
+        let stream = self.bridge.start_capture(window).await?;
+        Ok(AsyncDataStream::new(self.bridge.channel.command_channel().clone(), stream, window))
+    }
+
+    /// Takes the pin by its port letter and number, e.g. `pin('E', 9)` for PE9
+    pub fn pin(&mut self, port: char, number: u8) -> HalResult<AsyncGpioPin> {
+        self.pins.take(port, number)
+    }
+
+    /// Takes the pin by its label (e.g. "LD3") or by its port name (e.g. "PE9")
+    pub fn pin_by_label(&mut self, label: &str) -> HalResult<AsyncGpioPin> {
+        self.pins.take_by_label(label)
+    }
+
+    pub fn all_pins(&mut self) -> Vec<AsyncGpioPin> {
+        self.pins.take_all()
+    }
+}
+
+pub struct AsyncGpioPin {
+    bridge: Arc<AsyncGpioBridge>,
+    index: u8,
+    info: GpioPinInformation,
+}
+
+impl AsyncGpioPin {
+    pub fn information(&self) -> &GpioPinInformation {
+        &self.info
+    }
+
+    pub async fn mode(&self) -> HalResult<GpioPinMode> {
+        self.bridge.get_pin_mode(self.index).await
+    }
+
+    pub async fn into_output(&self) -> HalResult<()> {
+        self.set_mode(GpioPinMode::PushPullOutput).await
+    }
+
+    pub async fn set_mode(&self, mode: GpioPinMode) -> HalResult<()> {
+        if !self.info.supports(mode) {
+            return Err(HalErrorKind::InvalidGpioMode.into());
+        }
+        self.bridge.set_pin_mode(self.index, mode).await
+    }
+
+    /// Enables or disables `NotificationKind::PinEdge` notifications for this input pin
+    pub async fn watch(&self, enable: bool) -> HalResult<()> {
+        self.bridge.watch_pin(self.index, enable).await
+    }
+
+    /// Returns the index used to identify the pin in notifications
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Reverts the pin to floating input and releases it for other endpoints
+    pub async fn release(&self) -> HalResult<()> {
+        self.bridge.release_pin(self.index).await
+    }
+}
+
+impl EnumeratedPin for AsyncGpioPin {
+    fn index(&self) -> u8 {
+        self.index
+    }
+
+    fn information(&self) -> &GpioPinInformation {
+        &self.info
+    }
+}
+
+#[async_trait]
+impl digital::OutputPin for AsyncGpioPin {
+    type Error = HalError;
+
+    async fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.bridge.set_pin_value(self.index, false).await
+    }
+
+    async fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.bridge.set_pin_value(self.index, true).await
+    }
+}
+
+#[async_trait]
+impl digital::InputPin for AsyncGpioPin {
+    type Error = HalError;
+
+    async fn is_high(&self) -> Result<bool, Self::Error> {
+        self.bridge.get_pin_value(self.index).await
+    }
+
+    async fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().await.map(|high| !high)
+    }
+}
@@ -0,0 +1,9 @@
+//! Async host API on tokio
+//!
+//! Mirrors the blocking API, but commands issued by different tasks are in flight at the same
+//! time, so many bridges and peripherals can be driven from one runtime.
+
+pub mod bridge;
+pub mod digital;
+pub mod gpio;
+pub mod serial;
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{Serial, SerialPortSettings};
+use deadbug_common::protocol::async_channels::AsyncPacketChannel;
+use crate::serial::{CobsDecoder, encode_packet};
+
+/// COBS-framed packet channel on top of an async byte stream
+pub struct AsyncCobsChannel<T> {
+    inner: T,
+    decoder: CobsDecoder,
+}
+
+impl<T> AsyncCobsChannel<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            decoder: CobsDecoder::new(),
+        }
+    }
+}
+
+impl AsyncCobsChannel<Serial> {
+    /// Opens the serial port, must be called within a tokio runtime
+    pub fn open(path: &str) -> io::Result<Self> {
+        let port = Serial::from_path(path, &SerialPortSettings::default())?;
+        Ok(Self::new(port))
+    }
+}
+
+#[async_trait]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncPacketChannel for AsyncCobsChannel<T> {
+    async fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = [0; 128];
+        loop {
+            if let Some(packet) = self.decoder.next_packet() {
+                return packet;
+            }
+            let size = self.inner.read(&mut buf).await?;
+            if size == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.decoder.push(&buf[..size]);
+        }
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&encode_packet(data)).await?;
+        self.inner.flush().await
+    }
+}
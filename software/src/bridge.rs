@@ -0,0 +1,317 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use deadbug_common::hal::{HalError, HalResult, HalErrorKind};
+use deadbug_common::protocol::channels::{CommandChannel, SharedCommandChannel, SharedEndpointChannel};
+use deadbug_common::protocol::pipeline::PipelinedChannel;
+use deadbug_common::protocol::serialize_vec;
+use deadbug_common::protocol::notification::{LogLevel, LogRecord, NotificationDispatcher, NotificationKind};
+use deadbug_common::protocol::gpio::GPIO_ENDPOINT;
+use deadbug_common::protocol::system::{SafePinState, SystemCommand, SYSTEM_ENDPOINT};
+use serde::de::DeserializeOwned;
+use crate::crash_report::CrashReport;
+use crate::gpio::GpioPeripheral;
+
+pub struct BridgeDevice {
+    channel: SharedCommandChannel,
+    log_forwarder: RefCell<Option<LogForwarder>>,
+}
+
+impl BridgeDevice {
+    pub fn new(channel: Box<dyn CommandChannel>) -> Self {
+        Self {
+            channel: SharedCommandChannel::new(channel),
+            log_forwarder: RefCell::new(None),
+        }
+    }
+
+    /// Creates a device which lets commands from different peripherals be in flight at once
+    pub fn pipelined(channel: PipelinedChannel) -> Self {
+        Self {
+            channel: SharedCommandChannel::pipelined(channel),
+            log_forwarder: RefCell::new(None),
+        }
+    }
+
+    pub fn gpio(&self) -> HalResult<GpioPeripheral> {
+        let ep_channel = SharedEndpointChannel::new(self.channel.clone(), GPIO_ENDPOINT);
+        GpioPeripheral::probe(ep_channel)
+    }
+
+    /// Returns the dispatcher of notifications sent by the device
+    ///
+    /// Notifications are delivered while the channel is read, `poll` reads the channel
+    /// when there are no commands to send.
+    pub fn notifications(&self) -> NotificationDispatcher {
+        self.channel.notifications()
+    }
+
+    pub fn poll(&self) -> HalResult<()> {
+        (&self.channel).poll()
+    }
+
+    /// Returns the endpoint owning each pin, in pin enumeration order
+    pub fn pin_owners(&self) -> HalResult<Vec<Option<u8>>> {
+        let command = serialize_vec(&SystemCommand::GetPinOwners);
+        let response = (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        deserialize_list(&response)
+    }
+
+    /// Returns the message of the panic that caused the last device reset
+    ///
+    /// The device forgets the message, it's returned only once.
+    pub fn take_last_panic(&self) -> HalResult<Option<String>> {
+        let command = serialize_vec(&SystemCommand::TakeLastPanic);
+        let response = (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        deserialize_panic_message(response)
+    }
+
+    /// Returns the report of the panic or hard fault that caused the last device reset
+    ///
+    /// The device forgets it like `take_last_panic`.
+    pub fn take_last_crash(&self) -> HalResult<Option<CrashReport>> {
+        let command = serialize_vec(&SystemCommand::GetLastFault);
+        let response = (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        let fault = deserialize_response(&response)?;
+        Ok(self.take_last_panic()?.map(|message| CrashReport {
+            message,
+            fault,
+        }))
+    }
+
+    /// Resets the keep-alive timer of the device
+    ///
+    /// Any command does, this is for hosts that have nothing else to send.
+    pub fn keep_alive(&self) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::KeepAlive);
+        (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        Ok(())
+    }
+
+    /// Sets how long the device waits for a command before reverting the pins to their safe
+    /// state, `None` disables the timeout
+    ///
+    /// The timeout is disabled once it expires. It's limited to 65535 ms.
+    pub fn set_keep_alive_timeout(&self, timeout: Option<Duration>) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::SetKeepAliveTimeout(keep_alive_millis(timeout)));
+        (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        Ok(())
+    }
+
+    /// Sets the state the pin reverts to when the host is gone
+    ///
+    /// The pin is identified by its index in pin enumeration order.
+    pub fn set_safe_state(&self, pin: u8, state: SafePinState) -> HalResult<()> {
+        let command = serialize_vec(&SystemCommand::SetSafeState(pin, state));
+        (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        Ok(())
+    }
+
+    /// Forwards the device log records up to `level` to the `log` facade of the host
+    ///
+    /// The records are logged with the target prefixed by `device::`, like the notifications
+    /// they are delivered while the channel is read. `LevelFilter::Off` stops forwarding.
+    pub fn forward_log(&self, level: log::LevelFilter) -> HalResult<()> {
+        let level = log_level(level);
+        LogForwarder::update(&mut self.log_forwarder.borrow_mut(), level, &self.notifications());
+        let command = serialize_vec(&SystemCommand::SetLogLevel(level));
+        (&self.channel).command(SYSTEM_ENDPOINT, &command)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn log_level(level: log::LevelFilter) -> Option<LogLevel> {
+    level.to_level().map(LogLevel::from)
+}
+
+/// Logs the records received by the dispatcher on a background thread until it's dropped
+pub(crate) struct LogForwarder {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogForwarder {
+    /// How often the thread checks whether it should stop
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    fn spawn(notifications: &NotificationDispatcher) -> Self {
+        let receiver = notifications.subscribe(Some(NotificationKind::Log));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                let notification = match receiver.recv_timeout(Self::POLL_INTERVAL) {
+                    Ok(notification) => notification,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                match LogRecord::decode(&notification.payload) {
+                    Ok(record) => emit_log_record(&record),
+                    Err(e) => log::warn!("invalid device log record: {:?}", e),
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Starts the forwarder if the device logs at some level, stops it if `level` is `None`
+    ///
+    /// A running forwarder is kept, so every record is logged once.
+    pub(crate) fn update(forwarder: &mut Option<Self>, level: Option<LogLevel>, notifications: &NotificationDispatcher) {
+        if level.is_none() {
+            *forwarder = None;
+        } else if forwarder.is_none() {
+            *forwarder = Some(Self::spawn(notifications));
+        }
+    }
+}
+
+impl Drop for LogForwarder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn emit_log_record(record: &LogRecord) {
+    let level = log::Level::from(record.level);
+    if level > log::max_level() {
+        return;
+    }
+    let target = format!("device::{}", record.target);
+    log::logger().log(&log::Record::builder()
+        .level(level)
+        .target(&target)
+        .args(format_args!("[{}.{:03}] {}", record.timestamp / 1000, record.timestamp % 1000, record.message))
+        .build());
+}
+
+/// Deserializes the response to `SystemCommand::TakeLastPanic`
+pub(crate) fn deserialize_panic_message(response: Vec<u8>) -> HalResult<Option<String>> {
+    if response.is_empty() {
+        return Ok(None);
+    }
+    String::from_utf8(response).map(Some).map_err(|_| HalErrorKind::ProtocolError.into())
+}
+
+/// Converts the keep-alive timeout to milliseconds, 0 if it's disabled
+pub(crate) fn keep_alive_millis(timeout: Option<Duration>) -> u16 {
+    // Round up, a zero timeout would disable supervision
+    timeout.map_or(0, |timeout| timeout.as_millis().clamp(1, u128::from(u16::MAX)) as u16)
+}
+
+/// Deserializes a list of items prefixed with the item count
+pub(crate) fn deserialize_list<T: DeserializeOwned>(response: &[u8]) -> HalResult<Vec<T>> {
+    if response.is_empty() {
+        return Err(HalErrorKind::ProtocolError.into());
+    }
+    let n = response[0] as usize;
+    let mut result = Vec::new();
+    let mut offset = 1;
+    for _ in 0..n {
+        let (item, size) = ssmarshal::deserialize(&response[offset..]).map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
+        result.push(item);
+        offset += size;
+    }
+    if offset != response.len() {
+        return Err(HalErrorKind::ProtocolError.into());
+    }
+    Ok(result)
+}
+
+/// Deserializes a single fixed-size response
+pub(crate) fn deserialize_response<T: DeserializeOwned>(response: &[u8]) -> HalResult<T> {
+    ssmarshal::deserialize(response)
+        .map(|(value, _)| value)
+        .map_err(|_| HalErrorKind::ProtocolError.into())
+}
+
+/// Flow control state of an open stream on the host side
+pub(crate) struct StreamWindow {
+    pub stream: u8,
+    window: u16,
+    consumed: u16,
+    next_sequence: u16,
+    lost_messages: usize,
+}
+
+impl StreamWindow {
+    pub fn new(stream: u8, window: u16) -> Self {
+        Self {
+            stream,
+            window,
+            consumed: 0,
+            next_sequence: 0,
+            lost_messages: 0,
+        }
+    }
+
+    /// Accounts for a received data message, returns the command returning credits to the device
+    /// when it's time to send it
+    pub fn receive(&mut self, sequence: u16) -> Option<Vec<u8>> {
+        self.lost_messages += sequence.wrapping_sub(self.next_sequence) as usize;
+        self.next_sequence = sequence.wrapping_add(1);
+
+        self.consumed += 1;
+        if self.consumed >= self.window.div_ceil(2) {
+            let command = serialize_vec(&SystemCommand::GrantCredits(self.stream, self.consumed));
+            self.consumed = 0;
+            Some(command)
+        } else {
+            None
+        }
+    }
+
+    pub fn lost_messages(&self) -> usize {
+        self.lost_messages
+    }
+
+    pub fn close_command(&self) -> Vec<u8> {
+        serialize_vec(&SystemCommand::CloseStream(self.stream))
+    }
+}
+
+/// Host side of an open stream
+///
+/// Returns credits to the device as the data is consumed and closes the stream on drop.
+pub struct DataStream {
+    channel: SharedCommandChannel,
+    window: StreamWindow,
+}
+
+impl DataStream {
+    pub(crate) fn new(channel: SharedCommandChannel, stream: u8, window: u16) -> Self {
+        Self {
+            channel,
+            window: StreamWindow::new(stream, window),
+        }
+    }
+
+    /// Returns the next chunk of stream data, blocking until it's available
+    pub fn read(&mut self) -> HalResult<Vec<u8>> {
+        let packet = self.channel.read_stream(self.window.stream)?;
+        if let Some(command) = self.window.receive(packet.sequence) {
+            self.channel.command(SYSTEM_ENDPOINT, &command)?;
+        }
+        Ok(packet.data)
+    }
+
+    /// Returns the number of data messages lost so far
+    pub fn lost_messages(&self) -> usize {
+        self.window.lost_messages()
+    }
+}
+
+impl Drop for DataStream {
+    fn drop(&mut self) {
+        self.channel.command(SYSTEM_ENDPOINT, &self.window.close_command()).ok();
+    }
+}
@@ -0,0 +1,175 @@
+//! Operations of the command line tool
+//!
+//! Each returns a result which prints as text with `Display` and as JSON with `Serialize`.
+
+use std::fmt;
+use deadbug_common::hal::{HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::GpioPinMode;
+use embedded_hal::digital::v2::OutputPin;
+use serde::Serialize;
+use crate::bridge::BridgeDevice;
+use crate::gpio::GpioPin;
+
+/// Parses "input", "output" or "afN" for alternate function N
+pub fn parse_mode(mode: &str) -> Option<GpioPinMode> {
+    match mode.to_ascii_lowercase().as_str() {
+        "input" => Some(GpioPinMode::FloatingInput),
+        "output" => Some(GpioPinMode::PushPullOutput),
+        mode => mode.strip_prefix("af")?.parse().ok().map(GpioPinMode::Alternate),
+    }
+}
+
+/// Name of the mode as accepted by `parse_mode`
+pub fn mode_name(mode: GpioPinMode) -> String {
+    match mode {
+        GpioPinMode::FloatingInput => "input".to_string(),
+        GpioPinMode::PushPullOutput => "output".to_string(),
+        GpioPinMode::Alternate(af) => format!("af{}", af),
+    }
+}
+
+/// Parses "high", "low", "1" or "0"
+pub fn parse_level(level: &str) -> Option<bool> {
+    match level.to_ascii_lowercase().as_str() {
+        "high" | "1" => Some(true),
+        "low" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn level_name(level: bool) -> &'static str {
+    if level { "high" } else { "low" }
+}
+
+fn pin_name(pin: &GpioPin) -> String {
+    format!("P{}{}", pin.information().port(), pin.information().index_minor)
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PinInfo {
+    pub index: u8,
+    /// Port name, e.g. "PE9"
+    pub name: String,
+    pub label: String,
+    pub mode: String,
+    /// Endpoint which claimed the pin
+    pub owner: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DeviceInfo {
+    pub pins: Vec<PinInfo>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>5}  {:<5} {:<8} {:<7} owner", "index", "pin", "label", "mode")?;
+        for pin in &self.pins {
+            write!(f, "{:>5}  {:<5} {:<8} {:<7} ", pin.index, pin.name, pin.label, pin.mode)?;
+            match pin.owner {
+                Some(owner) => writeln!(f, "{}", owner)?,
+                None => writeln!(f, "-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the pins of the device with their modes and owners
+pub fn info(bridge: &BridgeDevice) -> HalResult<DeviceInfo> {
+    let owners = bridge.pin_owners()?;
+    let pins = bridge.gpio()?.all_pins().iter().map(|pin| Ok(PinInfo {
+        index: pin.index(),
+        name: pin_name(pin),
+        label: pin.information().label.as_str().to_string(),
+        mode: mode_name(pin.mode()?),
+        owner: owners.get(pin.index() as usize).copied().flatten(),
+    })).collect::<HalResult<_>>()?;
+    Ok(DeviceInfo {
+        pins,
+    })
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PinLevel {
+    pub pin: String,
+    pub level: bool,
+}
+
+impl fmt::Display for PinLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.pin, level_name(self.level))
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PinMode {
+    pub pin: String,
+    pub mode: String,
+}
+
+impl fmt::Display for PinMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.pin, self.mode)
+    }
+}
+
+/// Takes the pin by its label or port name
+fn pin(bridge: &BridgeDevice, pin: &str) -> HalResult<GpioPin> {
+    bridge.gpio()?.pin_by_label(pin)
+}
+
+/// Reads the level of the pin
+pub fn gpio_get(bridge: &BridgeDevice, name: &str) -> HalResult<PinLevel> {
+    let pin = pin(bridge, name)?;
+    Ok(PinLevel {
+        pin: pin_name(&pin),
+        level: pin.value()?,
+    })
+}
+
+/// Drives the pin, switching it to an output first
+///
+/// The device reverts the pin to its safe state when it considers the host gone: with the CDC
+/// firmware when the tool closes the port, and with any firmware when an enabled keep-alive
+/// timeout expires.
+pub fn gpio_set(bridge: &BridgeDevice, name: &str, level: bool) -> HalResult<PinLevel> {
+    let mut pin = pin(bridge, name)?;
+    if pin.mode()? != GpioPinMode::PushPullOutput {
+        pin.into_output()?;
+    }
+    if level {
+        pin.set_high()?;
+    } else {
+        pin.set_low()?;
+    }
+    Ok(PinLevel {
+        pin: pin_name(&pin),
+        level,
+    })
+}
+
+/// Returns the mode of the pin, switching it to `mode` first if given
+pub fn gpio_mode(bridge: &BridgeDevice, name: &str, mode: Option<GpioPinMode>) -> HalResult<PinMode> {
+    let pin = pin(bridge, name)?;
+    if let Some(mode) = mode {
+        pin.set_mode(mode)?;
+    }
+    Ok(PinMode {
+        pin: pin_name(&pin),
+        mode: mode_name(pin.mode()?),
+    })
+}
+
+/// Short description of the error for the command line
+pub fn error_message(kind: HalErrorKind) -> String {
+    match kind {
+        HalErrorKind::UnsupportedCommand => "the firmware doesn't support the command".to_string(),
+        HalErrorKind::InvalidParameter => "invalid parameter, e.g. an unknown pin".to_string(),
+        HalErrorKind::ProtocolError => "communication with the device failed".to_string(),
+        HalErrorKind::InvalidGpioMode => "the pin doesn't support the mode".to_string(),
+        HalErrorKind::PinBusy => "the pin is used by another peripheral".to_string(),
+        HalErrorKind::NoResources => "the device is out of resources".to_string(),
+        HalErrorKind::Other(code) => format!("device error {}", code),
+    }
+}
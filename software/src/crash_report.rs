@@ -0,0 +1,128 @@
+//! Reports of the panic or hard fault that caused the last device reset
+//!
+//! The addresses of a hard fault are looked up in the symbol table of the firmware ELF file, the
+//! same one the binary log is decoded with.
+
+use std::fmt::{self, Write};
+use deadbug_common::protocol::system::FaultRegisters;
+use object::{Object, ObjectSymbol, SymbolKind};
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CrashReport {
+    pub message: String,
+    /// Registers saved by the hard fault handler, `None` after a panic
+    pub fault: Option<FaultRegisters>,
+}
+
+impl CrashReport {
+    /// Formats the report, one line per register, with the functions the addresses are in if
+    /// `symbols` is given
+    pub fn describe(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut report = self.message.clone();
+        let fault = match &self.fault {
+            Some(fault) => fault,
+            None => return report,
+        };
+        let location = |address| symbols.and_then(|symbols| symbols.lookup(address));
+        for (name, value) in [("pc", fault.pc), ("lr", fault.lr)] {
+            write!(report, "\n  {:<5} {:#010x}", name, value).ok();
+            if let Some(location) = location(value) {
+                write!(report, " {}", location).ok();
+            }
+        }
+        write!(report, "\n  {:<5} {:#010x}", "xpsr", fault.xpsr).ok();
+        let causes: Vec<_> = fault.causes().collect();
+        write!(report, "\n  {:<5} {:#010x}", "cfsr", fault.cfsr).ok();
+        write!(report, "\n  {:<5} {:#010x}", "hfsr", fault.hfsr).ok();
+        if !causes.is_empty() {
+            write!(report, "\n  cause: {}", causes.join(", ")).ok();
+        }
+        if let Some(address) = fault.fault_address() {
+            write!(report, "\n  fault address: {:#010x}", address).ok();
+        }
+        for (name, value) in [("r0", fault.r0), ("r1", fault.r1), ("r2", fault.r2), ("r3", fault.r3), ("r12", fault.r12)] {
+            write!(report, "\n  {:<5} {:#010x}", name, value).ok();
+        }
+        report
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+    InvalidElf,
+    /// The ELF file is stripped
+    NoSymbols,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::InvalidElf => write!(f, "invalid ELF file"),
+            SymbolError::NoSymbols => write!(f, "no function symbols in the ELF file"),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+struct Function {
+    address: u32,
+    size: u32,
+    name: String,
+}
+
+/// Function symbols of the firmware
+pub struct SymbolTable {
+    /// Sorted by address
+    functions: Vec<Function>,
+}
+
+impl SymbolTable {
+    pub fn from_elf(elf: &[u8]) -> Result<Self, SymbolError> {
+        let file = object::File::parse(elf).map_err(|_| SymbolError::InvalidElf)?;
+        let mut functions: Vec<_> = file.symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() != 0)
+            .filter_map(|symbol| Some(Function {
+                // Thumb functions have the lowest bit set
+                address: symbol.address() as u32 & !1,
+                size: symbol.size() as u32,
+                name: format!("{:#}", rustc_demangle::demangle(symbol.name().ok()?)),
+            }))
+            .collect();
+        if functions.is_empty() {
+            return Err(SymbolError::NoSymbols);
+        }
+        functions.sort_by_key(|function| function.address);
+        Ok(Self {
+            functions,
+        })
+    }
+
+    /// Returns the function containing the address
+    pub fn lookup(&self, address: u32) -> Option<Location<'_>> {
+        // Return addresses have the Thumb bit set
+        let address = address & !1;
+        let index = self.functions.partition_point(|function| function.address <= address);
+        let function = &self.functions[index.checked_sub(1)?];
+        if address - function.address >= function.size {
+            return None;
+        }
+        Some(Location {
+            function: &function.name,
+            offset: address - function.address,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Location<'a> {
+    pub function: &'a str,
+    pub offset: u32,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}+{:#x}>", self.function, self.offset)
+    }
+}
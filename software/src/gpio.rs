@@ -0,0 +1,233 @@
+use deadbug_common::hal::{HalError, HalResult, HalErrorKind};
+use deadbug_common::hal::gpio::GpioPinMode;
+use deadbug_common::protocol::channels::SharedEndpointChannel;
+use deadbug_common::protocol::serialize_vec;
+use deadbug_common::protocol::gpio::{GpioCommand, GpioPinInformation};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::rc::Rc;
+use embedded_hal::digital;
+use crate::bridge::{DataStream, deserialize_list, deserialize_response};
+
+struct GpioBridge {
+    channel: SharedEndpointChannel,
+}
+
+impl GpioBridge {
+    fn enumerate(&self) -> HalResult<Vec<GpioPinInformation>> {
+        let command = GpioCommand::EnumeratePins;
+        let response = self.channel.command(&serialize_vec(&command))?;
+        deserialize_list(&response)
+    }
+
+    fn simple_command<C: Serialize, R: DeserializeOwned>(&self, command: C) -> HalResult<R> {
+        let response = self.channel.command(&serialize_vec(&command))?;
+        deserialize_response(&response)
+    }
+
+    fn get_pin_mode(&self, index: u8) -> HalResult<GpioPinMode> {
+        self.simple_command(GpioCommand::GetPinMode(index))
+    }
+
+    fn set_pin_mode(&self, index: u8, mode: GpioPinMode) -> HalResult<()> {
+        self.simple_command(GpioCommand::SetPinMode(index, mode))
+    }
+
+    fn get_pin_value(&self, index: u8) -> HalResult<bool> {
+        self.simple_command(GpioCommand::GetPinValue(index))
+    }
+
+    fn set_pin_value(&self, index: u8, value: bool) -> HalResult<()> {
+        self.simple_command(GpioCommand::SetPinValue(index, value))
+    }
+
+    fn release_pin(&self, index: u8) -> HalResult<()> {
+        self.simple_command(GpioCommand::ReleasePin(index))
+    }
+
+    fn start_capture(&self, credits: u16) -> HalResult<u8> {
+        self.simple_command(GpioCommand::StartCapture(credits))
+    }
+
+    fn watch_pin(&self, index: u8, enable: bool) -> HalResult<()> {
+        self.simple_command(GpioCommand::WatchPin(index, enable))
+    }
+}
+
+/// Pin which can be looked up by its port name or label
+pub(crate) trait EnumeratedPin {
+    fn index(&self) -> u8;
+
+    fn information(&self) -> &GpioPinInformation;
+}
+
+/// Pins of a peripheral which weren't taken yet
+pub(crate) struct PinMap<P> {
+    pins: HashMap<(char, u8), P>,
+}
+
+impl<P: EnumeratedPin> PinMap<P> {
+    pub fn new(pins: impl IntoIterator<Item=P>) -> Self {
+        let pins = pins.into_iter().map(|pin| {
+            let info = pin.information();
+            ((info.port(), info.index_minor), pin)
+        }).collect();
+        Self {
+            pins,
+        }
+    }
+
+    pub fn take(&mut self, port: char, number: u8) -> HalResult<P> {
+        let port = port.to_ascii_uppercase();
+        self.pins.remove(&(port, number)).ok_or_else(|| HalError::from(HalErrorKind::InvalidParameter))
+    }
+
+    pub fn take_by_label(&mut self, label: &str) -> HalResult<P> {
+        let key = self.pins.iter()
+            .find(|(_, pin)| pin.information().label.as_str().eq_ignore_ascii_case(label))
+            .map(|(key, _)| *key)
+            .or_else(|| parse_port_name(label));
+        match key {
+            Some((port, number)) => self.take(port, number),
+            None => Err(HalErrorKind::InvalidParameter.into()),
+        }
+    }
+
+    pub fn take_all(&mut self) -> Vec<P> {
+        let mut pins: Vec<_> = self.pins.drain().map(|(_, pin)| pin).collect();
+        pins.sort_unstable_by_key(|pin| pin.index());
+        pins
+    }
+}
+
+/// Parses port names like "PE9" into ('E', 9)
+fn parse_port_name(name: &str) -> Option<(char, u8)> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(p), Some(port)) if p.eq_ignore_ascii_case(&'P') && port.is_ascii_alphabetic() => {
+            let number = chars.as_str().parse().ok()?;
+            Some((port.to_ascii_uppercase(), number))
+        },
+        _ => None,
+    }
+}
+
+pub struct GpioPeripheral {
+    bridge: Rc<GpioBridge>,
+    pins: PinMap<GpioPin>,
+}
+
+impl GpioPeripheral {
+    pub(crate) fn probe(channel: SharedEndpointChannel) -> HalResult<Self> {
+        let bridge = Rc::new(GpioBridge {
+            channel,
+        });
+        let pin_info = bridge.enumerate()?;
+
+        let pins = pin_info.iter().enumerate().map(|(i, info)| {
+            GpioPin {
+                bridge: bridge.clone(),
+                index: i as u8,
+                info: *info,
+            }
+        });
+
+        Ok(Self {
+            pins: PinMap::new(pins),
+            bridge,
+        })
+    }
+
+    /// Starts sampling levels of all pins
+    ///
+    /// Each sample takes `(pin count + 7) / 8` bytes, bit N of a sample is the level of pin N
+    /// in enumeration order. `window` is the number of data messages the device may send ahead.
+    pub fn start_capture(&self, window: u16) -> HalResult<DataStream> {
+        let stream = self.bridge.start_capture(window)?;
+        Ok(DataStream::new(self.bridge.channel.command_channel().clone(), stream, window))
+    }
+
+    /// Takes the pin by its port letter and number, e.g. `pin('E', 9)` for PE9
+    pub fn pin(&mut self, port: char, number: u8) -> HalResult<GpioPin> {
+        self.pins.take(port, number)
+    }
+
+    /// Takes the pin by its label (e.g. "LD3") or by its port name (e.g. "PE9")
+    pub fn pin_by_label(&mut self, label: &str) -> HalResult<GpioPin> {
+        self.pins.take_by_label(label)
+    }
+
+    pub fn all_pins(&mut self) -> Vec<GpioPin> {
+        self.pins.take_all()
+    }
+}
+
+pub struct GpioPin {
+    bridge: Rc<GpioBridge>,
+    index: u8,
+    info: GpioPinInformation,
+}
+
+impl GpioPin {
+    pub fn information(&self) -> &GpioPinInformation {
+        &self.info
+    }
+
+    pub fn mode(&self) -> HalResult<GpioPinMode> {
+        self.bridge.get_pin_mode(self.index)
+    }
+
+    /// Returns the level of the pin
+    pub fn value(&self) -> HalResult<bool> {
+        self.bridge.get_pin_value(self.index)
+    }
+
+    pub fn into_output(&self) -> HalResult<()> {
+        self.set_mode(GpioPinMode::PushPullOutput)
+    }
+
+    pub fn set_mode(&self, mode: GpioPinMode) -> HalResult<()> {
+        if !self.info.supports(mode) {
+            return Err(HalErrorKind::InvalidGpioMode.into());
+        }
+        self.bridge.set_pin_mode(self.index, mode)
+    }
+
+    /// Enables or disables `NotificationKind::PinEdge` notifications for this input pin
+    pub fn watch(&self, enable: bool) -> HalResult<()> {
+        self.bridge.watch_pin(self.index, enable)
+    }
+
+    /// Returns the index used to identify the pin in notifications
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Reverts the pin to floating input and releases it for other endpoints
+    pub fn release(&self) -> HalResult<()> {
+        self.bridge.release_pin(self.index)
+    }
+}
+
+impl EnumeratedPin for GpioPin {
+    fn index(&self) -> u8 {
+        self.index
+    }
+
+    fn information(&self) -> &GpioPinInformation {
+        &self.info
+    }
+}
+
+impl digital::v2::OutputPin for GpioPin {
+    type Error = HalError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.bridge.set_pin_value(self.index, false)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.bridge.set_pin_value(self.index, true)
+    }
+}
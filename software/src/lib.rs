@@ -0,0 +1,14 @@
+//! Host side of the deadbug bridge
+
+pub mod bridge;
+pub mod commands;
+pub mod crash_report;
+pub mod gpio;
+pub mod log_decoder;
+pub mod serial;
+pub mod shell;
+#[cfg(feature = "usb")]
+pub mod usb;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
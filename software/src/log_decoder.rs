@@ -0,0 +1,421 @@
+//! Decoder of the binary log of `stm32-log`
+//!
+//! The firmware sends the id of the format string and the raw arguments, the format strings are
+//! read from the `.stm32_log` section of its ELF file. The frame format is described in
+//! `stm32_log::binary`.
+
+use std::fmt;
+use std::mem;
+use object::{Object, ObjectSection};
+
+const TEXT_RECORD: u16 = 0xffff;
+const DROPPED_RECORD: u16 = 0xfffe;
+
+const TAG_UNSIGNED: u8 = 0;
+const TAG_SIGNED: u8 = 1;
+const TAG_STR: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_F32: u8 = 4;
+const TAG_CHAR: u8 = 5;
+
+const SECTION_NAME: &str = ".stm32_log";
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The ELF file has no format strings, the firmware doesn't use the binary log
+    NoStringTable,
+    InvalidElf,
+    /// The frame ended in the middle of a field
+    Truncated,
+    InvalidCobs,
+    /// The id isn't in the string table, the ELF file doesn't match the firmware
+    UnknownString(u16),
+    InvalidArgument(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::NoStringTable => write!(f, "no {} section in the ELF file", SECTION_NAME),
+            DecodeError::InvalidElf => write!(f, "invalid ELF file"),
+            DecodeError::Truncated => write!(f, "truncated log frame"),
+            DecodeError::InvalidCobs => write!(f, "invalid COBS encoding of a log frame"),
+            DecodeError::UnknownString(id) => write!(f, "unknown format string {:#06x}, is the ELF file up to date?", id),
+            DecodeError::InvalidArgument(tag) => write!(f, "invalid argument type {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq)]
+pub struct DecodedRecord {
+    /// Milliseconds
+    pub timestamp: u32,
+    pub level: log::Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for DecodedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}.{:03}] {:<5} {}: {}",
+            self.timestamp / 1000, self.timestamp % 1000, self.level, self.target, self.message)
+    }
+}
+
+#[derive(Debug)]
+enum Value {
+    Unsigned(u64),
+    Signed(i64),
+    Str(String),
+    Bool(bool),
+    F32(f32),
+    Char(char),
+}
+
+/// Reads the fields of a frame
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < size {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let size = self.varint()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(size)?).into_owned())
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        let value = match self.u8()? {
+            TAG_UNSIGNED => Value::Unsigned(self.varint()?),
+            TAG_SIGNED => {
+                let value = self.varint()?;
+                Value::Signed((value >> 1) as i64 ^ -((value & 1) as i64))
+            },
+            TAG_STR => Value::Str(self.string()?),
+            TAG_BOOL => Value::Bool(self.u8()? != 0),
+            TAG_F32 => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(self.bytes(4)?);
+                Value::F32(f32::from_bits(u32::from_le_bytes(bytes)))
+            },
+            TAG_CHAR => Value::Char(std::char::from_u32(self.varint()? as u32).unwrap_or(char::REPLACEMENT_CHARACTER)),
+            tag => return Err(DecodeError::InvalidArgument(tag)),
+        };
+        Ok(value)
+    }
+}
+
+fn level_from_u8(level: u8) -> log::Level {
+    match level {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+/// Format specification after the colon, e.g. `#06x`
+#[derive(Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<char>,
+    plus: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: String,
+}
+
+impl Spec {
+    fn parse(s: &str) -> Self {
+        let mut spec = Spec::default();
+        let mut chars: Vec<char> = s.chars().collect();
+        if chars.len() >= 2 && "<^>".contains(chars[1]) {
+            spec.fill = Some(chars[0]);
+            spec.align = Some(chars[1]);
+            chars.drain(..2);
+        } else if !chars.is_empty() && "<^>".contains(chars[0]) {
+            spec.align = Some(chars[0]);
+            chars.remove(0);
+        }
+        let mut chars = chars.into_iter().peekable();
+        if chars.peek() == Some(&'+') {
+            spec.plus = true;
+            chars.next();
+        }
+        if chars.peek() == Some(&'#') {
+            spec.alternate = true;
+            chars.next();
+        }
+        if chars.peek() == Some(&'0') {
+            spec.zero = true;
+            chars.next();
+        }
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            spec.width = spec.width * 10 + digit as usize;
+            chars.next();
+        }
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut precision = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                precision = precision * 10 + digit as usize;
+                chars.next();
+            }
+            spec.precision = Some(precision);
+        }
+        spec.kind = chars.collect();
+        spec
+    }
+
+    /// Formats an integer given as its magnitude and sign
+    fn integer(&self, magnitude: u64, negative: bool) -> (String, String) {
+        let (digits, prefix) = match self.kind.as_str() {
+            "x" => (format!("{:x}", magnitude), "0x"),
+            "X" => (format!("{:X}", magnitude), "0x"),
+            "b" => (format!("{:b}", magnitude), "0b"),
+            "o" => (format!("{:o}", magnitude), "0o"),
+            _ => (magnitude.to_string(), ""),
+        };
+        let mut sign = String::new();
+        if negative {
+            sign.push('-');
+        } else if self.plus {
+            sign.push('+');
+        }
+        if self.alternate {
+            sign.push_str(prefix);
+        }
+        (sign, digits)
+    }
+
+    fn format(&self, value: &Value) -> String {
+        let debug = self.kind == "?";
+        let (sign, body, numeric) = match value {
+            Value::Unsigned(value) => {
+                let (sign, digits) = self.integer(*value, false);
+                (sign, digits, true)
+            },
+            Value::Signed(value) => {
+                let (sign, digits) = self.integer(value.unsigned_abs(), *value < 0);
+                (sign, digits, true)
+            },
+            Value::F32(value) => {
+                let text = match self.precision {
+                    Some(precision) => format!("{:.*}", precision, value.abs()),
+                    None if debug => format!("{:?}", value.abs()),
+                    None => value.abs().to_string(),
+                };
+                let sign = if value.is_sign_negative() { "-" } else if self.plus { "+" } else { "" };
+                (sign.to_string(), text, true)
+            },
+            Value::Str(s) => {
+                let s = match self.precision {
+                    Some(precision) => s.chars().take(precision).collect(),
+                    None => s.clone(),
+                };
+                (String::new(), if debug { format!("{:?}", s) } else { s }, false)
+            },
+            Value::Bool(value) => (String::new(), value.to_string(), false),
+            Value::Char(c) => (String::new(), if debug { format!("{:?}", c) } else { c.to_string() }, false),
+        };
+
+        let size = sign.chars().count() + body.chars().count();
+        if size >= self.width {
+            return sign + &body;
+        }
+        let padding = self.width - size;
+        if numeric && self.zero {
+            return sign + &"0".repeat(padding) + &body;
+        }
+        let fill = self.fill.unwrap_or(' ').to_string();
+        let align = self.align.unwrap_or(if numeric { '>' } else { '<' });
+        let (before, after) = match align {
+            '<' => (0, padding),
+            '^' => (padding / 2, padding - padding / 2),
+            _ => (padding, 0),
+        };
+        fill.repeat(before) + &sign + &body + &fill.repeat(after)
+    }
+}
+
+/// Replaces the placeholders of the format string with the arguments
+///
+/// Missing arguments are shown as `{?}`.
+fn format_message(format: &str, values: &[Value]) -> String {
+    let mut message = String::new();
+    let mut values = values.iter();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                message.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                message.push('}');
+            },
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let spec = match placeholder.find(':') {
+                    Some(index) => Spec::parse(&placeholder[index + 1..]),
+                    None => Spec::default(),
+                };
+                match values.next() {
+                    Some(value) => message.push_str(&spec.format(value)),
+                    None => message.push_str("{?}"),
+                }
+            },
+            c => message.push(c),
+        }
+    }
+    message
+}
+
+/// Format strings of a firmware build
+pub struct LogDecoder {
+    strings: Vec<u8>,
+}
+
+impl LogDecoder {
+    /// Creates a decoder from the contents of the `.stm32_log` section
+    pub fn new(strings: Vec<u8>) -> Self {
+        Self {
+            strings,
+        }
+    }
+
+    /// Creates a decoder from the ELF file of the firmware
+    pub fn from_elf(elf: &[u8]) -> Result<Self, DecodeError> {
+        let file = object::File::parse(elf).map_err(|_| DecodeError::InvalidElf)?;
+        let section = file.section_by_name(SECTION_NAME).ok_or(DecodeError::NoStringTable)?;
+        let strings = section.data().map_err(|_| DecodeError::InvalidElf)?;
+        Ok(Self::new(strings.to_vec()))
+    }
+
+    /// Returns the level, the target and the format string of the id
+    fn entry(&self, id: u16) -> Result<(log::Level, &str, &str), DecodeError> {
+        let entry = self.strings.get(id as usize..).ok_or(DecodeError::UnknownString(id))?;
+        let mut parts = entry.get(1..).ok_or(DecodeError::UnknownString(id))?.splitn(3, |&b| b == 0);
+        let target = parts.next().ok_or(DecodeError::UnknownString(id))?;
+        let format = parts.next().ok_or(DecodeError::UnknownString(id))?;
+        let target = std::str::from_utf8(target).map_err(|_| DecodeError::UnknownString(id))?;
+        let format = std::str::from_utf8(format).map_err(|_| DecodeError::UnknownString(id))?;
+        Ok((level_from_u8(entry[0]), target, format))
+    }
+
+    /// Decodes a frame without the COBS encoding
+    pub fn decode(&self, frame: &[u8]) -> Result<DecodedRecord, DecodeError> {
+        let mut reader = Reader { data: frame };
+        let mut id = [0; 2];
+        id.copy_from_slice(reader.bytes(2)?);
+        let id = u16::from_le_bytes(id);
+        let mut timestamp = [0; 4];
+        timestamp.copy_from_slice(reader.bytes(4)?);
+        let timestamp = u32::from_le_bytes(timestamp);
+
+        match id {
+            TEXT_RECORD => {
+                let level = level_from_u8(reader.u8()?);
+                let target = reader.string()?;
+                let message = reader.string()?;
+                Ok(DecodedRecord { timestamp, level, target, message })
+            },
+            DROPPED_RECORD => {
+                let count = match reader.value()? {
+                    Value::Unsigned(count) => count,
+                    _ => return Err(DecodeError::InvalidArgument(TAG_UNSIGNED)),
+                };
+                Ok(DecodedRecord {
+                    timestamp,
+                    level: log::Level::Warn,
+                    target: "stm32_log".to_string(),
+                    message: format!("{} log messages dropped", count),
+                })
+            },
+            id => {
+                let (level, target, format) = self.entry(id)?;
+                // Arguments which didn't fit into the frame are missing
+                let mut values = Vec::new();
+                while !reader.data.is_empty() {
+                    match reader.value() {
+                        Ok(value) => values.push(value),
+                        Err(DecodeError::Truncated) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(DecodedRecord {
+                    timestamp,
+                    level,
+                    target: target.to_string(),
+                    message: format_message(format, &values),
+                })
+            },
+        }
+    }
+}
+
+/// Splits the log output into frames and decodes them
+pub struct LogStream {
+    decoder: LogDecoder,
+    frame: Vec<u8>,
+}
+
+impl LogStream {
+    pub fn new(decoder: LogDecoder) -> Self {
+        Self {
+            decoder,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Decodes the frames completed by the data, which may end in the middle of a frame
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<DecodedRecord, DecodeError>> {
+        let mut records = Vec::new();
+        for &byte in data {
+            if byte != 0 {
+                self.frame.push(byte);
+                continue;
+            }
+            let mut frame = mem::take(&mut self.frame);
+            if frame.is_empty() {
+                continue;
+            }
+            let record = match cobs::decode_in_place(&mut frame) {
+                Ok(size) => self.decoder.decode(&frame[..size]),
+                Err(()) => Err(DecodeError::InvalidCobs),
+            };
+            records.push(record);
+        }
+        records
+    }
+}
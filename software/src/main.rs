@@ -1,219 +1,364 @@
-use serialport::{available_ports, SerialPortType, SerialPort};
-use rand::distributions::Alphanumeric;
-use rand::Rng;
+use serialport::SerialPort;
+use serde::Serialize;
+use structopt::StructOpt;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time::Duration;
-use std::{io, thread};
+use std::thread;
 use deadbug_common::hal::{HalError, HalResult, HalErrorKind};
-use deadbug_common::protocol::channels::{CommandChannel, PacketChannel, SharedCommandChannel, SharedEndpointChannel};
-use deadbug_common::protocol::gpio::{GpioCommand, GpioPinInformation};
 use deadbug_common::hal::gpio::GpioPinMode;
-use serde::Serialize;
-use serde::de::DeserializeOwned;
-use std::collections::HashMap;
-use std::sync::Arc;
-use embedded_hal::digital;
+use deadbug_common::protocol::channels::FragmentedChannel;
+use deadbug_common::protocol::pipeline::PipelinedChannel;
 use embedded_hal::digital::v2::OutputPin;
+use deadbug_cli::bridge::BridgeDevice;
+use deadbug_cli::commands::{self, parse_level, parse_mode};
+use deadbug_cli::crash_report::{CrashReport, SymbolTable};
+use deadbug_cli::serial::{find_device_ports, CobsSerialPort};
+use deadbug_cli::shell;
+#[cfg(feature = "usb")]
+use deadbug_cli::usb::{UsbConnection, UsbLogReader};
+#[cfg(feature = "usb")]
+use deadbug_cli::log_decoder::{LogDecoder, LogStream};
+
+/// The command failed on the device or the connection broke
+const EXIT_FAILURE: i32 = 1;
+/// Invalid arguments
+const EXIT_USAGE: i32 = 2;
+/// The device wasn't found
+const EXIT_NO_DEVICE: i32 = 3;
+
+/// Prefix of the USB devices in `--device`, followed by the location `list` prints
+const USB_PREFIX: &str = "usb:";
+
+#[derive(StructOpt)]
+#[structopt(name = "deadbug", about = "Drives the pins of a deadbug bridge")]
+struct Options {
+    /// Device as printed by `list`, "usb:BUS:ADDRESS" or a serial port [default: the first one found]
+    #[structopt(short, long, global = true)]
+    device: Option<String>,
+    /// Prints the results as JSON
+    #[structopt(long, global = true)]
+    json: bool,
+    /// Firmware ELF file, decodes the binary log and symbolicates hard faults
+    #[structopt(long, env = "DEADBUG_ELF", parse(from_os_str), global = true)]
+    elf: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Command,
+}
 
+#[derive(StructOpt)]
+enum Command {
+    /// Lists the connected devices
+    List,
+    /// Shows the pins with their modes and owning endpoints
+    Info,
+    Gpio(GpioCommand),
+    /// Shows the panic or hard fault that caused the last reset, the device forgets it
+    Crash,
+    /// Prints the firmware log received over USB until the device goes away
+    Log,
+    /// Chases a light over all pins until interrupted
+    LedTest,
+    /// Starts an interactive shell, with completion and the edges of watched pins
+    Shell,
+}
 
-fn find_device_port() -> Option<String> {
-    if let Ok(list) = available_ports() {
-        for info in list {
-            if let SerialPortType::UsbPort(usb_info) = info.port_type {
-                if usb_info.vid == 0x16c0 && usb_info.pid == 0x27dd {
-                    return Some(info.port_name);
-                }
-            }
-        }
-    }
-    None
+/// Reads and drives single pins, given by label (e.g. "LD3") or port name (e.g. "PE9")
+#[derive(StructOpt)]
+enum GpioCommand {
+    /// Reads the level of the pin
+    Get {
+        /// Label or port name
+        pin: String,
+    },
+    /// Drives the pin "high" or "low", switching it to an output
+    ///
+    /// The pin reverts to its safe state when the device considers the host gone, e.g. with the
+    /// CDC firmware once the tool exits.
+    Set {
+        /// Label or port name
+        pin: String,
+        /// "high" or "low", "1" or "0"
+        #[structopt(parse(try_from_str = level_argument))]
+        level: bool,
+    },
+    /// Shows the mode of the pin, switching it to MODE first if given: input, output or afN
+    Mode {
+        /// Label or port name
+        pin: String,
+        /// "input", "output" or "afN" for alternate function N
+        #[structopt(parse(try_from_str = mode_argument))]
+        mode: Option<GpioPinMode>,
+    },
 }
 
-struct CobsSerialPort {
-    inner: Box<dyn SerialPort>,
-    buffer: Vec<u8>,
+fn level_argument(level: &str) -> Result<bool, String> {
+    parse_level(level).ok_or_else(|| format!("invalid level {:?}, expected high or low", level))
 }
 
-impl CobsSerialPort {
-    pub fn new(serial_port: Box<dyn SerialPort>) -> Self {
-        Self {
-            inner: serial_port,
-            buffer: vec![]
-        }
-    }
+fn mode_argument(mode: &str) -> Result<GpioPinMode, String> {
+    parse_mode(mode).ok_or_else(|| format!("invalid mode {:?}, expected input, output or afN", mode))
+}
 
-    fn fill_buf(&mut self) -> io::Result<()> {
-        if self.buffer.contains(&0) {
-            return Ok(());
-        }
-        let mut buf = [0; 128];
-        loop {
-            let size = self.inner.read(&mut buf)?;
-            println!("read chink : {:?}", &buf[..size]);
-            self.buffer.extend_from_slice(&buf[..size]);
-            if buf.contains(&0) {
-                return Ok(());
-            }
+enum Error {
+    Usage(String),
+    NoDevice(String),
+    Device(HalError),
+    Other(String),
+}
+
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => EXIT_USAGE,
+            Error::NoDevice(_) => EXIT_NO_DEVICE,
+            Error::Device(_) | Error::Other(_) => EXIT_FAILURE,
         }
     }
 }
 
-impl PacketChannel for CobsSerialPort {
-    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
-        self.fill_buf()?;
-        println!("filled buffer {:?}", self.buffer);
-
-        while !self.buffer.is_empty() && self.buffer[0] == 0 {
-            self.buffer.remove(0);
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(message) | Error::NoDevice(message) | Error::Other(message) => f.write_str(message),
+            Error::Device(e) => f.write_str(&commands::error_message(e.kind())),
         }
-
-        let pos = self.buffer.iter().enumerate().find(|(_, b)| **b == 0).map(|(i, _)| i).unwrap();
-        let (packet_buf, tail) = self.buffer.split_at(pos);
-        let tail = tail[1..].to_vec();
-        println!("decoding {:?}", packet_buf);
-        let data = cobs::decode_vec(&packet_buf).unwrap();
-        self.buffer = tail;
-        Ok(data)
     }
+}
 
-    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
-        let mut data = cobs::encode_vec(data);
-        data.push(0);
-        self.inner.write_all(&data)?;
-        self.inner.flush()
+impl From<HalError> for Error {
+    fn from(e: HalError) -> Self {
+        Error::Device(e)
     }
 }
 
-struct BridgeDevice {
-    channel: SharedCommandChannel
+/// Device found by `list`
+#[derive(Serialize)]
+struct DeviceEntry {
+    device: String,
+    transport: &'static str,
 }
 
-impl BridgeDevice {
-    pub fn new(channel: Box<dyn CommandChannel>) -> Self {
-        Self {
-            channel: SharedCommandChannel::new(channel)
-        }
-    }
+#[derive(Serialize)]
+#[serde(transparent)]
+struct DeviceList(Vec<DeviceEntry>);
 
-    pub fn gpio(&self) -> HalResult<GpioPeripheral> {
-        let ep_channel = SharedEndpointChannel::new(self.channel.clone(), 1);
-        GpioPeripheral::probe(ep_channel)
+impl fmt::Display for DeviceList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.0 {
+            writeln!(f, "{:<16} {}", entry.device, entry.transport)?;
+        }
+        Ok(())
     }
 }
 
-struct GpioBridge {
-    channel: SharedEndpointChannel,
+/// Report of `crash`, `null` in JSON if the device didn't crash
+#[derive(Serialize)]
+#[serde(transparent)]
+struct Crash {
+    report: Option<CrashReport>,
+    #[serde(skip)]
+    symbols: Option<SymbolTable>,
 }
 
-impl GpioBridge {
-    fn enumerate(&self) -> HalResult<Vec<GpioPinInformation>> {
-        let command = GpioCommand::EnumeratePins;
-        let mut buf = [0; 16];
-        let size = ssmarshal::serialize(&mut buf, &command).unwrap();
-        let response = self.channel.command(&buf[..size])?;
-        if response.len() < 1 {
-            return Err(HalErrorKind::ProtocolError.into());
-        }
-        let n = response[0] as usize;
-        let mut result = Vec::new();
-        let mut offset = 1;
-        for _ in 0..n {
-            let (item, size) = ssmarshal::deserialize(&response[offset..]).map_err(|_| HalError::from(HalErrorKind::ProtocolError))?;
-            result.push(item);
-            offset += size;
-        }
-        if offset != response.len() {
-            return Err(HalErrorKind::ProtocolError.into());
+impl fmt::Display for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.report {
+            Some(report) => writeln!(f, "{}", report.describe(self.symbols.as_ref())),
+            None => writeln!(f, "no crash since the last report"),
         }
-        Ok(result)
-    }
-
-    fn simple_command<'a, C: Serialize, R: DeserializeOwned>(&self, command: C) -> HalResult<R> {
-        let mut buf = [0; 16];
-        let size = ssmarshal::serialize(&mut buf, &command).unwrap();
-        let response = self.channel.command(&buf[..size])?;
-        let response = ssmarshal::deserialize(&response).unwrap().0;
-        Ok(response)
     }
+}
 
-    fn get_pin_mode(&self, index: u8) -> HalResult<GpioPinMode> {
-        self.simple_command(GpioCommand::GetPinMode(index))
+fn print<T: Serialize + fmt::Display>(options: &Options, value: &T) {
+    if options.json {
+        println!("{}", serde_json::to_string(value).unwrap());
+    } else {
+        print!("{}", value);
     }
+}
 
-    fn set_pin_mode(&self, index: u8, mode: GpioPinMode) -> HalResult<()> {
-        self.simple_command(GpioCommand::SetPinMode(index, mode))
-    }
+fn serial_bridge(port: Box<dyn SerialPort>) -> HalResult<BridgeDevice> {
+    let reader = FragmentedChannel::new(CobsSerialPort::new(port.try_clone().map_err(|_| HalError::from(HalErrorKind::ProtocolError))?));
+    let writer = FragmentedChannel::new(CobsSerialPort::new(port));
+    Ok(BridgeDevice::pipelined(PipelinedChannel::new(reader, writer)))
+}
 
-    fn get_pin_value(&self, index: u8) -> HalResult<bool> {
-        self.simple_command(GpioCommand::GetPinValue(index))
-    }
+fn open_serial(path: &str) -> Result<BridgeDevice, Error> {
+    let mut port = serialport::open(path)
+        .map_err(|e| Error::NoDevice(format!("Can't open {}: {}", path, e)))?;
+    port.set_timeout(Duration::from_secs(1)).ok();
 
-    fn set_pin_value(&self, index: u8, value: bool) -> HalResult<()> {
-        self.simple_command(GpioCommand::SetPinValue(index, value))
+    // Discard any buffered leftovers
+    loop {
+        match port.read(&mut [0u8; 1024]) {
+            Ok(0) => break,
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(Error::Other(format!("Can't read {}: {}", path, e))),
+            _ => continue,
+        }
     }
+    // Terminate a partial packet the device may be waiting for
+    port.write_all(&[0u8; 4]).map_err(|e| Error::Other(format!("Can't write {}: {}", path, e)))?;
+    Ok(serial_bridge(port)?)
 }
 
-struct GpioPeripheral {
-    pins: HashMap<u8, GpioPin>,
+/// Opens the USB device at the location, or the first one
+#[cfg(feature = "usb")]
+fn open_usb(location: Option<&str>) -> Result<Option<UsbConnection>, Error> {
+    UsbConnection::open_at(location).map_err(|e| Error::Other(format!("Can't open the USB device: {}", e)))
 }
 
-impl GpioPeripheral {
-    fn probe(channel: SharedEndpointChannel) -> HalResult<Self> {
-        let bridge = Arc::new(GpioBridge {
-            channel,
-        });
-        let pin_info = bridge.enumerate()?;
-
-        let pins: HashMap<_, _> = pin_info.iter().enumerate().map(|(i, info)| {
-            let pin = GpioPin {
-                bridge: bridge.clone(),
-                index: i as u8
-            };
-            (info.index_minor, pin)
-        }).collect();
-
-        Ok(Self {
-            pins,
-        })
+#[cfg(feature = "usb")]
+fn usb_bridge(usb: &UsbConnection) -> Result<Option<BridgeDevice>, Error> {
+    match usb.command_channel(Duration::from_secs(1)) {
+        Ok(Some(channel)) => {
+            let reader = FragmentedChannel::new(channel.clone());
+            let writer = FragmentedChannel::new(channel);
+            Ok(Some(BridgeDevice::pipelined(PipelinedChannel::new(reader, writer))))
+        },
+        Ok(None) => Ok(None),
+        Err(e) => Err(Error::Other(format!("Can't open the USB interface: {}", e))),
     }
+}
 
-    pub fn pin(&mut self, index_minor: u8) -> HalResult<GpioPin> {
-        self.pins.remove(&index_minor).ok_or_else(|| HalError::from(HalErrorKind::InvalidParameter))
+/// Connects to the selected device
+///
+/// Without a selection the bulk interface of the first USB device is used, the firmware may be
+/// built with the serial port instead.
+fn connect(device: Option<&str>) -> Result<BridgeDevice, Error> {
+    match device {
+        #[cfg(feature = "usb")]
+        Some(device) if device.starts_with(USB_PREFIX) => {
+            let usb = open_usb(Some(&device[USB_PREFIX.len()..]))?
+                .ok_or_else(|| Error::NoDevice(format!("No device at {}", device)))?;
+            usb_bridge(&usb)?.ok_or_else(|| Error::NoDevice(format!("{} has no bulk interface, use its serial port", device)))
+        },
+        #[cfg(not(feature = "usb"))]
+        Some(device) if device.starts_with(USB_PREFIX) => Err(Error::Usage("Built without USB support".to_string())),
+        Some(path) => open_serial(path),
+        None => {
+            #[cfg(feature = "usb")]
+            {
+                if let Some(usb) = open_usb(None)? {
+                    if let Some(bridge) = usb_bridge(&usb)? {
+                        return Ok(bridge);
+                    }
+                }
+            }
+            match find_device_ports().first() {
+                Some(path) => open_serial(path),
+                None => Err(Error::NoDevice("Can't find device!".to_string())),
+            }
+        },
     }
+}
 
-    pub fn all_pins(&mut self) -> Vec<GpioPin> {
-        let mut pins: Vec<_> = self.pins.drain().map(|(_, pin)| pin).collect();
-        pins.sort_unstable_by(|a, b| a.index.cmp(&b.index));
-        pins
+fn list() -> Result<DeviceList, Error> {
+    let mut devices = Vec::new();
+    #[cfg(feature = "usb")]
+    {
+        let locations = UsbConnection::list().map_err(|e| Error::Other(format!("Can't list the USB devices: {}", e)))?;
+        devices.extend(locations.into_iter().map(|location| DeviceEntry {
+            device: format!("{}{}", USB_PREFIX, location),
+            transport: "usb",
+        }));
     }
+    devices.extend(find_device_ports().into_iter().map(|port| DeviceEntry {
+        device: port,
+        transport: "serial",
+    }));
+    Ok(DeviceList(devices))
 }
 
-struct GpioPin {
-    bridge: Arc<GpioBridge>,
-    index: u8,
+/// Reads `what` from the firmware ELF file
+fn firmware_elf<T, E: fmt::Display>(path: Option<&Path>, what: &str, read: impl FnOnce(&[u8]) -> Result<T, E>) -> Option<T> {
+    let path = path?;
+    let result = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|elf| read(&elf).map_err(|e| e.to_string()));
+    match result {
+        Ok(result) => Some(result),
+        Err(e) => {
+            eprintln!("Can't read the {} from {:?}: {}", what, path, e);
+            None
+        },
+    }
 }
 
-impl GpioPin {
-    pub fn into_output(&self) -> HalResult<()> {
-        self.bridge.set_pin_mode(self.index, GpioPinMode::PushPullOutput)
+/// Prints the log line by line until the device goes away
+///
+/// A firmware with the binary log is decoded with its ELF file.
+#[cfg(feature = "usb")]
+fn print_log(mut reader: UsbLogReader, elf: Option<&Path>) {
+    let mut stream = match firmware_elf(elf, "log format strings", |elf| LogDecoder::from_elf(elf).map(LogStream::new)) {
+        Some(stream) => stream,
+        None => return print_text_log(reader),
+    };
+    loop {
+        match reader.read() {
+            Ok(data) => {
+                for record in stream.push(&data) {
+                    match record {
+                        Ok(record) => println!("device: {}", record),
+                        Err(e) => println!("device: {}", e),
+                    }
+                }
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(_) => break,
+        }
     }
 }
 
-impl digital::v2::OutputPin for GpioPin {
-    type Error = HalError;
-
-    fn set_low(&mut self) -> Result<(), Self::Error> {
-        self.bridge.set_pin_value(self.index, false)
+#[cfg(feature = "usb")]
+fn print_text_log(mut reader: UsbLogReader) {
+    let mut line = Vec::new();
+    loop {
+        match reader.read() {
+            Ok(data) => {
+                for byte in data {
+                    match byte {
+                        b'\n' => {
+                            println!("device: {}", String::from_utf8_lossy(&line));
+                            line.clear();
+                        },
+                        b'\r' => {},
+                        _ => line.push(byte),
+                    }
+                }
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(_) => break,
+        }
     }
+}
 
-    fn set_high(&mut self) -> Result<(), Self::Error> {
-        self.bridge.set_pin_value(self.index, true)
+#[cfg(feature = "usb")]
+fn log(options: &Options) -> Result<(), Error> {
+    let location = match options.device.as_deref() {
+        Some(device) if device.starts_with(USB_PREFIX) => Some(&device[USB_PREFIX.len()..]),
+        Some(_) => return Err(Error::Usage("The log is only read from USB devices".to_string())),
+        None => None,
+    };
+    let usb = open_usb(location)?.ok_or_else(|| Error::NoDevice("Can't find device!".to_string()))?;
+    match usb.log_reader(Duration::from_secs(1)) {
+        Ok(Some(reader)) => {
+            print_log(reader, options.elf.as_deref());
+            Ok(())
+        },
+        Ok(None) => Err(Error::Other("The firmware has no log interface".to_string())),
+        Err(e) => Err(Error::Other(format!("Can't open the log interface: {}", e))),
     }
 }
 
-fn led_test(port: Box<dyn SerialPort>) -> HalResult<()> {
-    let port = CobsSerialPort::new(port);
+#[cfg(not(feature = "usb"))]
+fn log(_options: &Options) -> Result<(), Error> {
+    Err(Error::Usage("Built without USB support".to_string()))
+}
 
-    let bridge = BridgeDevice::new(Box::new(port));
+fn led_test(bridge: BridgeDevice) -> HalResult<()> {
     let mut gpio = bridge.gpio()?;
 
     let mut pins = gpio.all_pins();
@@ -232,76 +377,56 @@ fn led_test(port: Box<dyn SerialPort>) -> HalResult<()> {
     }
 }
 
-#[allow(unused)]
-fn rng_test(mut port: Box<dyn SerialPort>) {
-    let mut rng = rand::thread_rng();
-    for packet_size in 2..2048 {
-        //let packet_size = 64;
-        let data: String = (0..packet_size).map(|_| rng.sample(Alphanumeric)).collect();
-        let mut packet_data = cobs::encode_vec(&data.as_bytes());
-        packet_data.push(0);
-
-        println!("writing {}-byte packet ({} encoded)", packet_size, packet_data.len());
-        println!("{:?}", packet_data);
-        let mut total = 0;
-        while total < packet_data.len() {
-            let count = std::cmp::min(packet_data.len() - total, 1024);
-            let count = port.write(&packet_data[total..total+count]).unwrap();
-
-            total += count as usize;
-
-            println!("write {}", total);
-        }
-
-        println!("reading back...");
-
-        let rx_data = packet_data.clone();
-        let mut total = 0;
-        let mut buf = [0u8; 1024];
-
-        while total < rx_data.len() {
-            let count = port.read(&mut buf).unwrap();
-
-            println!("read: {} / {}", total + count, rx_data.len());
-
-            let received = &buf[..count as usize];
-            let expected = &rx_data[total..total+count as usize];
+fn gpio(options: &Options, bridge: &BridgeDevice, command: &GpioCommand) -> Result<(), Error> {
+    match command {
+        GpioCommand::Get { pin } => print(options, &commands::gpio_get(bridge, pin)?),
+        GpioCommand::Set { pin, level } => print(options, &commands::gpio_set(bridge, pin, *level)?),
+        GpioCommand::Mode { pin, mode } => print(options, &commands::gpio_mode(bridge, pin, *mode)?),
+    }
+    Ok(())
+}
 
-            if received != expected {
-                panic!("mismatch at {} ({:?} != {:?})", total, received, expected);
+fn run(options: &Options) -> Result<(), Error> {
+    match &options.command {
+        Command::List => print(options, &list()?),
+        Command::Log => log(options)?,
+        command => {
+            let bridge = connect(options.device.as_deref())?;
+            match command {
+                Command::Info => print(options, &commands::info(&bridge)?),
+                Command::Gpio(command) => gpio(options, &bridge, command)?,
+                Command::Crash => {
+                    let report = bridge.take_last_crash()?;
+                    let symbols = match report {
+                        Some(CrashReport { fault: Some(_), .. }) => firmware_elf(options.elf.as_deref(), "symbols", SymbolTable::from_elf),
+                        _ => None,
+                    };
+                    print(options, &Crash {
+                        report,
+                        symbols,
+                    });
+                },
+                Command::LedTest => led_test(bridge)?,
+                Command::Shell => shell::run(&bridge).map_err(|e| Error::Other(format!("Terminal error: {}", e)))?,
+                Command::List | Command::Log => unreachable!(),
             }
-
-            total += count as usize;
-        }
+        },
     }
+    Ok(())
 }
 
 fn main() {
-    let port_path = if let Some(path) = find_device_port() {
-        path
-    } else {
-        println!("Can't find device!");
-        return;
+    let options = match Options::from_args_safe() {
+        Ok(options) => options,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_USAGE);
+        },
+        // Help and version
+        Err(e) => e.exit(),
     };
-
-    let mut port = serialport::open(&port_path).unwrap();
-    port.set_timeout(Duration::from_secs(1)).ok();
-
-    // Discard any buffered leftovers
-    println!("discarding...");
-    loop {
-        match port.read(&mut [0u8; 1024]) {
-            Ok(0) => break,
-            Err(ref err) if err.kind() == std::io::ErrorKind::TimedOut => break,
-            Err(err) => panic!(err),
-            _ => continue,
-        }
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
     }
-
-    println!("writing zeros...");
-    port.write(&[0u8; 4]).unwrap();
-
-    println!("running test...");
-    //rng_test(port);
-    led_test(port).unwrap();
 }
@@ -0,0 +1,93 @@
+use serialport::{available_ports, SerialPortType, SerialPort};
+use std::io;
+use deadbug_common::protocol::channels::PacketChannel;
+use deadbug_common::protocol::usb::{USB_VID, USB_PID};
+
+pub fn find_device_port() -> Option<String> {
+    find_device_ports().into_iter().next()
+}
+
+/// Returns the serial ports of all connected devices
+pub fn find_device_ports() -> Vec<String> {
+    let mut ports = Vec::new();
+    if let Ok(list) = available_ports() {
+        for info in list {
+            if let SerialPortType::UsbPort(usb_info) = info.port_type {
+                if usb_info.vid == USB_VID && usb_info.pid == USB_PID {
+                    ports.push(info.port_name);
+                }
+            }
+        }
+    }
+    ports
+}
+
+/// Splits a byte stream into COBS-encoded packets delimited by zero bytes
+#[derive(Default)]
+pub struct CobsDecoder {
+    buffer: Vec<u8>,
+}
+
+impl CobsDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete packet, if any
+    pub fn next_packet(&mut self) -> Option<io::Result<Vec<u8>>> {
+        loop {
+            let end = self.buffer.iter().position(|b| *b == 0)?;
+            let frame: Vec<u8> = self.buffer.drain(..=end).collect();
+            // Skip extra delimiters
+            if end == 0 {
+                continue;
+            }
+            let packet = cobs::decode_vec(&frame[..end])
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid COBS frame"));
+            return Some(packet);
+        }
+    }
+}
+
+/// Encodes a packet and appends the delimiter
+pub fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut data = cobs::encode_vec(data);
+    data.push(0);
+    data
+}
+
+pub struct CobsSerialPort {
+    inner: Box<dyn SerialPort>,
+    decoder: CobsDecoder,
+}
+
+impl CobsSerialPort {
+    pub fn new(serial_port: Box<dyn SerialPort>) -> Self {
+        Self {
+            inner: serial_port,
+            decoder: CobsDecoder::new(),
+        }
+    }
+}
+
+impl PacketChannel for CobsSerialPort {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = [0; 128];
+        loop {
+            if let Some(packet) = self.decoder.next_packet() {
+                return packet;
+            }
+            let size = self.inner.read(&mut buf)?;
+            self.decoder.push(&buf[..size]);
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&encode_packet(data))?;
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,362 @@
+//! Interactive shell for exploring a device
+//!
+//! A line is an endpoint, a command and its arguments, e.g. `gpio set LD3 high`. Endpoints,
+//! commands, pin names and argument values are completed with tab. Edges of watched pins are
+//! printed while the shell waits for input.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use deadbug_common::hal::HalError;
+use deadbug_common::protocol::notification::{NotificationKind, PinEdgeNotification};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use crate::bridge::BridgeDevice;
+use crate::commands::{self, parse_level, parse_mode};
+
+/// Kind of a command argument, completed from its values
+#[derive(Clone, Copy)]
+enum Argument {
+    Pin,
+    Level,
+    Mode,
+    Timeout,
+}
+
+impl Argument {
+    fn usage(self) -> &'static str {
+        match self {
+            Argument::Pin => "PIN",
+            Argument::Level => "high|low",
+            Argument::Mode => "[input|output|afN]",
+            Argument::Timeout => "MS|off",
+        }
+    }
+}
+
+struct CommandSpec {
+    endpoint: &'static str,
+    name: &'static str,
+    arguments: &'static [Argument],
+    help: &'static str,
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        endpoint: "gpio",
+        name: "pins",
+        arguments: &[],
+        help: "shows the pins with their modes and owning endpoints",
+    },
+    CommandSpec {
+        endpoint: "gpio",
+        name: "get",
+        arguments: &[Argument::Pin],
+        help: "reads the level of the pin",
+    },
+    CommandSpec {
+        endpoint: "gpio",
+        name: "set",
+        arguments: &[Argument::Pin, Argument::Level],
+        help: "drives the pin, switching it to an output",
+    },
+    CommandSpec {
+        endpoint: "gpio",
+        name: "mode",
+        arguments: &[Argument::Pin, Argument::Mode],
+        help: "shows the mode of the pin, switching it to the mode first if given",
+    },
+    CommandSpec {
+        endpoint: "gpio",
+        name: "watch",
+        arguments: &[Argument::Pin],
+        help: "prints the level of the input pin whenever it changes",
+    },
+    CommandSpec {
+        endpoint: "gpio",
+        name: "unwatch",
+        arguments: &[Argument::Pin],
+        help: "stops printing the level of the pin",
+    },
+    CommandSpec {
+        endpoint: "system",
+        name: "crash",
+        arguments: &[],
+        help: "shows the panic or hard fault that caused the last reset",
+    },
+    CommandSpec {
+        endpoint: "system",
+        name: "keep-alive",
+        arguments: &[Argument::Timeout],
+        help: "sets the timeout after which the pins revert to their safe state",
+    },
+];
+
+const BUILTINS: &[&str] = &["help", "exit"];
+
+/// Result of a line
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Output(String),
+    Exit,
+}
+
+/// Pins known to the shell, by their port names and labels
+#[derive(Clone, Default)]
+struct PinNames {
+    /// Port name and label of each pin, in enumeration order
+    pins: Vec<(String, String)>,
+}
+
+impl PinNames {
+    fn name(&self, index: u8) -> String {
+        self.pins.get(index as usize).map_or_else(|| format!("pin {}", index), |(name, _)| name.clone())
+    }
+
+    fn candidates(&self) -> impl Iterator<Item = &str> {
+        self.pins.iter().flat_map(|(name, label)| [name.as_str(), label.as_str()]).filter(|name| !name.is_empty())
+    }
+}
+
+/// Executes the lines of the shell
+pub struct Shell<'a> {
+    bridge: &'a BridgeDevice,
+    pins: PinNames,
+    /// Watched pins by index, shared with the thread printing the edges
+    watched: Arc<Mutex<HashMap<u8, String>>>,
+}
+
+impl<'a> Shell<'a> {
+    /// Reads the pins of the device, `print` is called with the edges of watched pins
+    pub fn new(bridge: &'a BridgeDevice, mut print: impl FnMut(String) + Send + 'static) -> Result<Self, String> {
+        let info = commands::info(bridge).map_err(error_message)?;
+        let pins = PinNames {
+            pins: info.pins.into_iter().map(|pin| (pin.name, pin.label)).collect(),
+        };
+        let watched = Arc::new(Mutex::new(HashMap::<u8, String>::new()));
+        let edges = bridge.notifications().subscribe(Some(NotificationKind::PinEdge));
+        let watched_pins = watched.clone();
+        thread::spawn(move || {
+            for notification in edges {
+                let edge: PinEdgeNotification = match notification.decode() {
+                    Ok(edge) => edge,
+                    Err(_) => continue,
+                };
+                if let Some(name) = watched_pins.lock().unwrap().get(&edge.pin) {
+                    print(format!("{}: {}", name, if edge.level { "high" } else { "low" }));
+                }
+            }
+        });
+        Ok(Self {
+            bridge,
+            pins,
+            watched,
+        })
+    }
+
+    /// Returns the completer for the pins of this device
+    pub fn completer(&self) -> ShellCompleter {
+        ShellCompleter {
+            pins: self.pins.clone(),
+        }
+    }
+
+    /// Executes the line, errors are returned as their messages
+    pub fn execute(&mut self, line: &str) -> Result<Outcome, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (endpoint, name, arguments) = match words.as_slice() {
+            [] => return Ok(Outcome::Output(String::new())),
+            ["help"] => return Ok(Outcome::Output(help())),
+            ["exit"] | ["quit"] => return Ok(Outcome::Exit),
+            [endpoint, name, arguments @ ..] => (*endpoint, *name, arguments),
+            [endpoint] => return Err(format!("expected a command after {:?}, see help", endpoint)),
+        };
+        let spec = COMMANDS.iter()
+            .find(|spec| spec.endpoint == endpoint && spec.name == name)
+            .ok_or_else(|| format!("unknown command {:?}, see help", format!("{} {}", endpoint, name)))?;
+        let required = spec.arguments.iter().filter(|argument| !matches!(argument, Argument::Mode)).count();
+        if arguments.len() < required || arguments.len() > spec.arguments.len() {
+            return Err(format!("usage: {}", usage(spec)));
+        }
+        self.run(spec.name, arguments).map(Outcome::Output)
+    }
+
+    fn run(&mut self, name: &str, arguments: &[&str]) -> Result<String, String> {
+        let bridge = self.bridge;
+        let output = match (name, arguments) {
+            ("pins", []) => commands::info(bridge).map_err(error_message)?.to_string(),
+            ("get", [pin]) => commands::gpio_get(bridge, pin).map_err(error_message)?.to_string(),
+            ("set", [pin, level]) => {
+                let level = parse_level(level).ok_or_else(|| format!("invalid level {:?}, expected high or low", level))?;
+                commands::gpio_set(bridge, pin, level).map_err(error_message)?.to_string()
+            },
+            ("mode", [pin, mode @ ..]) => {
+                let mode = match mode.first() {
+                    Some(mode) => Some(parse_mode(mode).ok_or_else(|| format!("invalid mode {:?}, expected input, output or afN", mode))?),
+                    None => None,
+                };
+                commands::gpio_mode(bridge, pin, mode).map_err(error_message)?.to_string()
+            },
+            ("watch", [pin]) => self.watch(pin, true)?,
+            ("unwatch", [pin]) => self.watch(pin, false)?,
+            ("crash", []) => match bridge.take_last_crash().map_err(error_message)? {
+                Some(report) => format!("{}\n", report.describe(None)),
+                None => "no crash since the last report\n".to_string(),
+            },
+            ("keep-alive", [timeout]) => {
+                let timeout = match *timeout {
+                    "off" => None,
+                    timeout => Some(Duration::from_millis(timeout.parse().map_err(|_| format!("invalid timeout {:?}", timeout))?)),
+                };
+                bridge.set_keep_alive_timeout(timeout).map_err(error_message)?;
+                String::new()
+            },
+            _ => unreachable!(),
+        };
+        Ok(output)
+    }
+
+    fn watch(&mut self, name: &str, enable: bool) -> Result<String, String> {
+        let pin = self.bridge.gpio().and_then(|mut gpio| gpio.pin_by_label(name)).map_err(error_message)?;
+        let name = self.pins.name(pin.index());
+        if !enable {
+            pin.watch(false).map_err(error_message)?;
+            self.watched.lock().unwrap().remove(&pin.index());
+            return Ok(String::new());
+        }
+        pin.watch(true).map_err(error_message)?;
+        self.watched.lock().unwrap().insert(pin.index(), name.clone());
+        let level = pin.value().map_err(error_message)?;
+        Ok(format!("{}: {} (watching)\n", name, if level { "high" } else { "low" }))
+    }
+}
+
+fn error_message(e: HalError) -> String {
+    commands::error_message(e.kind())
+}
+
+fn usage(spec: &CommandSpec) -> String {
+    let mut usage = format!("{} {}", spec.endpoint, spec.name);
+    for argument in spec.arguments {
+        usage.push(' ');
+        usage.push_str(argument.usage());
+    }
+    usage
+}
+
+fn help() -> String {
+    let mut help = String::new();
+    for spec in COMMANDS {
+        help.push_str(&format!("{:<28} {}\n", usage(spec), spec.help));
+    }
+    help.push_str(&format!("{:<28} {}\n", "help", "shows this list"));
+    help.push_str(&format!("{:<28} {}\n", "exit", "leaves the shell"));
+    help
+}
+
+/// Completes endpoints, commands and their arguments
+pub struct ShellCompleter {
+    pins: PinNames,
+}
+
+impl ShellCompleter {
+    /// Returns the start of the word at `pos` and the candidates for it
+    pub fn complete_word(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let prefix = &line[start..];
+        let words: Vec<_> = line[..start].split_whitespace().collect();
+        let candidates: Vec<&str> = match words.as_slice() {
+            [] => {
+                let mut endpoints: Vec<_> = COMMANDS.iter().map(|spec| spec.endpoint).collect();
+                endpoints.dedup();
+                endpoints.into_iter().chain(BUILTINS.iter().copied()).collect()
+            },
+            [endpoint] => COMMANDS.iter().filter(|spec| spec.endpoint == *endpoint).map(|spec| spec.name).collect(),
+            [endpoint, name, arguments @ ..] => {
+                let argument = COMMANDS.iter()
+                    .find(|spec| spec.endpoint == *endpoint && spec.name == *name)
+                    .and_then(|spec| spec.arguments.get(arguments.len()));
+                match argument {
+                    Some(Argument::Pin) => self.pins.candidates().collect(),
+                    Some(Argument::Level) => vec!["high", "low"],
+                    Some(Argument::Mode) => vec!["input", "output"],
+                    Some(Argument::Timeout) => vec!["off"],
+                    None => Vec::new(),
+                }
+            },
+        };
+        let matches = candidates.into_iter()
+            .filter(|candidate| candidate.len() >= prefix.len() && candidate[..prefix.len()].eq_ignore_ascii_case(prefix))
+            .map(str::to_string)
+            .collect();
+        (start, matches)
+    }
+}
+
+impl Completer for ShellCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.complete_word(line, pos))
+    }
+}
+
+impl Hinter for ShellCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for ShellCompleter {}
+
+impl Validator for ShellCompleter {}
+
+impl Helper for ShellCompleter {}
+
+/// History kept between sessions, in the home directory
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".deadbug_history"))
+}
+
+/// Runs the shell on the terminal until it's closed or `exit` is entered
+pub fn run(bridge: &BridgeDevice) -> rustyline::Result<()> {
+    let mut editor = Editor::new()?;
+    let mut printer = editor.create_external_printer()?;
+    let mut shell = match Shell::new(bridge, move |line| { printer.print(line).ok(); }) {
+        Ok(shell) => shell,
+        Err(e) => {
+            eprintln!("Can't read the pins: {}", e);
+            return Ok(());
+        },
+    };
+    editor.set_helper(Some(shell.completer()));
+    let history = history_path();
+    if let Some(history) = &history {
+        editor.load_history(history).ok();
+    }
+    loop {
+        let line = match editor.readline("deadbug> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        match shell.execute(&line) {
+            Ok(Outcome::Output(output)) => print!("{}", output),
+            Ok(Outcome::Exit) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+    if let Some(history) = &history {
+        editor.save_history(history).ok();
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, Recipient, RequestType, TransferType};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use deadbug_common::protocol::channels::PacketChannel;
+use deadbug_common::protocol::fragment::{needs_zero_length_packet, BULK_PACKET_SIZE, MAX_PACKET_SIZE};
+use deadbug_common::protocol::usb::{
+    USB_VID, USB_PID, USB_CLASS_VENDOR_SPECIFIC, COMMAND_INTERFACE_SUBCLASS, LOG_INTERFACE_SUBCLASS,
+    LOG_REQUEST_ENABLE,
+};
+
+/// Vendor interface found in the active configuration
+struct Interface {
+    number: u8,
+    read_endpoint: Option<u8>,
+    write_endpoint: Option<u8>,
+}
+
+/// Opened device, its interfaces are claimed by the channels using them
+pub struct UsbConnection {
+    handle: Arc<DeviceHandle<GlobalContext>>,
+}
+
+impl UsbConnection {
+    /// Opens the first device, returns `None` if there's none
+    pub fn open() -> rusb::Result<Option<Self>> {
+        Self::open_at(None)
+    }
+
+    /// Opens the device at the location returned by `list`, or the first one if `location` is
+    /// `None`
+    pub fn open_at(location: Option<&str>) -> rusb::Result<Option<Self>> {
+        for device in devices()? {
+            if location.is_none_or(|location| location == device_location(&device)) {
+                return Ok(Some(Self {
+                    handle: Arc::new(device.open()?),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the locations of the connected devices as "BUS:ADDRESS"
+    pub fn list() -> rusb::Result<Vec<String>> {
+        Ok(devices()?.iter().map(device_location).collect())
+    }
+
+    fn find_interface(&self, subclass: u8) -> rusb::Result<Option<Interface>> {
+        let config = self.handle.device().active_config_descriptor()?;
+        for interface in config.interfaces() {
+            for setting in interface.descriptors() {
+                if setting.class_code() != USB_CLASS_VENDOR_SPECIFIC || setting.sub_class_code() != subclass {
+                    continue;
+                }
+                let mut found = Interface {
+                    number: setting.interface_number(),
+                    read_endpoint: None,
+                    write_endpoint: None,
+                };
+                for endpoint in setting.endpoint_descriptors() {
+                    if endpoint.transfer_type() != TransferType::Bulk {
+                        continue;
+                    }
+                    match endpoint.direction() {
+                        Direction::In => found.read_endpoint = Some(endpoint.address()),
+                        Direction::Out => found.write_endpoint = Some(endpoint.address()),
+                    }
+                }
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Claims the command interface
+    ///
+    /// Returns `None` if there's none, e.g. when the firmware was built with the serial port.
+    pub fn command_channel(&self, timeout: Duration) -> rusb::Result<Option<UsbBulkChannel>> {
+        let interface = match self.find_interface(COMMAND_INTERFACE_SUBCLASS)? {
+            Some(interface) => interface,
+            None => return Ok(None),
+        };
+        let (read_endpoint, write_endpoint) = match (interface.read_endpoint, interface.write_endpoint) {
+            (Some(read_endpoint), Some(write_endpoint)) => (read_endpoint, write_endpoint),
+            _ => return Ok(None),
+        };
+        self.handle.claim_interface(interface.number)?;
+        Ok(Some(UsbBulkChannel {
+            handle: self.handle.clone(),
+            read_endpoint,
+            write_endpoint,
+            timeout,
+        }))
+    }
+
+    /// Claims the log interface and moves the firmware log from the UART to it
+    pub fn log_reader(&self, timeout: Duration) -> rusb::Result<Option<UsbLogReader>> {
+        let interface = match self.find_interface(LOG_INTERFACE_SUBCLASS)? {
+            Some(interface) => interface,
+            None => return Ok(None),
+        };
+        let endpoint = match interface.read_endpoint {
+            Some(endpoint) => endpoint,
+            None => return Ok(None),
+        };
+        self.handle.claim_interface(interface.number)?;
+        let reader = UsbLogReader {
+            handle: self.handle.clone(),
+            interface: interface.number,
+            endpoint,
+            timeout,
+        };
+        reader.set_enabled(true)?;
+        Ok(Some(reader))
+    }
+}
+
+fn devices() -> rusb::Result<Vec<Device<GlobalContext>>> {
+    let mut found = Vec::new();
+    for device in rusb::devices()?.iter() {
+        let descriptor = device.device_descriptor()?;
+        if descriptor.vendor_id() == USB_VID && descriptor.product_id() == USB_PID {
+            found.push(device);
+        }
+    }
+    Ok(found)
+}
+
+fn device_location(device: &Device<GlobalContext>) -> String {
+    format!("{:03}:{:03}", device.bus_number(), device.address())
+}
+
+/// Packets carried by the transfers of the vendor bulk interface
+///
+/// Clones share the device, so one can read while another writes.
+#[derive(Clone)]
+pub struct UsbBulkChannel {
+    handle: Arc<DeviceHandle<GlobalContext>>,
+    read_endpoint: u8,
+    write_endpoint: u8,
+    timeout: Duration,
+}
+
+/// Text output of the firmware log
+pub struct UsbLogReader {
+    handle: Arc<DeviceHandle<GlobalContext>>,
+    interface: u8,
+    endpoint: u8,
+    timeout: Duration,
+}
+
+impl UsbLogReader {
+    fn set_enabled(&self, enabled: bool) -> rusb::Result<()> {
+        let request_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface);
+        self.handle.write_control(
+            request_type,
+            LOG_REQUEST_ENABLE,
+            u16::from(enabled),
+            u16::from(self.interface),
+            &[],
+            self.timeout,
+        )?;
+        Ok(())
+    }
+
+    /// Returns the next chunk of the log, which may end in the middle of a line
+    pub fn read(&mut self) -> io::Result<Vec<u8>> {
+        // One packet at a time, the device doesn't end transfers with short packets
+        let mut buf = [0; BULK_PACKET_SIZE];
+        let size = self.handle.read_bulk(self.endpoint, &mut buf, self.timeout).map_err(to_io_error)?;
+        Ok(buf[..size].to_vec())
+    }
+}
+
+impl Drop for UsbLogReader {
+    fn drop(&mut self) {
+        // Back to the UART
+        self.set_enabled(false).ok();
+    }
+}
+
+fn to_io_error(e: rusb::Error) -> io::Error {
+    match e {
+        rusb::Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, e),
+        e => io::Error::other(e),
+    }
+}
+
+impl PacketChannel for UsbBulkChannel {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        loop {
+            let size = self.handle.read_bulk(self.read_endpoint, &mut buf, self.timeout).map_err(to_io_error)?;
+            // Skip zero-length packets which weren't merged into the previous transfer
+            if size > 0 {
+                return Ok(buf[..size].to_vec());
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        self.handle.write_bulk(self.write_endpoint, data, self.timeout).map_err(to_io_error)?;
+        // Tells the device the transfer ended
+        if needs_zero_length_packet(data.len()) {
+            self.handle.write_bulk(self.write_endpoint, &[], self.timeout).map_err(to_io_error)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use deadbug_cli::bridge::BridgeDevice;
+use deadbug_cli::commands::{self, parse_level, parse_mode, PinLevel, PinMode};
+use deadbug_common::hal::HalErrorKind;
+use deadbug_common::hal::gpio::GpioPinMode;
+use deadbug_common::protocol::channels::{DeviceChannel, FragmentedChannel};
+use deadbug_sim::SimulatedDevice;
+
+fn connect(sim: &SimulatedDevice) -> BridgeDevice {
+    let channel = DeviceChannel::new(FragmentedChannel::new(sim.clone()));
+    BridgeDevice::new(Box::new(channel))
+}
+
+#[test]
+fn parse_arguments() {
+    assert_eq!(parse_mode("Output"), Some(GpioPinMode::PushPullOutput));
+    assert_eq!(parse_mode("input"), Some(GpioPinMode::FloatingInput));
+    assert_eq!(parse_mode("af7"), Some(GpioPinMode::Alternate(7)));
+    assert_eq!(parse_mode("af"), None);
+    assert_eq!(parse_mode("analog"), None);
+    assert_eq!(commands::mode_name(GpioPinMode::Alternate(14)), "af14");
+
+    assert_eq!(parse_level("HIGH"), Some(true));
+    assert_eq!(parse_level("0"), Some(false));
+    assert_eq!(parse_level("on"), None);
+}
+
+#[test]
+fn drive_and_read_pins() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+
+    assert_eq!(commands::gpio_set(&bridge, "ld5", true).unwrap(), PinLevel {
+        pin: "PE10".to_string(),
+        level: true,
+    });
+    assert_eq!(sim.output_level(2), Some(true));
+    commands::gpio_set(&bridge, "PE10", false).unwrap();
+    assert_eq!(sim.output_level(2), Some(false));
+
+    assert_eq!(commands::gpio_mode(&bridge, "PE10", Some(GpioPinMode::FloatingInput)).unwrap(), PinMode {
+        pin: "PE10".to_string(),
+        mode: "input".to_string(),
+    });
+    assert_eq!(commands::gpio_mode(&bridge, "PE10", None).unwrap().mode, "input");
+    sim.set_input_level(2, true);
+    assert!(commands::gpio_get(&bridge, "PE10").unwrap().level);
+
+    let err = commands::gpio_get(&bridge, "PZ1").unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::InvalidParameter));
+}
+
+#[test]
+fn device_info() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    commands::gpio_set(&bridge, "LD3", true).unwrap();
+
+    let info = commands::info(&bridge).unwrap();
+    assert_eq!(info.pins.len(), 8);
+    let pin = &info.pins[1];
+    assert_eq!((pin.name.as_str(), pin.label.as_str(), pin.mode.as_str()), ("PE9", "LD3", "output"));
+    assert!(pin.owner.is_some());
+    assert_eq!(info.pins[0].owner, None);
+    assert!(info.to_string().lines().nth(2).unwrap().contains("LD3"));
+}
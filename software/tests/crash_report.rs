@@ -0,0 +1,77 @@
+use deadbug_cli::crash_report::{CrashReport, Location, SymbolError, SymbolTable};
+use deadbug_common::protocol::system::FaultRegisters;
+use object::write::{Object, Symbol, SymbolSection};
+use object::{Architecture, BinaryFormat, Endianness, SymbolFlags, SymbolKind, SymbolScope};
+
+/// ELF file with Thumb function symbols at the addresses
+fn firmware_elf(functions: &[(&str, u64, u64)]) -> Vec<u8> {
+    let mut elf = Object::new(BinaryFormat::Elf, Architecture::Arm, Endianness::Little);
+    for &(name, address, size) in functions {
+        elf.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value: address | 1,
+            size,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Absolute,
+            flags: SymbolFlags::None,
+        });
+    }
+    elf.write().unwrap()
+}
+
+#[test]
+fn look_up_functions() {
+    let elf = firmware_elf(&[
+        ("_ZN10deadbug_fw3app4poll17h0123456789abcdefE", 0x0800_1000, 0x100),
+        ("main", 0x0800_0400, 0x20),
+    ]);
+    let symbols = SymbolTable::from_elf(&elf).unwrap();
+
+    assert_eq!(symbols.lookup(0x0800_1034), Some(Location {
+        function: "deadbug_fw::app::poll",
+        offset: 0x34,
+    }));
+    // Return address with the Thumb bit
+    assert_eq!(symbols.lookup(0x0800_0411).unwrap().to_string(), "<main+0x10>");
+    assert_eq!(symbols.lookup(0x0800_0420), None);
+    assert_eq!(symbols.lookup(0x0800_0000), None);
+
+    assert_eq!(SymbolTable::from_elf(&firmware_elf(&[])).err(), Some(SymbolError::NoSymbols));
+    assert_eq!(SymbolTable::from_elf(b"not an ELF file").err(), Some(SymbolError::InvalidElf));
+}
+
+#[test]
+fn describe_fault() {
+    let elf = firmware_elf(&[("main", 0x0800_0400, 0x20)]);
+    let symbols = SymbolTable::from_elf(&elf).unwrap();
+    let report = CrashReport {
+        message: "hard fault at 0x08000408".to_string(),
+        fault: Some(FaultRegisters {
+            pc: 0x0800_0408,
+            lr: 0x0800_1001,
+            xpsr: 0x6100_0000,
+            cfsr: (1 << 1) | (1 << 7),
+            hfsr: 1 << 30,
+            mmfar: 0x2000_4000,
+            ..FaultRegisters::default()
+        }),
+    };
+    let description = report.describe(Some(&symbols));
+    let lines: Vec<_> = description.lines().collect();
+    assert_eq!(lines[..4], [
+        "hard fault at 0x08000408",
+        "  pc    0x08000408 <main+0x8>",
+        "  lr    0x08001001",
+        "  xpsr  0x61000000",
+    ]);
+    assert!(lines.contains(&"  cause: data access violation, escalated fault"));
+    assert!(lines.contains(&"  fault address: 0x20004000"));
+
+    let panic = CrashReport {
+        message: "panicked at 'oops'".to_string(),
+        fault: None,
+    };
+    assert_eq!(panic.describe(Some(&symbols)), "panicked at 'oops'");
+}
@@ -0,0 +1,137 @@
+use deadbug_cli::log_decoder::{DecodeError, DecodedRecord, LogDecoder, LogStream};
+
+/// String table in the layout of the `.stm32_log` section, returns the ids
+fn string_table(entries: &[(log::Level, &str, &str)]) -> (Vec<u8>, Vec<u16>) {
+    let mut strings = Vec::new();
+    let mut ids = Vec::new();
+    for (level, target, format) in entries {
+        ids.push(strings.len() as u16);
+        strings.push(*level as u8);
+        strings.extend_from_slice(target.as_bytes());
+        strings.push(0);
+        strings.extend_from_slice(format.as_bytes());
+        strings.push(0);
+    }
+    (strings, ids)
+}
+
+fn header(id: u16, timestamp: u32) -> Vec<u8> {
+    let mut frame = id.to_le_bytes().to_vec();
+    frame.extend_from_slice(&timestamp.to_le_bytes());
+    frame
+}
+
+fn varint(mut value: u64, frame: &mut Vec<u8>) {
+    while value >= 0x80 {
+        frame.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    frame.push(value as u8);
+}
+
+fn string(s: &str, frame: &mut Vec<u8>) {
+    varint(s.len() as u64, frame);
+    frame.extend_from_slice(s.as_bytes());
+}
+
+#[test]
+fn format_arguments() {
+    let (strings, ids) = string_table(&[
+        (log::Level::Info, "fw::usb", "rx {} bytes from {:?}, flags {:#06x}"),
+        (log::Level::Debug, "fw", "t={:.2} offset={:>4} {}{{}}"),
+    ]);
+    let decoder = LogDecoder::new(strings);
+
+    let mut frame = header(ids[0], 1234);
+    frame.push(0);
+    varint(300, &mut frame);
+    frame.push(2);
+    string("host", &mut frame);
+    frame.push(0);
+    varint(0x1f, &mut frame);
+    assert_eq!(decoder.decode(&frame).unwrap(), DecodedRecord {
+        timestamp: 1234,
+        level: log::Level::Info,
+        target: "fw::usb".to_string(),
+        message: "rx 300 bytes from \"host\", flags 0x001f".to_string(),
+    });
+
+    let mut frame = header(ids[1], 0);
+    frame.push(4);
+    frame.extend_from_slice(&1.5f32.to_bits().to_le_bytes());
+    // -3, zigzag encoded
+    frame.push(1);
+    varint(5, &mut frame);
+    frame.push(3);
+    frame.push(1);
+    let record = decoder.decode(&frame).unwrap();
+    assert_eq!(record.level, log::Level::Debug);
+    assert_eq!(record.message, "t=1.50 offset=  -3 true{}");
+}
+
+#[test]
+fn missing_arguments() {
+    let (strings, ids) = string_table(&[(log::Level::Warn, "fw", "{} and {}")]);
+    let decoder = LogDecoder::new(strings);
+
+    // The second argument didn't fit into the frame
+    let mut frame = header(ids[0], 0);
+    frame.push(0);
+    varint(7, &mut frame);
+    assert_eq!(decoder.decode(&frame).unwrap().message, "7 and {?}");
+}
+
+#[test]
+fn text_and_dropped_records() {
+    let decoder = LogDecoder::new(Vec::new());
+
+    let mut frame = header(0xffff, 5);
+    frame.push(1);
+    string("fw::persistent_panic", &mut frame);
+    string("panic! at main.rs:10", &mut frame);
+    let record = decoder.decode(&frame).unwrap();
+    assert_eq!(record.level, log::Level::Error);
+    assert_eq!(record.target, "fw::persistent_panic");
+    assert_eq!(record.message, "panic! at main.rs:10");
+
+    let mut frame = header(0xfffe, 5);
+    frame.push(0);
+    varint(12, &mut frame);
+    assert_eq!(decoder.decode(&frame).unwrap().message, "12 log messages dropped");
+}
+
+#[test]
+fn reject_invalid_frames() {
+    let (strings, ids) = string_table(&[(log::Level::Info, "fw", "{}")]);
+    let decoder = LogDecoder::new(strings);
+
+    assert_eq!(decoder.decode(&[0, 0, 0]), Err(DecodeError::Truncated));
+    assert_eq!(decoder.decode(&header(0x1000, 0)), Err(DecodeError::UnknownString(0x1000)));
+    let mut frame = header(ids[0], 0);
+    frame.extend_from_slice(&[9, 0]);
+    assert_eq!(decoder.decode(&frame), Err(DecodeError::InvalidArgument(9)));
+    assert!(matches!(LogDecoder::from_elf(b"not an ELF file"), Err(DecodeError::InvalidElf)));
+}
+
+#[test]
+fn split_stream_into_frames() {
+    let (strings, ids) = string_table(&[(log::Level::Info, "fw", "count {}")]);
+    let mut stream = LogStream::new(LogDecoder::new(strings));
+
+    let mut data = Vec::new();
+    for count in 0..3 {
+        let mut frame = header(ids[0], count);
+        frame.push(0);
+        varint(u64::from(count), &mut frame);
+        data.extend(cobs::encode_vec(&frame));
+        data.push(0);
+    }
+
+    // Split in the middle of the second frame
+    let split = data.len() / 2;
+    let mut records = stream.push(&data[..split]);
+    assert_eq!(records.len(), 1);
+    records.extend(stream.push(&data[split..]));
+    let messages: Vec<_> = records.into_iter().map(|record| record.unwrap().message).collect();
+    assert_eq!(messages, ["count 0", "count 1", "count 2"]);
+}
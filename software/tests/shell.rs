@@ -0,0 +1,65 @@
+use std::sync::mpsc;
+use std::time::Duration;
+use deadbug_cli::bridge::BridgeDevice;
+use deadbug_cli::shell::{Outcome, Shell};
+use deadbug_common::protocol::channels::FragmentedChannel;
+use deadbug_common::protocol::pipeline::PipelinedChannel;
+use deadbug_sim::SimulatedDevice;
+
+/// Pipelined, so notifications are delivered without polling
+fn connect(sim: &SimulatedDevice) -> BridgeDevice {
+    let reader = FragmentedChannel::new(sim.clone());
+    let writer = FragmentedChannel::new(sim.clone());
+    BridgeDevice::pipelined(PipelinedChannel::new(reader, writer))
+}
+
+#[test]
+fn complete_words() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let shell = Shell::new(&bridge, |_| {}).unwrap();
+    let completer = shell.completer();
+
+    assert_eq!(completer.complete_word("", 0), (0, vec!["gpio".to_string(), "system".to_string(), "help".to_string(), "exit".to_string()]));
+    assert_eq!(completer.complete_word("gpio s", 6), (5, vec!["set".to_string()]));
+    assert_eq!(completer.complete_word("gpio set ld", 11).1, ["LD4", "LD3", "LD5", "LD7", "LD9", "LD10", "LD8", "LD6"]);
+    assert_eq!(completer.complete_word("gpio set pe1", 12).1, ["PE10", "PE11", "PE12", "PE13", "PE14", "PE15"]);
+    assert_eq!(completer.complete_word("gpio set LD3 h", 14), (13, vec!["high".to_string()]));
+    // Completes the word at the cursor
+    assert_eq!(completer.complete_word("gpio m LD3", 6), (5, vec!["mode".to_string()]));
+    assert!(completer.complete_word("gpio get LD3 ", 13).1.is_empty());
+}
+
+#[test]
+fn execute_lines() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut shell = Shell::new(&bridge, |_| {}).unwrap();
+
+    assert_eq!(shell.execute("gpio set LD3 high"), Ok(Outcome::Output("PE9: high\n".to_string())));
+    assert_eq!(sim.output_level(1), Some(true));
+    assert_eq!(shell.execute("  gpio mode pe9 "), Ok(Outcome::Output("PE9: output\n".to_string())));
+    assert_eq!(shell.execute("system keep-alive 500"), Ok(Outcome::Output(String::new())));
+    assert_eq!(shell.execute("exit"), Ok(Outcome::Exit));
+
+    assert_eq!(shell.execute("gpio set LD3"), Err("usage: gpio set PIN high|low".to_string()));
+    assert_eq!(shell.execute("gpio set LD3 maybe"), Err("invalid level \"maybe\", expected high or low".to_string()));
+    assert!(shell.execute("gpio blink LD3").unwrap_err().starts_with("unknown command"));
+    assert!(shell.execute("gpio get PZ1").is_err());
+}
+
+#[test]
+fn print_watched_pins() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let (sender, receiver) = mpsc::channel();
+    let mut shell = Shell::new(&bridge, move |line| sender.send(line).unwrap()).unwrap();
+
+    assert_eq!(shell.execute("gpio watch LD8"), Ok(Outcome::Output("PE14: low (watching)\n".to_string())));
+    sim.set_input_level(6, true);
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(1)).unwrap(), "PE14: high");
+
+    shell.execute("gpio unwatch LD8").unwrap();
+    sim.set_input_level(6, false);
+    assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+}
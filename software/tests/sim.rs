@@ -0,0 +1,342 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use deadbug_cli::bridge::BridgeDevice;
+use deadbug_common::hal::HalErrorKind;
+use deadbug_common::hal::gpio::GpioPinMode;
+use deadbug_common::protocol::channels::{CommandChannel, DeviceChannel, FragmentedChannel, PacketChannel};
+use deadbug_common::protocol::gpio::GPIO_ENDPOINT;
+use deadbug_common::protocol::notification::{LogLevel, LogRecord, NotificationKind, PinEdgeNotification};
+use deadbug_common::protocol::pipeline::PipelinedChannel;
+use deadbug_common::protocol::serialize_vec;
+use deadbug_common::protocol::gpio::GpioCommand;
+use deadbug_common::protocol::system::{FaultRegisters, SafePinState};
+use deadbug_sim::SimulatedDevice;
+use embedded_hal::digital::v2::OutputPin;
+
+fn connect(sim: &SimulatedDevice) -> BridgeDevice {
+    let channel = DeviceChannel::new(FragmentedChannel::new(sim.clone()));
+    BridgeDevice::new(Box::new(channel))
+}
+
+fn connect_pipelined(sim: &SimulatedDevice) -> PipelinedChannel {
+    let reader = FragmentedChannel::new(sim.clone());
+    let writer = FragmentedChannel::new(sim.clone());
+    PipelinedChannel::new(reader, writer)
+}
+
+#[test]
+fn enumerate_pins() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+
+    let pin = gpio.pin_by_label("ld3").unwrap();
+    assert_eq!(pin.index(), 1);
+    assert_eq!(pin.information().port(), 'E');
+    assert_eq!(pin.information().index_minor, 9);
+
+    let pin = gpio.pin_by_label("PE10").unwrap();
+    assert_eq!(pin.information().label.as_str(), "LD5");
+
+    // Taken pins are gone
+    assert!(gpio.pin('E', 9).is_err());
+
+    let pins = gpio.all_pins();
+    let indices: Vec<_> = pins.iter().map(|pin| pin.index()).collect();
+    assert_eq!(indices, vec![0, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn drive_outputs() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+    let mut pin = gpio.pin('E', 12).unwrap();
+
+    assert_eq!(sim.output_level(4), None);
+    pin.into_output().unwrap();
+    assert_eq!(pin.mode().unwrap(), GpioPinMode::PushPullOutput);
+    pin.set_high().unwrap();
+    assert_eq!(sim.output_level(4), Some(true));
+    pin.set_low().unwrap();
+    assert_eq!(sim.output_level(4), Some(false));
+
+    let err = pin.set_mode(GpioPinMode::Alternate(7)).unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::InvalidGpioMode));
+}
+
+#[test]
+fn read_inputs() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+    let pin = gpio.pin_by_label("LD7").unwrap();
+
+    assert!(!pin.value().unwrap());
+    sim.set_input_level(pin.index(), true);
+    assert!(pin.value().unwrap());
+
+    pin.into_output().unwrap();
+    assert!(matches!(pin.value().unwrap_err().kind(), HalErrorKind::InvalidGpioMode));
+}
+
+#[test]
+fn pin_ownership() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+    let pin = gpio.pin('E', 11).unwrap();
+
+    assert!(bridge.pin_owners().unwrap().iter().all(Option::is_none));
+    pin.into_output().unwrap();
+    let owners = bridge.pin_owners().unwrap();
+    assert_eq!(owners.len(), 8);
+    assert_eq!(owners[3], Some(GPIO_ENDPOINT));
+
+    pin.release().unwrap();
+    assert_eq!(bridge.pin_owners().unwrap()[3], None);
+    assert_eq!(sim.pin_mode(3), Some(GpioPinMode::FloatingInput));
+}
+
+#[test]
+fn capture_levels() {
+    let sim = SimulatedDevice::f3_discovery();
+    sim.set_input_level(0, true);
+    sim.set_input_level(7, true);
+    let bridge = connect(&sim);
+    let gpio = bridge.gpio().unwrap();
+
+    let mut stream = gpio.start_capture(4).unwrap();
+    for _ in 0..10 {
+        let data = stream.read().unwrap();
+        assert!(!data.is_empty());
+        assert!(data.iter().all(|sample| *sample == 0b1000_0001));
+    }
+    assert_eq!(stream.lost_messages(), 0);
+}
+
+#[test]
+fn pin_edge_notifications() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+    let pin = gpio.pin_by_label("LD8").unwrap();
+    let notifications = bridge.notifications().subscribe(Some(NotificationKind::PinEdge));
+
+    pin.watch(true).unwrap();
+    sim.set_input_level(pin.index(), true);
+    bridge.poll().unwrap();
+
+    let notification = notifications.recv_timeout(Duration::from_secs(1)).unwrap();
+    let edge: PinEdgeNotification = notification.decode().unwrap();
+    assert_eq!(edge.pin, pin.index());
+    assert!(edge.level);
+}
+
+#[test]
+fn pipelined_commands() {
+    let sim = SimulatedDevice::f3_discovery();
+    let channel = connect_pipelined(&sim);
+
+    let futures: Vec<_> = (0..8).map(|index| {
+        let command = serialize_vec(&GpioCommand::SetPinMode(index, GpioPinMode::PushPullOutput));
+        channel.send_command(GPIO_ENDPOINT, &command).unwrap()
+    }).collect();
+    for future in futures.into_iter().rev() {
+        assert_eq!(future.wait().unwrap(), Vec::<u8>::new());
+    }
+    for index in 0..8 {
+        assert_eq!(sim.output_level(index), Some(false));
+    }
+
+    let err = channel.call(0x42, &[0]).unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::UnsupportedCommand));
+}
+
+#[test]
+fn reject_empty_command() {
+    let sim = SimulatedDevice::f3_discovery();
+    let channel = connect_pipelined(&sim);
+    let err = channel.call(GPIO_ENDPOINT, &[]).unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::ProtocolError));
+
+    let mut channel = DeviceChannel::new(FragmentedChannel::new(sim.clone()));
+    let err = channel.command(GPIO_ENDPOINT, &[]).unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::ProtocolError));
+}
+
+/// Device which never answers, keeping every command in flight
+struct SilentDevice;
+
+impl PacketChannel for SilentDevice {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        thread::sleep(Duration::from_millis(10));
+        Err(io::ErrorKind::TimedOut.into())
+    }
+
+    fn write_packet(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pipelined_commands_exhaust_request_ids() {
+    let channel = PipelinedChannel::new(SilentDevice, SilentDevice);
+    let futures: Vec<_> = (0..256).map(|_| channel.send_command(GPIO_ENDPOINT, &[]).unwrap()).collect();
+    let err = channel.send_command(GPIO_ENDPOINT, &[]).err().unwrap();
+    assert!(matches!(err.kind(), HalErrorKind::NoResources));
+    assert_eq!(futures.len(), 256);
+}
+
+#[test]
+fn last_panic_report() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    assert_eq!(bridge.take_last_panic().unwrap(), None);
+
+    sim.set_last_panic("panicked at 'oops', src/app.rs:10:5");
+    assert_eq!(bridge.take_last_panic().unwrap().unwrap(), "panicked at 'oops', src/app.rs:10:5");
+    assert_eq!(bridge.take_last_panic().unwrap(), None);
+}
+
+#[test]
+fn safe_state_on_keep_alive_timeout() {
+    let sim = SimulatedDevice::f3_discovery();
+    sim.set_read_timeout(Duration::from_millis(10));
+    let bridge = connect(&sim);
+    let mut gpio = bridge.gpio().unwrap();
+    let mut high = gpio.pin('E', 11).unwrap();
+    let mut low = gpio.pin('E', 12).unwrap();
+    bridge.set_safe_state(high.index(), SafePinState::OutputHigh).unwrap();
+    high.into_output().unwrap();
+    high.set_low().unwrap();
+    low.into_output().unwrap();
+    low.set_high().unwrap();
+
+    bridge.set_keep_alive_timeout(Some(Duration::from_millis(200))).unwrap();
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(50));
+        bridge.keep_alive().unwrap();
+    }
+    assert_eq!(sim.output_level(low.index()), Some(true));
+
+    // The host goes silent, nothing is received and the read times out
+    thread::sleep(Duration::from_millis(250));
+    bridge.poll().ok();
+    assert_eq!(sim.output_level(high.index()), Some(true));
+    assert_eq!(sim.pin_mode(low.index()), Some(GpioPinMode::FloatingInput));
+    assert!(bridge.pin_owners().unwrap().iter().all(Option::is_none));
+
+    // The timeout is disabled until the next host sets it
+    low.into_output().unwrap();
+    thread::sleep(Duration::from_millis(250));
+    bridge.poll().ok();
+    assert_eq!(sim.pin_mode(low.index()), Some(GpioPinMode::PushPullOutput));
+}
+
+#[test]
+fn reject_unsupported_safe_state() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let err = bridge.set_safe_state(8, SafePinState::OutputLow).unwrap_err();
+    assert!(matches!(err.kind(), HalErrorKind::InvalidParameter));
+}
+
+#[test]
+fn forward_log_records() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    let notifications = bridge.notifications().subscribe(Some(NotificationKind::Log));
+
+    // Forwarding is off until the host asks for it
+    sim.log(LogLevel::Error, "app", "dropped");
+    bridge.forward_log(log::LevelFilter::Info).unwrap();
+    sim.log(LogLevel::Debug, "app", "filtered");
+    sim.log(LogLevel::Warn, "app::usb", "rx overrun");
+    bridge.poll().unwrap();
+
+    let notification = notifications.recv_timeout(Duration::from_secs(1)).unwrap();
+    let record = LogRecord::decode(&notification.payload).unwrap();
+    assert_eq!(record.level, LogLevel::Warn);
+    assert_eq!(record.target, "app::usb");
+    assert_eq!(record.message, "rx overrun");
+    assert!(notifications.try_recv().is_err());
+
+    bridge.forward_log(log::LevelFilter::Off).unwrap();
+    sim.log(LogLevel::Error, "app", "dropped");
+    bridge.poll().ok();
+    assert!(notifications.try_recv().is_err());
+}
+
+/// Collects the messages of the forwarded device log records
+struct CaptureLogger(Mutex<Vec<String>>);
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target().starts_with("device::")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+fn captured(message: &str) -> usize {
+    CAPTURE.0.lock().unwrap().iter().filter(|m| m.ends_with(message)).count()
+}
+
+#[test]
+fn forward_log_records_once() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+
+    bridge.forward_log(log::LevelFilter::Info).unwrap();
+    bridge.forward_log(log::LevelFilter::Warn).unwrap();
+    sim.log(LogLevel::Warn, "app", "logged once");
+    bridge.poll().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while captured("logged once") == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Give a second forwarder the chance to log the record again
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(captured("logged once"), 1);
+
+    bridge.forward_log(log::LevelFilter::Off).unwrap();
+}
+
+#[test]
+fn last_fault_report() {
+    let sim = SimulatedDevice::f3_discovery();
+    let bridge = connect(&sim);
+    assert_eq!(bridge.take_last_crash().unwrap(), None);
+
+    sim.set_last_panic("panicked at 'oops', src/app.rs:10:5");
+    let report = bridge.take_last_crash().unwrap().unwrap();
+    assert_eq!(report.message, "panicked at 'oops', src/app.rs:10:5");
+    assert_eq!(report.fault, None);
+
+    let registers = FaultRegisters {
+        pc: 0x0800_1234,
+        lr: 0x0800_1001,
+        cfsr: 1 << 25,
+        hfsr: 1 << 30,
+        ..FaultRegisters::default()
+    };
+    sim.set_last_fault("hard fault at 0x08001234", registers);
+    let report = bridge.take_last_crash().unwrap().unwrap();
+    assert_eq!(report.fault, Some(registers));
+    assert_eq!(report.fault.unwrap().causes().collect::<Vec<_>>(), ["divide by zero", "escalated fault"]);
+    assert_eq!(bridge.take_last_crash().unwrap(), None);
+}
@@ -0,0 +1,26 @@
+//! Destinations of the log output
+
+/// Writes the buffered output
+///
+/// Implemented for the UART transmitters of the HALs and by the debugger backends. Boards only
+/// set up their UART and pass the transmitter to `init`.
+pub trait Backend: Send {
+    /// Writes as much of the data as the backend takes without blocking, returns the number of
+    /// bytes taken
+    fn write(&mut self, data: &[u8]) -> usize;
+
+    /// Enables the interrupt whose handler writes the buffered output with `on_interrupt`
+    ///
+    /// Returns false if the backend has no interrupt, the output is then written right away.
+    fn start_interrupt(&mut self) -> bool {
+        false
+    }
+
+    fn stop_interrupt(&mut self) {}
+
+    /// Returns false if waiting doesn't make the backend take more output, so `flush` doesn't
+    /// wait, e.g. for an RTT debugger which doesn't read the channel
+    fn can_wait(&self) -> bool {
+        true
+    }
+}
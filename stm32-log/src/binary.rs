@@ -0,0 +1,264 @@
+//! Deferred formatting, the format strings stay on the host
+//!
+//! The macros of this crate put the level, the module path and the format string of every call
+//! into the `.stm32_log` section. `stm32-log.x` places it at address 0 and doesn't load it into
+//! the flash, the host reads it from the ELF file. A record is sent as a COBS frame terminated by
+//! a zero byte:
+//!
+//! - the address of the strings in the section, u16 little endian,
+//! - the timestamp in milliseconds, u32 little endian,
+//! - the arguments, each a type tag followed by the value.
+//!
+//! Integers are LEB128 varints, the signed ones zigzag encoded, strings are a varint length
+//! followed by UTF-8. The section entry is the level followed by the module path and the format
+//! string, both terminated by a zero byte.
+//!
+//! Records of the `log` facade are formatted on the device and sent with the `TEXT_RECORD` id,
+//! followed by the level, the target and the message. Lost records are reported with the
+//! `DROPPED_RECORD` id, followed by their number.
+
+use core::fmt::{self, Write};
+use log::{Level, Record};
+use crate::log::{timestamp, write};
+
+/// Id of the records formatted on the device
+pub const TEXT_RECORD: u16 = 0xffff;
+/// Id of the lost record reports
+pub const DROPPED_RECORD: u16 = 0xfffe;
+
+pub const TAG_UNSIGNED: u8 = 0;
+pub const TAG_SIGNED: u8 = 1;
+pub const TAG_STR: u8 = 2;
+pub const TAG_BOOL: u8 = 3;
+pub const TAG_F32: u8 = 4;
+pub const TAG_CHAR: u8 = 5;
+
+/// Arguments that don't fit are dropped, strings are truncated
+pub const MAX_FRAME_SIZE: usize = 128;
+/// COBS adds a byte every 254 bytes, plus the terminating zero
+const MAX_ENCODED_SIZE: usize = MAX_FRAME_SIZE + MAX_FRAME_SIZE / 254 + 2;
+const MAX_VARINT_SIZE: usize = 10;
+
+#[doc(hidden)]
+pub const fn entry_size(target: &str, format: &str) -> usize {
+    1 + target.len() + 1 + format.len() + 1
+}
+
+#[doc(hidden)]
+pub const fn entry<const N: usize>(level: Level, target: &str, format: &str) -> [u8; N] {
+    let mut entry = [0; N];
+    entry[0] = level as u8;
+    let target = target.as_bytes();
+    let mut i = 0;
+    while i < target.len() {
+        entry[1 + i] = target[i];
+        i += 1;
+    }
+    let format = format.as_bytes();
+    let mut i = 0;
+    while i < format.len() {
+        entry[2 + target.len() + i] = format[i];
+        i += 1;
+    }
+    entry
+}
+
+/// Encodes the value, returns the size
+fn encode_varint(mut value: u64, buffer: &mut [u8; MAX_VARINT_SIZE]) -> usize {
+    let mut size = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer[size] = byte;
+            return size + 1;
+        }
+        buffer[size] = byte | 0x80;
+        size += 1;
+    }
+}
+
+/// Record being built, written to the buffer by `write`
+pub struct Frame {
+    data: [u8; MAX_FRAME_SIZE],
+    size: usize,
+    /// An argument didn't fit, the following ones are dropped too
+    full: bool,
+}
+
+impl Frame {
+    #[doc(hidden)]
+    pub fn new(id: u16) -> Self {
+        let mut frame = Frame {
+            data: [0; MAX_FRAME_SIZE],
+            size: 0,
+            full: false,
+        };
+        frame.push(&id.to_le_bytes());
+        frame.push(&timestamp().to_le_bytes());
+        frame
+    }
+
+    /// Appends the data if all of it fits
+    fn push(&mut self, data: &[u8]) -> bool {
+        if self.full || self.size + data.len() > MAX_FRAME_SIZE {
+            self.full = true;
+            return false;
+        }
+        self.data[self.size..self.size + data.len()].copy_from_slice(data);
+        self.size += data.len();
+        true
+    }
+
+    fn push_tagged(&mut self, tag: u8, value: &[u8]) {
+        let mut data = [0; 1 + MAX_VARINT_SIZE];
+        data[0] = tag;
+        data[1..1 + value.len()].copy_from_slice(value);
+        self.push(&data[..1 + value.len()]);
+    }
+
+    fn push_varint(&mut self, tag: u8, value: u64) {
+        let mut buffer = [0; MAX_VARINT_SIZE];
+        let size = encode_varint(value, &mut buffer);
+        self.push_tagged(tag, &buffer[..size]);
+    }
+
+    /// Appends as much of the string as fits
+    fn push_str(&mut self, s: &str) {
+        let mut buffer = [0; MAX_VARINT_SIZE];
+        let header_size = encode_varint(s.len() as u64, &mut buffer);
+        let free = MAX_FRAME_SIZE.saturating_sub(self.size + header_size);
+        let mut size = s.len().min(free);
+        while !s.is_char_boundary(size) {
+            size -= 1;
+        }
+        let header_size = encode_varint(size as u64, &mut buffer);
+        if self.push(&buffer[..header_size]) {
+            self.push(&s.as_bytes()[..size]);
+        }
+    }
+
+    /// Appends the formatted text as a string, the frame must have room for its length
+    fn push_formatted(&mut self, args: fmt::Arguments) {
+        // Frames are short enough for one byte varints
+        let header = self.size;
+        if !self.push(&[0]) {
+            return;
+        }
+        self.write_fmt(args).ok();
+        self.data[header] = (self.size - header - 1) as u8;
+    }
+
+    fn encode(&self) -> EncodedFrame {
+        let mut encoded = EncodedFrame {
+            data: [0; MAX_ENCODED_SIZE],
+            size: 0,
+        };
+        encoded.size = cobs::encode(&self.data[..self.size], &mut encoded.data) + 1;
+        encoded
+    }
+
+    #[doc(hidden)]
+    pub fn write(self) {
+        write(self.encode().as_bytes());
+    }
+}
+
+impl Write for Frame {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut size = s.len().min(MAX_FRAME_SIZE - self.size);
+        while !s.is_char_boundary(size) {
+            size -= 1;
+        }
+        self.data[self.size..self.size + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.size += size;
+        Ok(())
+    }
+}
+
+/// COBS-encoded frame with the terminating zero
+pub(crate) struct EncodedFrame {
+    data: [u8; MAX_ENCODED_SIZE],
+    size: usize,
+}
+
+impl EncodedFrame {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.size]
+    }
+}
+
+pub(crate) fn text_frame(record: &Record) -> EncodedFrame {
+    let mut frame = Frame::new(TEXT_RECORD);
+    frame.push(&[record.level() as u8]);
+    frame.push_str(record.target());
+    frame.push_formatted(*record.args());
+    frame.encode()
+}
+
+pub(crate) fn dropped_frame(count: u32) -> EncodedFrame {
+    let mut frame = Frame::new(DROPPED_RECORD);
+    frame.push_varint(TAG_UNSIGNED, u64::from(count));
+    frame.encode()
+}
+
+/// Value which can be a deferred formatting argument
+pub trait Argument {
+    fn encode(&self, frame: &mut Frame);
+}
+
+impl<T: Argument + ?Sized> Argument for &T {
+    fn encode(&self, frame: &mut Frame) {
+        (**self).encode(frame);
+    }
+}
+
+macro_rules! unsigned_argument {
+    ($($t:ty),*) => {
+        $(impl Argument for $t {
+            fn encode(&self, frame: &mut Frame) {
+                frame.push_varint(TAG_UNSIGNED, *self as u64);
+            }
+        })*
+    };
+}
+
+macro_rules! signed_argument {
+    ($($t:ty),*) => {
+        $(impl Argument for $t {
+            fn encode(&self, frame: &mut Frame) {
+                let value = *self as i64;
+                frame.push_varint(TAG_SIGNED, ((value << 1) ^ (value >> 63)) as u64);
+            }
+        })*
+    };
+}
+
+unsigned_argument!(u8, u16, u32, u64, usize);
+signed_argument!(i8, i16, i32, i64, isize);
+
+impl Argument for bool {
+    fn encode(&self, frame: &mut Frame) {
+        frame.push_tagged(TAG_BOOL, &[u8::from(*self)]);
+    }
+}
+
+impl Argument for f32 {
+    fn encode(&self, frame: &mut Frame) {
+        frame.push_tagged(TAG_F32, &self.to_bits().to_le_bytes());
+    }
+}
+
+impl Argument for char {
+    fn encode(&self, frame: &mut Frame) {
+        frame.push_varint(TAG_CHAR, u64::from(u32::from(*self)));
+    }
+}
+
+impl Argument for str {
+    fn encode(&self, frame: &mut Frame) {
+        if frame.push(&[TAG_STR]) {
+            frame.push_str(self);
+        }
+    }
+}
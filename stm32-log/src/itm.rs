@@ -0,0 +1,47 @@
+//! Output to an ITM stimulus port, read by the debugger through SWO
+//!
+//! Written right away, the port FIFO is drained by the hardware.
+
+use cortex_m::peripheral::ITM;
+use crate::backend::Backend;
+
+pub struct Itm {
+    port: usize,
+}
+
+impl Itm {
+    /// The debugger enables the ITM and the port, and sets up the SWO pin
+    pub fn new(_itm: ITM, port: usize) -> Self {
+        Self {
+            port,
+        }
+    }
+
+    /// Returns true if the debugger enabled the port
+    fn enabled(&self) -> bool {
+        let itm = unsafe { &*ITM::PTR };
+        itm.tcr.read() & 1 != 0 && itm.ter[self.port / 32].read() & (1 << (self.port % 32)) != 0
+    }
+}
+
+impl Backend for Itm {
+    fn write(&mut self, data: &[u8]) -> usize {
+        // Nobody listens, the output is discarded
+        if !self.enabled() {
+            return data.len();
+        }
+        let stim = unsafe { &mut (*ITM::PTR).stim[self.port] };
+        data.iter().take_while(|&&byte| {
+            if !stim.is_fifo_ready() {
+                return false;
+            }
+            stim.write_u8(byte);
+            true
+        }).count()
+    }
+}
+
+/// Configures the output to the stimulus port, `buffer` holds the output until it's written
+pub fn configure(itm: ITM, port: usize, buffer: &'static mut [u8]) {
+    crate::init(cortex_m::singleton!(: Itm = Itm::new(itm, port)).unwrap(), buffer);
+}
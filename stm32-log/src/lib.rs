@@ -1,22 +1,67 @@
+//! Logger for the `log` facade writing to a UART or to the debugger
+//!
+//! The output goes to a `Backend`, set with `init`. Backends are provided for:
+//!
+//! - the UART transmitters of the HALs, with the `stm32f3` or `stm32f4` feature,
+//! - the ITM, RTT and semihosting, with the `itm`, `rtt` and `semihosting` features.
+//!
+//! The board features `f3-discovery` and `nucleo-f429zi` select the HAL and add a `configure`
+//! which sets up the USART connected to the debugger probe. A board is added as a module which
+//! builds the `Serial` of its USART and passes the transmitter to `init`, if the HAL has a USART
+//! the `uart` module doesn't cover yet, it goes into the `uart_backend!` list there.
+
 #![no_std]
 
-#[cfg(not(feature = "target-selected"))]
-compile_error!("This crate requires one of the following device features enabled:
+mod macros;
+
+#[cfg(all(feature = "f3-discovery", feature = "nucleo-f429zi"))]
+compile_error!("The board features are mutually exclusive, enable one of:
+        f3-discovery
         nucleo-f429zi");
 
-#[cfg(feature = "nucleo-f429zi")]
+#[cfg(all(feature = "stm32f3", feature = "stm32f4"))]
+compile_error!("The HAL features are mutually exclusive, enable one of:
+        stm32f3
+        stm32f4");
+
+#[cfg(not(any(feature = "stm32f3", feature = "stm32f4", feature = "itm", feature = "rtt", feature = "semihosting")))]
+compile_error!("No backend selected, enable a board or at least one of:
+        stm32f3
+        stm32f4
+        itm
+        rtt
+        semihosting");
+
+// Neither board is compiled in when both are enabled, so conflicting `configure`s don't bury
+// the board compile_error
+#[cfg(all(feature = "nucleo-f429zi", not(feature = "f3-discovery")))]
 mod nucleo_f429zi;
-#[cfg(feature = "nucleo-f429zi")]
+#[cfg(all(feature = "nucleo-f429zi", not(feature = "f3-discovery")))]
 pub use nucleo_f429zi::configure;
-#[cfg(feature = "nucleo-f429zi")]
-pub(crate) use nucleo_f429zi::write_bytes;
 
-#[cfg(feature = "f3-discovery")]
+#[cfg(all(feature = "f3-discovery", not(feature = "nucleo-f429zi")))]
 mod f3_discovery;
-#[cfg(feature = "f3-discovery")]
+#[cfg(all(feature = "f3-discovery", not(feature = "nucleo-f429zi")))]
 pub use f3_discovery::configure;
-#[cfg(feature = "f3-discovery")]
-pub(crate) use f3_discovery::write_bytes;
 
-#[cfg(feature = "target-selected")]
+#[cfg(any(feature = "stm32f3", feature = "stm32f4"))]
+pub mod uart;
+#[cfg(feature = "itm")]
+pub mod itm;
+#[cfg(feature = "rtt")]
+pub mod rtt;
+#[cfg(feature = "semihosting")]
+pub mod semihosting;
+
+mod backend;
 mod log;
+#[cfg(feature = "binary")]
+pub mod binary;
+pub use crate::backend::Backend;
+pub use crate::log::{
+    init, enabled, on_interrupt, pending, redirect, drain, set_hook, set_level, set_module_level, set_format,
+    set_timestamp_source, Format, MAX_LINE_SIZE, MAX_MODULE_LEVELS,
+};
+
+#[doc(hidden)]
+pub use ::log as __log;
@@ -1,105 +1,455 @@
-use core::cell::RefCell;
-use core::fmt::Write;
+use core::cell::{Cell, RefCell};
+#[cfg(not(feature = "binary"))]
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_m::interrupt::Mutex;
-use log::{Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
 use bbqueue::BBQueue;
+use crate::backend::Backend;
+
+type TimestampSource = fn() -> u32;
+type Hook = fn(&Record);
+
+/// Longer lines are truncated
+pub const MAX_LINE_SIZE: usize = 256;
+/// Number of modules with a level of their own
+pub const MAX_MODULE_LEVELS: usize = 8;
 
-static mut BUFFER: [u8; 4096] = [0u8; 4096];
 static LOGBUF: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+static FILTER: Mutex<RefCell<Filter>> = Mutex::new(RefCell::new(Filter::new()));
+static FORMAT: Mutex<Cell<Format>> = Mutex::new(Cell::new(Format::new()));
+static TIMESTAMP: Mutex<Cell<Option<TimestampSource>>> = Mutex::new(Cell::new(None));
+/// The output is read with `drain` instead of being written by the backend
+static REDIRECTED: AtomicBool = AtomicBool::new(false);
+static HOOK: Mutex<Cell<Option<Hook>>> = Mutex::new(Cell::new(None));
+
+/// Prefixes written before the message, the binary output always has the timestamp
+#[derive(Clone, Copy)]
+pub struct Format {
+    /// Milliseconds from the `set_timestamp_source` function
+    pub timestamp: bool,
+    pub level: bool,
+    pub target: bool,
+}
+
+impl Format {
+    /// Message only
+    pub const fn new() -> Self {
+        Self {
+            timestamp: false,
+            level: false,
+            target: false,
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Level of the records written to the buffer, per module
+struct Filter {
+    level: LevelFilter,
+    modules: [Option<(&'static str, LevelFilter)>; MAX_MODULE_LEVELS],
+}
+
+impl Filter {
+    const fn new() -> Self {
+        Filter {
+            level: LevelFilter::Trace,
+            modules: [None; MAX_MODULE_LEVELS],
+        }
+    }
+
+    /// Returns the level of the most specific module containing the target
+    fn level(&self, target: &str) -> LevelFilter {
+        let mut level = self.level;
+        let mut matched = 0;
+        for (module, module_level) in self.modules.iter().flatten() {
+            if module.len() > matched && is_within(target, module) {
+                level = *module_level;
+                matched = module.len();
+            }
+        }
+        level
+    }
+
+    fn set_module(&mut self, module: &'static str, level: Option<LevelFilter>) -> bool {
+        if let Some(entry) = self.modules.iter_mut().find(|entry| matches!(entry, Some((m, _)) if *m == module)) {
+            *entry = level.map(|level| (module, level));
+            return true;
+        }
+        let level = match level {
+            Some(level) => level,
+            None => return true,
+        };
+        match self.modules.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some((module, level));
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Returns true if `target` is `module` or one of its submodules
+fn is_within(target: &str, module: &str) -> bool {
+    target.starts_with(module)
+        && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+}
+
+/// A line formatted before it's queued, so lines are never split
+///
+/// Line endings are converted to CRLF.
+#[cfg(not(feature = "binary"))]
+struct Line {
+    data: [u8; MAX_LINE_SIZE],
+    size: usize,
+}
+
+#[cfg(not(feature = "binary"))]
+impl Line {
+    fn new() -> Self {
+        Line {
+            data: [0; MAX_LINE_SIZE],
+            size: 0,
+        }
+    }
 
-pub struct Buffer(Option<BBQueue>);
+    /// Copies as much of the text as fits, keeping room for the line ending
+    fn push(&mut self, s: &str) {
+        let free = MAX_LINE_SIZE - 2 - self.size;
+        let mut size = s.len().min(free);
+        while !s.is_char_boundary(size) {
+            size -= 1;
+        }
+        self.data[self.size..self.size + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.size += size;
+    }
+
+    fn end(&mut self) {
+        self.data[self.size..self.size + 2].copy_from_slice(b"\r\n");
+        self.size += 2;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.size]
+    }
+}
+
+#[cfg(not(feature = "binary"))]
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.push(first);
+        }
+        for line in lines {
+            if self.size + 2 <= MAX_LINE_SIZE - 2 {
+                self.end();
+            }
+            self.push(line);
+        }
+        Ok(())
+    }
+}
+
+pub struct Buffer {
+    queue: Option<BBQueue>,
+    backend: Option<&'static mut dyn Backend>,
+    /// Lines which didn't fit since the last report
+    dropped: u32,
+}
 
 impl Buffer {
     const fn new() -> Self {
-        Buffer(None)
+        Buffer {
+            queue: None,
+            backend: None,
+            dropped: 0,
+        }
     }
 }
 
 unsafe impl Send for Buffer {}
 
 impl Buffer {
-    fn queue(&mut self) -> &mut BBQueue {
-        if self.0.is_none() {
-            unsafe {
-                self.0 = Some(BBQueue::unpinned_new(&mut BUFFER));
-            }
+    fn is_empty(&mut self) -> bool {
+        let queue = match self.queue.as_mut() {
+            Some(queue) => queue,
+            None => return true,
+        };
+        match queue.read() {
+            Ok(r) => {
+                queue.release(0, r);
+                false
+            },
+            Err(_) => true,
         }
-        self.0.as_mut().unwrap()
     }
 
+    /// Writes as much of the buffered output as the backend takes right away
     fn flush(&mut self) {
-        let queue = self.queue();
+        if let (Some(queue), Some(backend)) = (self.queue.as_mut(), self.backend.as_mut()) {
+            drain_queue(queue, |data| backend.write(data));
+        }
+    }
 
-        if let Ok(r) = queue.read() {
-            let n = crate::write_bytes(&r);
-            queue.release(n, r);
+    /// Passes buffered output to `write` until it returns 0
+    fn drain<F: FnMut(&[u8]) -> usize>(&mut self, write: F) {
+        if let Some(queue) = self.queue.as_mut() {
+            drain_queue(queue, write);
         }
     }
-}
 
-impl core::fmt::Write for Buffer {
-    fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        if s.is_empty() {
-            return Ok(());
+    /// Lets the backend write the buffered output
+    fn start(&mut self) {
+        let interrupt = match self.backend.as_mut() {
+            Some(backend) => backend.start_interrupt(),
+            None => return,
+        };
+        if !interrupt {
+            self.flush();
         }
-        let queue = self.queue();
+    }
 
-        let bytes = s.as_bytes();
-        if let Ok(mut w) = queue.grant(bytes.len() * 2) {
-            let mut index = 0;
-            for byte in bytes {
-                if *byte == 0x0A {
-                    w[index] = 0x0D;
-                    index += 1;
-                }
-                w[index] = *byte;
-                index += 1;
+    fn can_wait(&self) -> bool {
+        matches!(self.backend.as_ref(), Some(backend) if backend.can_wait())
+    }
+
+    /// Queues a line or a frame, or counts it as dropped if it doesn't fit
+    fn write(&mut self, data: &[u8]) {
+        if self.dropped > 0 {
+            #[cfg(not(feature = "binary"))]
+            let report = dropped_line(self.dropped);
+            #[cfg(feature = "binary")]
+            let report = crate::binary::dropped_frame(self.dropped);
+            if !self.queue(report.as_bytes()) {
+                self.dropped += 1;
+                return;
             }
-            queue.commit(index, w);
+            self.dropped = 0;
+        }
+        if !self.queue(data) {
+            self.dropped += 1;
+        }
+    }
+
+    fn queue(&mut self, data: &[u8]) -> bool {
+        let queue = match self.queue.as_mut() {
+            Some(queue) => queue,
+            None => return false,
+        };
+        let mut w = match queue.grant(data.len()) {
+            Ok(w) => w,
+            Err(_) => return false,
+        };
+        w.copy_from_slice(data);
+        queue.commit(data.len(), w);
+        true
+    }
+}
+
+fn drain_queue<F: FnMut(&[u8]) -> usize>(queue: &mut BBQueue, mut write: F) {
+    while let Ok(r) = queue.read() {
+        let n = write(&r);
+        queue.release(n, r);
+        if n == 0 {
+            break;
         }
-        Ok(())
     }
 }
 
+#[cfg(not(feature = "binary"))]
+fn dropped_line(count: u32) -> Line {
+    let mut line = Line::new();
+    write!(line, "[{} log messages dropped]", count).ok();
+    line.end();
+    line
+}
+
+/// Queues a line or a frame, they are never split
+pub(crate) fn write(data: &[u8]) {
+    cortex_m::interrupt::free(|cs| {
+        let mut buffer = LOGBUF.borrow(cs).borrow_mut();
+        buffer.write(data);
+        if !REDIRECTED.load(Ordering::Relaxed) {
+            buffer.start();
+        }
+    });
+}
+
+/// Returns the time from the `set_timestamp_source` function, 0 if there's none
+pub(crate) fn timestamp() -> u32 {
+    match cortex_m::interrupt::free(|cs| TIMESTAMP.borrow(cs).get()) {
+        Some(now) => now(),
+        None => 0,
+    }
+}
+
+/// Returns true if records of the target at the level are buffered
+#[doc(hidden)]
+pub fn enabled(level: Level, target: &str) -> bool {
+    cortex_m::interrupt::free(|cs| level <= FILTER.borrow(cs).borrow().level(target))
+}
+
+#[cfg(not(feature = "binary"))]
+fn format_line(record: &Record) -> Line {
+    let mut line = Line::new();
+    let format = cortex_m::interrupt::free(|cs| FORMAT.borrow(cs).get());
+    if format.timestamp {
+        let now = timestamp();
+        write!(line, "[{}.{:03}] ", now / 1000, now % 1000).ok();
+    }
+    if format.level {
+        write!(line, "{:<5} ", record.level()).ok();
+    }
+    if format.target {
+        write!(line, "{}: ", record.target()).ok();
+    }
+    write!(line, "{}", record.args()).ok();
+    line.end();
+    line
+}
+
 pub struct BufferLogger;
 
+impl BufferLogger {
+    fn buffered(&self, metadata: &Metadata) -> bool {
+        enabled(metadata.level(), metadata.target())
+    }
+}
+
 impl log::Log for BufferLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.buffered(metadata) || cortex_m::interrupt::free(|cs| HOOK.borrow(cs).get().is_some())
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            cortex_m::interrupt::free(|cs| {
-                // make sure you are alone on the buffer
+        if self.buffered(record.metadata()) {
+            // Formatted outside of the critical section, the arguments may take a while
+            #[cfg(not(feature = "binary"))]
+            write(format_line(record).as_bytes());
+            #[cfg(feature = "binary")]
+            write(crate::binary::text_frame(record).as_bytes());
+        }
+        if let Some(hook) = cortex_m::interrupt::free(|cs| HOOK.borrow(cs).get()) {
+            hook(record);
+        }
+    }
+
+    /// Waits until the buffered output is written, e.g. before a reset
+    ///
+    /// Doesn't need the interrupt, so it works in a panic handler. Doesn't wait for a backend
+    /// which can't make progress, see `Backend::can_wait`.
+    fn flush(&self) {
+        if REDIRECTED.load(Ordering::Relaxed) {
+            return;
+        }
+        loop {
+            let done = cortex_m::interrupt::free(|cs| {
                 let mut buffer = LOGBUF.borrow(cs).borrow_mut();
-//                writeln!(
-//                    buffer,
-//                    "{} {}: {}",
-//                    record.level(),
-//                    record.target(),
-//                    record.args()
-//                )
-//                .unwrap();
-                writeln!(
-                    buffer,
-                    "{}",
-                    record.args()
-                )
-                .unwrap();
+                buffer.flush();
+                buffer.is_empty() || !buffer.can_wait()
             });
+            if done {
+                break;
+            }
         }
     }
+}
 
-    fn flush(&self) {
-        cortex_m::interrupt::free(|cs| {
-            let mut buffer = LOGBUF.borrow(cs).borrow_mut();
-            buffer.flush();
-        });
+/// Returns true if some output waits to be written by `flush`
+pub fn pending() -> bool {
+    if REDIRECTED.load(Ordering::Relaxed) {
+        return false;
     }
+    cortex_m::interrupt::free(|cs| {
+        !LOGBUF.borrow(cs).borrow_mut().is_empty()
+    })
+}
+
+/// Stops writing the output to the backend, it's read with `drain` instead
+///
+/// Output already in the buffer goes to the new destination.
+pub fn redirect(enabled: bool) {
+    REDIRECTED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        cortex_m::interrupt::free(|cs| LOGBUF.borrow(cs).borrow_mut().start());
+    }
+}
+
+/// Writes the buffered output, called from the interrupt of the backend
+///
+/// Disables the interrupt when the buffer is empty or the output is redirected.
+pub fn on_interrupt() {
+    cortex_m::interrupt::free(|cs| {
+        let mut buffer = LOGBUF.borrow(cs).borrow_mut();
+        let redirected = REDIRECTED.load(Ordering::Relaxed);
+        if !redirected {
+            buffer.flush();
+        }
+        if redirected || buffer.is_empty() {
+            if let Some(backend) = buffer.backend.as_mut() {
+                backend.stop_interrupt();
+            }
+        }
+    });
+}
+
+/// Passes the buffered output to `write`, which returns the number of bytes it took
+///
+/// Stops when `write` returns 0.
+pub fn drain<F: FnMut(&[u8]) -> usize>(write: F) {
+    cortex_m::interrupt::free(|cs| {
+        LOGBUF.borrow(cs).borrow_mut().drain(write);
+    });
+}
+
+/// Passes every record to `hook` too, e.g. to forward it to the host
+///
+/// The hook runs outside of the critical section, it may be preempted by other records.
+/// It isn't affected by the levels of the buffered output, only by `log::max_level`.
+pub fn set_hook(hook: fn(&Record)) {
+    cortex_m::interrupt::free(|cs| HOOK.borrow(cs).set(Some(hook)));
+}
+
+/// Sets the level of the modules without a level of their own
+pub fn set_level(level: LevelFilter) {
+    cortex_m::interrupt::free(|cs| FILTER.borrow(cs).borrow_mut().level = level);
+}
+
+/// Sets the level of the module and its submodules, `None` reverts it to the default level
+///
+/// Returns false if `MAX_MODULE_LEVELS` modules already have a level.
+pub fn set_module_level(module: &'static str, level: Option<LevelFilter>) -> bool {
+    cortex_m::interrupt::free(|cs| FILTER.borrow(cs).borrow_mut().set_module(module, level))
+}
+
+pub fn set_format(format: Format) {
+    cortex_m::interrupt::free(|cs| FORMAT.borrow(cs).set(format));
+}
+
+/// Sets the function returning the timestamp prefix in milliseconds
+pub fn set_timestamp_source(now: fn() -> u32) {
+    cortex_m::interrupt::free(|cs| TIMESTAMP.borrow(cs).set(Some(now)));
 }
 
-pub fn init() {
+/// Installs the logger, `buffer` holds the output until the backend writes it
+///
+/// The backend is usually created with `cortex_m::singleton!`, the board and backend modules
+/// have `configure` functions doing it.
+pub fn init(backend: &'static mut dyn Backend, buffer: &'static mut [u8]) {
     static LOGGER: BufferLogger = BufferLogger;
+    // The buffer is borrowed for the lifetime of the program
+    let queue = unsafe { BBQueue::unpinned_new(buffer) };
+    cortex_m::interrupt::free(move |cs| {
+        let mut logbuf = LOGBUF.borrow(cs).borrow_mut();
+        logbuf.queue = Some(queue);
+        logbuf.backend = Some(backend);
+    });
     let _ = log::set_logger(&LOGGER).unwrap();
 }
@@ -0,0 +1,57 @@
+//! Logging macros, with the `binary` feature they defer the formatting to the host
+//!
+//! The format string must be a literal. Without the feature they forward to the `log` macros.
+
+#[cfg(not(feature = "binary"))]
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $format:literal $(, $argument:expr)* $(,)?) => {
+        $crate::__log::log!($level, $format $(, $argument)*)
+    };
+}
+
+/// With the `binary` feature, the arguments implement `binary::Argument` and are formatted as
+/// with `Display`, or as the format specification says
+#[cfg(feature = "binary")]
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $format:literal $(, $argument:expr)* $(,)?) => {{
+        const LEVEL: $crate::__log::Level = $level;
+        if LEVEL <= $crate::__log::max_level() && $crate::enabled(LEVEL, module_path!()) {
+            const TARGET: &str = module_path!();
+            const FORMAT: &str = $format;
+            #[link_section = ".stm32_log"]
+            static ENTRY: [u8; $crate::binary::entry_size(TARGET, FORMAT)] =
+                $crate::binary::entry(LEVEL, TARGET, FORMAT);
+            // The section is at address 0, so the address is the offset in the string table
+            let mut frame = $crate::binary::Frame::new(&ENTRY as *const _ as usize as u16);
+            $($crate::binary::Argument::encode(&$argument, &mut frame);)*
+            frame.write();
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($argument:tt)+) => { $crate::log!($crate::__log::Level::Error, $($argument)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($argument:tt)+) => { $crate::log!($crate::__log::Level::Warn, $($argument)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($argument:tt)+) => { $crate::log!($crate::__log::Level::Info, $($argument)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($argument:tt)+) => { $crate::log!($crate::__log::Level::Debug, $($argument)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($argument:tt)+) => { $crate::log!($crate::__log::Level::Trace, $($argument)+) };
+}
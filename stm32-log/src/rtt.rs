@@ -0,0 +1,112 @@
+//! Output to an RTT up channel, read by the debugger from the RAM
+//!
+//! Written right away, the debugger finds the `_SEGGER_RTT` control block by its id. When the
+//! channel is full because the debugger doesn't read it, the output stays in the log buffer.
+
+use core::ptr;
+use core::sync::atomic::{compiler_fence, AtomicUsize, Ordering};
+use crate::backend::Backend;
+
+const ID: &[u8; 16] = b"SEGGER RTT\0\0\0\0\0\0";
+const CHANNEL_NAME: &[u8] = b"Terminal\0";
+
+#[repr(C)]
+struct Channel {
+    name: *const u8,
+    buffer: *mut u8,
+    size: usize,
+    /// Written by the device for up channels
+    write: AtomicUsize,
+    /// Written by the debugger for up channels
+    read: AtomicUsize,
+    flags: usize,
+}
+
+impl Channel {
+    const fn new() -> Self {
+        Channel {
+            name: ptr::null(),
+            buffer: ptr::null_mut(),
+            size: 0,
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            flags: 0,
+        }
+    }
+}
+
+#[repr(C)]
+struct ControlBlock {
+    id: [u8; 16],
+    max_up_channels: usize,
+    max_down_channels: usize,
+    up: Channel,
+    down: Channel,
+}
+
+#[no_mangle]
+static mut _SEGGER_RTT: ControlBlock = ControlBlock {
+    // Set by `configure`, so the debugger doesn't find a copy of it in the flash
+    id: [0; 16],
+    max_up_channels: 1,
+    max_down_channels: 1,
+    up: Channel::new(),
+    down: Channel::new(),
+};
+
+fn up_channel() -> &'static Channel {
+    unsafe { &*ptr::addr_of!(_SEGGER_RTT.up) }
+}
+
+/// Up channel 0 of the control block
+pub struct Rtt {
+    _private: (),
+}
+
+impl Rtt {
+    /// Sets up the control block, `channel` is the ring buffer of the up channel
+    pub fn new(channel: &'static mut [u8]) -> Self {
+        unsafe {
+            let block = &mut *ptr::addr_of_mut!(_SEGGER_RTT);
+            block.up.name = CHANNEL_NAME.as_ptr();
+            block.up.buffer = channel.as_mut_ptr();
+            block.up.size = channel.len();
+            block.down.name = CHANNEL_NAME.as_ptr();
+            // The id is written last, the debugger may look for it at any time
+            compiler_fence(Ordering::SeqCst);
+            ptr::write_volatile(&mut block.id, *ID);
+        }
+        Self {
+            _private: (),
+        }
+    }
+}
+
+impl Backend for Rtt {
+    fn write(&mut self, data: &[u8]) -> usize {
+        let channel = up_channel();
+        let read = channel.read.load(Ordering::Acquire);
+        let mut write = channel.write.load(Ordering::Relaxed);
+        // One byte stays free, a full buffer would look empty
+        let free = (read + channel.size - write - 1) % channel.size;
+        let size = data.len().min(free);
+        for &byte in &data[..size] {
+            unsafe { ptr::write_volatile(channel.buffer.add(write), byte) };
+            write = (write + 1) % channel.size;
+        }
+        channel.write.store(write, Ordering::Release);
+        size
+    }
+
+    /// The debugger empties the channel, there's no point in waiting for it
+    fn can_wait(&self) -> bool {
+        false
+    }
+}
+
+/// Configures the output to up channel 0, `channel` is its ring buffer and `buffer` holds the
+/// output until it fits into the channel
+pub fn configure(channel: &'static mut [u8], buffer: &'static mut [u8]) {
+    let rtt = Rtt::new(channel);
+    crate::init(cortex_m::singleton!(: Rtt = rtt).unwrap(), buffer);
+}
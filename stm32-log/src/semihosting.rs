@@ -0,0 +1,39 @@
+//! Output to the debugger console through semihosting
+//!
+//! Every write halts the core until the debugger has taken the data, which takes milliseconds.
+//! Without a debugger attached the semihosting calls fault.
+
+use cortex_m_semihosting::hio::{self, HStdout};
+use crate::backend::Backend;
+
+pub struct Semihosting {
+    stdout: Option<HStdout>,
+}
+
+impl Semihosting {
+    pub fn new() -> Self {
+        Self {
+            stdout: hio::hstdout().ok(),
+        }
+    }
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for Semihosting {
+    fn write(&mut self, data: &[u8]) -> usize {
+        if let Some(stdout) = self.stdout.as_mut() {
+            stdout.write_all(data).ok();
+        }
+        data.len()
+    }
+}
+
+/// Configures the output to the debugger, `buffer` holds the output until it's written
+pub fn configure(buffer: &'static mut [u8]) {
+    crate::init(cortex_m::singleton!(: Semihosting = Semihosting::new()).unwrap(), buffer);
+}
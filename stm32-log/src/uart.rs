@@ -0,0 +1,43 @@
+//! Output to a USART of the HAL, written from its TXE interrupt
+//!
+//! Any USART the HAL supports works, the application sets it up with its pins and passes the
+//! transmitter to `init`:
+//!
+//! ```ignore
+//! let (tx, _) = serial.split();
+//! stm32_log::init(cortex_m::singleton!(: Tx<USART2> = tx).unwrap(), LOG_BUFFER);
+//! ```
+//!
+//! The interrupt handler of the USART calls `on_interrupt`.
+
+use crate::backend::Backend;
+
+macro_rules! uart_backend {
+    ($hal:ident: $($usart:ident),*) => {
+        $(impl Backend for $hal::serial::Tx<$hal::stm32::$usart> {
+            fn write(&mut self, data: &[u8]) -> usize {
+                use $hal::hal::serial::Write;
+                data.iter().take_while(|&&byte| Write::write(self, byte).is_ok()).count()
+            }
+
+            fn start_interrupt(&mut self) -> bool {
+                let usart = unsafe { &*$hal::stm32::$usart::ptr() };
+                usart.cr1.modify(|_, w| w.txeie().set_bit());
+                true
+            }
+
+            fn stop_interrupt(&mut self) {
+                let usart = unsafe { &*$hal::stm32::$usart::ptr() };
+                usart.cr1.modify(|_, w| w.txeie().clear_bit());
+            }
+        })*
+    };
+}
+
+#[cfg(feature = "stm32f3")]
+uart_backend!(stm32f3xx_hal: USART1, USART2, USART3);
+#[cfg(feature = "stm32f4")]
+uart_backend!(stm32f4xx_hal: USART1, USART2, USART6);
+// Not every F4 has it, and the HAL doesn't tell which chip it's built for
+#[cfg(feature = "nucleo-f429zi")]
+uart_backend!(stm32f4xx_hal: USART3);
@@ -0,0 +1,8 @@
+/* Format strings of the binary log, read from the ELF file by the host */
+SECTIONS
+{
+  .stm32_log 0 (INFO) :
+  {
+    KEEP(*(.stm32_log .stm32_log.*));
+  }
+}